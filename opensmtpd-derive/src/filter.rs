@@ -0,0 +1,84 @@
+use crate::handlers;
+use crate::{has_fn, parse_subsystems};
+use proc_macro2::{Span, TokenStream};
+use quote::quote;
+use syn::{Error, Ident, ImplItem, ItemImpl, NestedMeta};
+
+pub(crate) fn expand(args: &[NestedMeta], mut item: ItemImpl) -> Result<TokenStream, Error> {
+	let is_session = is_session_filter(&item)?;
+	let subsystems = parse_subsystems(args, false)?;
+	let filter_subsystems: Vec<Ident> = subsystems
+		.iter()
+		.filter(|s| *s == "SmtpIn")
+		.cloned()
+		.collect();
+	let mut errors: Option<Error> = None;
+	let mut has_fns = Vec::new();
+	for impl_item in &item.items {
+		let method = match impl_item {
+			ImplItem::Method(m) => m,
+			_ => continue,
+		};
+		let name = method.sig.ident.to_string();
+		if !name.starts_with("on_") || handlers::LIFECYCLE.contains(&name.as_str()) {
+			continue;
+		}
+		let res = handlers::find_spanned(&method.sig.ident).and_then(|handler| {
+			handler.check_signature(&method.sig, is_session)?;
+			if method.attrs.iter().any(is_register) {
+				return Ok(());
+			}
+			let subsystems = if handler.is_filter() {
+				&filter_subsystems
+			} else {
+				&subsystems
+			};
+			if subsystems.is_empty() {
+				return Err(Error::new_spanned(
+					&method.sig.ident,
+					"filters are only available for the smtp-in subsystem",
+				));
+			}
+			let fn_name = Ident::new(&handler.has_name(), Span::call_site());
+			has_fns.push(ImplItem::Verbatim(has_fn(&fn_name, subsystems)));
+			Ok(())
+		});
+		if let Err(e) = res {
+			match &mut errors {
+				Some(errors) => errors.combine(e),
+				None => errors = Some(e),
+			}
+		}
+	}
+	if let Some(e) = errors {
+		// The implementation is kept so only the relevant errors show.
+		let errors = e.to_compile_error();
+		return Ok(quote! { #errors #item });
+	}
+	item.items.extend(has_fns);
+	Ok(quote! { #item })
+}
+
+fn is_session_filter(item: &ItemImpl) -> Result<bool, Error> {
+	let name = item
+		.trait_
+		.as_ref()
+		.and_then(|(_, path, _)| path.segments.last())
+		.map(|s| s.ident.to_string());
+	match name.as_deref() {
+		Some("Filter") | Some("AsyncFilter") => Ok(false),
+		Some("SessionFilter") => Ok(true),
+		_ => Err(Error::new_spanned(
+			&item.self_ty,
+			"expected an implementation of `Filter`, `AsyncFilter` or `SessionFilter`",
+		)),
+	}
+}
+
+fn is_register(attr: &syn::Attribute) -> bool {
+	attr.path
+		.segments
+		.last()
+		.map(|s| s.ident == "register")
+		.unwrap_or(false)
+}
@@ -0,0 +1,393 @@
+use quote::ToTokens;
+use syn::{Error, FnArg, GenericArgument, Ident, PathArguments, ReturnType, Signature, Type};
+
+/// Kind of event a handler is called for.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum Kind {
+	/// Filter request, answered with a `FilterResponse`.
+	Filter,
+	/// Data-line, which is not answered.
+	DataLine,
+	/// Complete message, answered with its new content.
+	Message,
+	/// Report.
+	Report,
+}
+
+/// Handler which has to be registered.
+pub(crate) struct Handler {
+	pub(crate) name: &'static str,
+	pub(crate) kind: Kind,
+	/// Parameters following the entry, and the session if any.
+	pub(crate) params: &'static [&'static str],
+}
+
+macro_rules! handlers {
+	($($name: literal, $kind: ident, [$($param: literal),*];)*) => {
+		&[$(Handler {
+			name: $name,
+			kind: Kind::$kind,
+			params: &[$($param),*],
+		}),*]
+	};
+}
+
+pub(crate) const HANDLERS: &[Handler] = handlers! {
+	"on_filter_auth", Filter, ["&str"];
+	"on_filter_commit", Filter, [];
+	"on_filter_connect", Filter, ["&str", "&str", "&Address", "&Address"];
+	"on_filter_data", Filter, [];
+	"on_filter_data_line", DataLine, ["&[u8]"];
+	"on_filter_ehlo", Filter, ["&str"];
+	"on_filter_helo", Filter, ["&str"];
+	"on_filter_mail_from", Filter, ["&str"];
+	"on_filter_rcpt_to", Filter, ["&str"];
+	"on_filter_starttls", Filter, ["&str"];
+	"on_message", Message, ["Message"];
+	"on_report_link_auth", Report, ["&str", "AuthResult"];
+	"on_report_link_connect", Report, ["&str", "&str", "&Address", "&Address"];
+	"on_report_link_disconnect", Report, [];
+	"on_report_link_greeting", Report, ["&str"];
+	"on_report_link_identify", Report, ["Method", "&str"];
+	"on_report_link_tls", Report, ["&str"];
+	"on_report_tx_begin", Report, ["&str"];
+	"on_report_tx_mail", Report, ["&str", "MailResult", "&str"];
+	"on_report_tx_reset", Report, ["&Option<String>"];
+	"on_report_tx_rcpt", Report, ["&str", "MailResult", "&str"];
+	"on_report_tx_envelope", Report, ["&str", "&str"];
+	"on_report_tx_data", Report, ["&str", "MailResult"];
+	"on_report_tx_commit", Report, ["&str", "usize"];
+	"on_report_tx_rollback", Report, ["&str"];
+	"on_report_protocol_client", Report, ["&str"];
+	"on_report_protocol_server", Report, ["&str"];
+	"on_report_filter_response", Report, ["FilterPhase", "&str", "&Option<String>"];
+	"on_report_filter_report", Report, ["FilterKind", "&str", "&str"];
+	"on_report_timeout", Report, [];
+};
+
+/// Methods of the filter traits which are not registered.
+pub(crate) const LIFECYCLE: &[&str] = &[
+	"on_config",
+	"on_ready",
+	"on_shutdown",
+	"on_panic",
+	"on_filter_data_end",
+];
+
+pub(crate) fn find(name: &str) -> Option<&'static Handler> {
+	HANDLERS.iter().find(|h| h.name == name)
+}
+
+/// Returns the handler with the given name, or an error pointing at it
+/// and suggesting the closest handler.
+pub(crate) fn find_spanned(ident: &Ident) -> Result<&'static Handler, Error> {
+	let name = ident.to_string();
+	if let Some(handler) = find(&name) {
+		return Ok(handler);
+	}
+	if LIFECYCLE.contains(&name.as_str()) {
+		return Err(Error::new_spanned(
+			ident,
+			format!("`{}` is always called and cannot be registered", name),
+		));
+	}
+	if !name.starts_with("on_") {
+		return Err(Error::new_spanned(
+			ident,
+			"the name of a handler must start with `on_`",
+		));
+	}
+	let suggestion = HANDLERS
+		.iter()
+		.map(|h| (distance(&name, h.name), h.name))
+		.min()
+		.filter(|(d, _)| *d <= 3);
+	let msg = match suggestion {
+		Some((_, s)) => format!("unknown handler `{}`, did you mean `{}`?", name, s),
+		None => format!("unknown handler `{}`", name),
+	};
+	Err(Error::new_spanned(ident, msg))
+}
+
+/// Levenshtein distance between two names.
+fn distance(a: &str, b: &str) -> usize {
+	let b: Vec<char> = b.chars().collect();
+	let mut row: Vec<usize> = (0..=b.len()).collect();
+	for (i, ca) in a.chars().enumerate() {
+		let mut prev = row[0];
+		row[0] = i + 1;
+		for (j, cb) in b.iter().enumerate() {
+			let cur = row[j + 1];
+			row[j + 1] = if ca == *cb {
+				prev
+			} else {
+				1 + prev.min(cur).min(row[j])
+			};
+			prev = cur;
+		}
+	}
+	row[b.len()]
+}
+
+/// Returns the type as written in the handlers table: paths are reduced
+/// to their last segment and lifetimes are removed.
+fn normalize(ty: &Type) -> String {
+	match ty {
+		Type::Reference(r) => {
+			let m = if r.mutability.is_some() { "mut " } else { "" };
+			format!("&{}{}", m, normalize(&r.elem))
+		}
+		Type::Slice(s) => format!("[{}]", normalize(&s.elem)),
+		Type::Paren(p) => normalize(&p.elem),
+		Type::Group(g) => normalize(&g.elem),
+		Type::Tuple(t) if t.elems.is_empty() => String::from("()"),
+		Type::Path(p) if p.qself.is_none() => match p.path.segments.last() {
+			Some(seg) => {
+				let args = match &seg.arguments {
+					PathArguments::AngleBracketed(a) => {
+						let args: Vec<String> = a
+							.args
+							.iter()
+							.filter_map(|arg| match arg {
+								GenericArgument::Type(t) => Some(normalize(t)),
+								GenericArgument::Lifetime(_) => None,
+								other => Some(other.to_token_stream().to_string()),
+							})
+							.collect();
+						format!("<{}>", args.join(", "))
+					}
+					_ => String::new(),
+				};
+				format!("{}{}", seg.ident, args)
+			}
+			None => String::new(),
+		},
+		_ => ty.to_token_stream().to_string().replace(' ', ""),
+	}
+}
+
+fn is_session_context(ty: &Type) -> bool {
+	normalize(ty).starts_with("&mut SessionContext<")
+}
+
+/// Tells whether the handler receives a `SessionContext`, which is the
+/// case in a `SessionFilter`.
+pub(crate) fn has_session_param(sig: &Signature) -> bool {
+	match sig.inputs.iter().nth(2) {
+		Some(FnArg::Typed(t)) => is_session_context(&t.ty),
+		_ => false,
+	}
+}
+
+impl Handler {
+	pub(crate) fn is_filter(&self) -> bool {
+		self.kind != Kind::Report
+	}
+
+	pub(crate) fn has_name(&self) -> String {
+		self.name.replacen("on_", "has_", 1)
+	}
+
+	fn entry_type(&self) -> &'static str {
+		match self.kind {
+			Kind::Report => "&ReportEntry",
+			_ => "&FilterEntry",
+		}
+	}
+
+	fn return_type(&self) -> &'static str {
+		match self.kind {
+			Kind::Filter => "FilterResponse",
+			Kind::Message => "Vec<u8>",
+			Kind::DataLine | Kind::Report => "()",
+		}
+	}
+
+	/// Checks the parameters and the return type of the handler.
+	pub(crate) fn check_signature(&self, sig: &Signature, is_session: bool) -> Result<(), Error> {
+		let mut inputs = sig.inputs.iter();
+		match inputs.next() {
+			Some(FnArg::Receiver(_)) => {}
+			_ => {
+				return Err(Error::new_spanned(
+					&sig.inputs,
+					format!("`{}` must take `self` as first parameter", self.name),
+				));
+			}
+		}
+		let mut expected = vec![self.entry_type()];
+		if is_session {
+			expected.push("&mut SessionContext<Self::Session>");
+		}
+		expected.extend_from_slice(self.params);
+		if sig.inputs.len() - 1 != expected.len() {
+			return Err(Error::new_spanned(
+				&sig.inputs,
+				format!(
+					"`{}` takes {} parameter(s) after `self`: {}",
+					self.name,
+					expected.len(),
+					expected.join(", ")
+				),
+			));
+		}
+		for (input, expected) in inputs.zip(expected.iter()) {
+			let ty = match input {
+				FnArg::Typed(t) => &t.ty,
+				FnArg::Receiver(_) => continue,
+			};
+			let matches = if expected.starts_with("&mut SessionContext<") {
+				is_session_context(ty)
+			} else {
+				normalize(ty) == *expected
+			};
+			if !matches {
+				return Err(Error::new_spanned(
+					ty,
+					format!(
+						"mismatched parameter type in `{}`, expected `{}`",
+						self.name, expected
+					),
+				));
+			}
+		}
+		let output = match &sig.output {
+			ReturnType::Default => String::from("()"),
+			ReturnType::Type(_, ty) => normalize(ty),
+		};
+		// `async_trait` already turned the return type into a future.
+		if output.starts_with("Pin<") {
+			return Ok(());
+		}
+		if output != self.return_type() {
+			let msg = match self.kind {
+				Kind::DataLine | Kind::Report => {
+					format!("`{}` must not return anything", self.name)
+				}
+				_ => format!("`{}` must return `{}`", self.name, self.return_type()),
+			};
+			return Err(match &sig.output {
+				ReturnType::Default => Error::new_spanned(sig, msg),
+				ReturnType::Type(_, ty) => Error::new_spanned(ty, msg),
+			});
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{find, find_spanned, has_session_param, Kind, HANDLERS};
+	use syn::{parse_str, ItemFn};
+
+	/// Handler table of the `opensmtpd` crate, which this one copies.
+	const TABLE: &str = include_str!("../../opensmtpd/src/handlers.rs");
+
+	fn check(item: &str) -> Result<(), String> {
+		let item: ItemFn = parse_str(item).unwrap();
+		let handler = find_spanned(&item.sig.ident).map_err(|e| e.to_string())?;
+		handler
+			.check_signature(&item.sig, has_session_param(&item.sig))
+			.map_err(|e| e.to_string())
+	}
+
+	#[test]
+	fn test_valid() {
+		assert!(check(
+			"fn on_filter_helo(&mut self, e: &FilterEntry, id: &str) -> FilterResponse {}"
+		)
+		.is_ok());
+		assert!(check(
+			"fn on_report_tx_reset(&mut self, e: &opensmtpd::ReportEntry, id: &Option<String>) {}"
+		)
+		.is_ok());
+		assert!(
+			check("fn on_filter_data_line(&self, e: &'a FilterEntry, line: &'a [u8]) {}").is_ok()
+		);
+		assert!(check("fn on_message(&mut self, e: &FilterEntry, s: &mut SessionContext<S>, m: Message) -> Vec<u8> {}").is_ok());
+		assert!(check(
+			"fn on_report_timeout(&self, e: &ReportEntry) -> Pin<Box<dyn Future<Output = ()>>> {}"
+		)
+		.is_ok());
+	}
+
+	#[test]
+	fn test_unknown_name() {
+		assert_eq!(
+			check(
+				"fn on_filter_mailfrom(&mut self, e: &FilterEntry, a: &str) -> FilterResponse {}"
+			),
+			Err(
+				"unknown handler `on_filter_mailfrom`, did you mean `on_filter_mail_from`?"
+					.to_string()
+			)
+		);
+		assert_eq!(
+			check("fn on_something(&mut self) {}"),
+			Err("unknown handler `on_something`".to_string())
+		);
+		assert!(check("fn filter_helo(&mut self) {}").is_err());
+		assert!(check("fn on_config(&mut self) {}").is_err());
+	}
+
+	#[test]
+	fn test_invalid_signature() {
+		assert_eq!(
+			check("fn on_filter_helo(&mut self, e: &FilterEntry, id: String) -> FilterResponse {}"),
+			Err("mismatched parameter type in `on_filter_helo`, expected `&str`".to_string())
+		);
+		assert_eq!(
+			check("fn on_filter_helo(&mut self, e: &ReportEntry, id: &str) -> FilterResponse {}"),
+			Err(
+				"mismatched parameter type in `on_filter_helo`, expected `&FilterEntry`"
+					.to_string()
+			)
+		);
+		assert_eq!(
+			check("fn on_filter_helo(&mut self, e: &FilterEntry) -> FilterResponse {}"),
+			Err(
+				"`on_filter_helo` takes 2 parameter(s) after `self`: &FilterEntry, &str"
+					.to_string()
+			)
+		);
+		assert_eq!(
+			check("fn on_filter_helo(&mut self, e: &FilterEntry, id: &str) {}"),
+			Err("`on_filter_helo` must return `FilterResponse`".to_string())
+		);
+		assert_eq!(
+			check("fn on_report_timeout(&mut self, e: &ReportEntry) -> bool {}"),
+			Err("`on_report_timeout` must not return anything".to_string())
+		);
+		assert!(check("fn on_report_timeout(e: &ReportEntry) {}").is_err());
+	}
+
+	#[test]
+	fn test_same_table() {
+		let mut table = Vec::new();
+		for line in TABLE.lines().map(str::trim) {
+			if !line.starts_with("on_") {
+				continue;
+			}
+			let name = &line[..line.find(',').unwrap()];
+			let params = &line[line.find('(').unwrap() + 1..line.rfind(')').unwrap()];
+			let params: Vec<&str> = params
+				.split(", ")
+				.filter(|p| !p.is_empty())
+				.map(|p| &p[p.find(": ").unwrap() + 2..])
+				.collect();
+			table.push((name, params));
+		}
+		let handlers: Vec<(&str, Vec<&str>)> = HANDLERS
+			.iter()
+			.filter(|h| matches!(h.kind, Kind::Filter | Kind::Report))
+			.map(|h| (h.name, h.params.to_vec()))
+			.collect();
+		assert_eq!(handlers, table);
+	}
+
+	#[test]
+	fn test_find() {
+		assert!(find("on_filter_rcpt_to").is_some());
+		assert!(find("on_filter_data_end").is_none());
+	}
+}
@@ -1,18 +1,97 @@
+mod filter;
+mod handlers;
+
 use proc_macro::TokenStream;
 use proc_macro2::Span;
 use quote::quote;
-use syn::{parse_macro_input, Ident, ItemFn};
+use syn::{parse_macro_input, AttributeArgs, Error, Ident, ItemFn, ItemImpl, Meta, NestedMeta};
 
+/// Registers a handler of a `Filter`, `AsyncFilter` or
+/// `SessionFilter` implementation.
+///
+/// The subsystems may be listed, e.g. `#[register(smtp_in, smtp_out)]`,
+/// and default to `smtp_in`. The name and the signature of the handler
+/// are checked at compile time.
 #[proc_macro_attribute]
-pub fn register(_attr: TokenStream, input: TokenStream) -> TokenStream {
+pub fn register(attr: TokenStream, input: TokenStream) -> TokenStream {
+	let args = parse_macro_input!(attr as AttributeArgs);
 	let item = parse_macro_input!(input as ItemFn);
-	let fn_name = item.sig.ident.to_string().replacen("on_", "has_", 1);
-	let fn_name = Ident::new(&fn_name, Span::call_site());
-	let output = quote! {
-		fn #fn_name(&self) -> bool {
-			true
+	let res = handlers::find_spanned(&item.sig.ident).and_then(|handler| {
+		handler.check_signature(&item.sig, handlers::has_session_param(&item.sig))?;
+		Ok((handler, parse_subsystems(&args, handler.is_filter())?))
+	});
+	let (handler, subsystems) = match res {
+		Ok(r) => r,
+		Err(e) => {
+			let errors = e.to_compile_error();
+			return quote!(#errors #item).into();
 		}
+	};
+	let fn_name = Ident::new(&handler.has_name(), Span::call_site());
+	let has_fn = has_fn(&fn_name, &subsystems);
+	let output = quote! {
+		#has_fn
 		#item
 	};
 	output.into()
 }
+
+/// Registers every handler of a `Filter`, `AsyncFilter` or
+/// `SessionFilter` implementation.
+///
+/// The subsystems are the same as for `register`, filters being only
+/// registered for `smtp_in`. A handler may still use `register` to
+/// override them. Unknown handlers and wrong signatures are reported
+/// as compile errors. With `async_trait`, this attribute must be placed
+/// first.
+#[proc_macro_attribute]
+pub fn filter(attr: TokenStream, input: TokenStream) -> TokenStream {
+	let args = parse_macro_input!(attr as AttributeArgs);
+	let item = parse_macro_input!(input as ItemImpl);
+	match filter::expand(&args, item) {
+		Ok(output) => output.into(),
+		Err(e) => e.to_compile_error().into(),
+	}
+}
+
+fn has_fn(fn_name: &Ident, subsystems: &[Ident]) -> proc_macro2::TokenStream {
+	quote! {
+		fn #fn_name(&self, subsystem: &::opensmtpd::SubSystem) -> bool {
+			match subsystem {
+				#(::opensmtpd::SubSystem::#subsystems)|* => true,
+				_ => false,
+			}
+		}
+	}
+}
+
+fn parse_subsystems(args: &[NestedMeta], is_filter: bool) -> Result<Vec<Ident>, Error> {
+	if args.is_empty() {
+		return Ok(vec![Ident::new("SmtpIn", Span::call_site())]);
+	}
+	let mut subsystems = Vec::with_capacity(args.len());
+	for arg in args {
+		let name = match arg {
+			NestedMeta::Meta(Meta::Path(p)) => p.get_ident().map(|i| i.to_string()),
+			_ => None,
+		};
+		let subsystem = match name.as_deref() {
+			Some("smtp_in") => "SmtpIn",
+			Some("smtp_out") if is_filter => {
+				return Err(Error::new_spanned(
+					arg,
+					"filters are only available for the smtp-in subsystem",
+				));
+			}
+			Some("smtp_out") => "SmtpOut",
+			_ => {
+				return Err(Error::new_spanned(
+					arg,
+					"unknown subsystem, expected `smtp_in` or `smtp_out`",
+				));
+			}
+		};
+		subsystems.push(Ident::new(subsystem, Span::call_site()));
+	}
+	Ok(subsystems)
+}
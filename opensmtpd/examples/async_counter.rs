@@ -0,0 +1,59 @@
+use opensmtpd::{async_trait, run_async_filter, Address, AsyncFilter, ReportEntry};
+use opensmtpd_derive::register;
+use simplelog::{Config, LevelFilter, WriteLogger};
+use std::fs::File;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub const DEFAULT_LOG_FILE: &str = "/tmp/async_counter.log";
+
+#[derive(Default)]
+struct MyCounter {
+	nb_connected: AtomicU64,
+	nb_total: AtomicU64,
+}
+
+#[async_trait]
+impl AsyncFilter for MyCounter {
+	#[register]
+	async fn on_report_link_connect(
+		&self,
+		_entry: &ReportEntry,
+		_rdns: &str,
+		_fcrdns: &str,
+		_src: &Address,
+		_dest: &Address,
+	) {
+		let nb_connected = self.nb_connected.fetch_add(1, Ordering::SeqCst) + 1;
+		let nb_total = self.nb_total.fetch_add(1, Ordering::SeqCst) + 1;
+		log::info!(
+			"New client (connected: {}, total: {})",
+			nb_connected,
+			nb_total
+		);
+	}
+
+	#[register]
+	async fn on_report_link_disconnect(&self, _entry: &ReportEntry) {
+		let nb_connected = self.nb_connected.fetch_sub(1, Ordering::SeqCst) - 1;
+		log::info!(
+			"Client left (connected: {}, total: {})",
+			nb_connected,
+			self.nb_total.load(Ordering::SeqCst)
+		);
+	}
+}
+
+fn main() {
+	let log_file = std::env::var("LOG_FILE").unwrap_or(String::from(DEFAULT_LOG_FILE));
+	WriteLogger::init(
+		LevelFilter::Info,
+		Config::default(),
+		File::create(&log_file).unwrap(),
+	)
+	.unwrap();
+	let my_counter: MyCounter = Default::default();
+	let runtime = tokio::runtime::Builder::new_current_thread()
+		.build()
+		.unwrap();
+	runtime.block_on(run_async_filter(my_counter));
+}
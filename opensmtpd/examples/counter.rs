@@ -1,4 +1,3 @@
-use log;
 use opensmtpd::{run_filter, Address, Filter, ReportEntry};
 use opensmtpd_derive::register;
 use simplelog::{Config, LevelFilter, WriteLogger};
@@ -1,27 +1,48 @@
-use opensmtpd::{return_data_line, run_filter, Filter, FilterEntry};
+use opensmtpd::{
+	return_data_end, return_data_line, return_headers, run_filter, FilterEntry, HeaderEvent,
+	HeaderReader, SessionContext, SessionFilter, Sessions,
+};
 use opensmtpd_derive::register;
 
-pub const HEADER_NAME: &str = "x-originating-ip:";
-pub const HEADER_LEN: usize = 17;
+pub const HEADER_NAME: &str = "X-Originating-Ip";
 
 struct RmXOriginatingIp {}
 
-impl Filter for RmXOriginatingIp {
+impl SessionFilter for RmXOriginatingIp {
+	type Session = HeaderReader;
+
 	#[register]
-	fn on_filter_data_line(&mut self, entry: &FilterEntry, data_line: &[u8]) {
-		if data_line.len() >= HEADER_LEN {
-			let head_start = data_line[..HEADER_LEN].to_vec();
-			if let Ok(s) = String::from_utf8(head_start) {
-				if s.to_lowercase() == HEADER_NAME {
-					return;
-				}
+	fn on_filter_data_line(
+		&mut self,
+		entry: &FilterEntry,
+		reader: &mut SessionContext<HeaderReader>,
+		data_line: &[u8],
+	) {
+		match reader.push(data_line) {
+			HeaderEvent::Header => {}
+			HeaderEvent::EndOfHeaders(mut headers) => {
+				headers.remove(HEADER_NAME);
+				return_headers(entry, &headers);
+				return_data_line(entry, data_line);
 			}
+			HeaderEvent::Body => return_data_line(entry, data_line),
+		}
+	}
+
+	fn on_filter_data_end(
+		&mut self,
+		entry: &FilterEntry,
+		reader: &mut SessionContext<HeaderReader>,
+	) {
+		if let Some(mut headers) = reader.end() {
+			headers.remove(HEADER_NAME);
+			return_headers(entry, &headers);
 		}
-		return_data_line(entry, data_line);
+		return_data_end(entry);
 	}
 }
 
 fn main() {
-	let mut my_filter = RmXOriginatingIp {};
+	let mut my_filter = Sessions::new(RmXOriginatingIp {});
 	run_filter(&mut my_filter);
 }
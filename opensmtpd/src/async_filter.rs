@@ -0,0 +1,86 @@
+use crate::{
+	return_data_end, Address, AuthResult, FilterConfig, FilterEntry, FilterKind, FilterPhase,
+	FilterResponse, MailResult, Message, Method, ReportEntry, ShutdownReason, SubSystem,
+};
+use async_trait::async_trait;
+
+macro_rules! async_filter_trait {
+	(
+		filters { $($(#[$f_attr: meta])* $f_name: ident, $f_has: ident, ($($f_param: ident: $f_type: ty),*);)* }
+		reports { $($(#[$r_attr: meta])* $r_name: ident, $r_has: ident, $r_event: ident, ($($r_param: ident: $r_type: ty),*);)* }
+	) => {
+		/// Asynchronous counterpart of the [`Filter`](crate::Filter) trait.
+		///
+		/// Handlers take `&self` because the filter is shared between the
+		/// tasks processing concurrent sessions: use interior mutability to
+		/// keep some state. Filter requests are processed concurrently while
+		/// reports and data-lines are processed in the order they are
+		/// received.
+		#[async_trait]
+		pub trait AsyncFilter: Send + Sync {
+			/// Called with the configuration sent by OpenSMTPD during the
+			/// handshake, before the filter registers its events.
+			async fn on_config(&self, _config: &FilterConfig) {}
+
+			/// Called once the handshake with OpenSMTPD is done, before any
+			/// report or filter request is processed.
+			async fn on_ready(&self) {}
+
+			/// Called when the filter stops processing its input, before
+			/// returning from the runner.
+			async fn on_shutdown(&self, _reason: &ShutdownReason) {}
+
+			/// Called after a handler panicked while processing an event of the
+			/// given session. The panic has been caught and the filter keeps
+			/// processing the other events.
+			async fn on_panic(&self, _session_id: &str, _message: &str) {}
+
+			$(
+				$(#[$f_attr])*
+				#[allow(unused_variables)]
+				async fn $f_name(&self, entry: &FilterEntry, $($f_param: $f_type),*) -> FilterResponse {
+					FilterResponse::Proceed
+				}
+				#[doc(hidden)]
+				fn $f_has(&self, _subsystem: &SubSystem) -> bool {
+					false
+				}
+			)*
+
+			async fn on_filter_data_line(&self, _entry: &FilterEntry, _data_line: &[u8]) {}
+			#[doc(hidden)]
+			fn has_filter_data_line(&self, _subsystem: &SubSystem) -> bool {
+				false
+			}
+
+			/// Called once every data-line of the message has been received.
+			/// The default implementation ends the message sent back to
+			/// OpenSMTPD.
+			async fn on_filter_data_end(&self, entry: &FilterEntry) {
+				return_data_end(entry);
+			}
+
+			/// Called with the complete message instead of the individual
+			/// data-lines. The returned content is sent back to OpenSMTPD.
+			async fn on_message(&self, _entry: &FilterEntry, message: Message) -> Vec<u8> {
+				message.into_bytes()
+			}
+			#[doc(hidden)]
+			fn has_message(&self, _subsystem: &SubSystem) -> bool {
+				false
+			}
+
+			$(
+				$(#[$r_attr])*
+				#[allow(unused_variables)]
+				async fn $r_name(&self, entry: &ReportEntry, $($r_param: $r_type),*) {}
+				#[doc(hidden)]
+				fn $r_has(&self, _subsystem: &SubSystem) -> bool {
+					false
+				}
+			)*
+		}
+	};
+}
+
+for_each_handler!(async_filter_trait);
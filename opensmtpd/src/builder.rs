@@ -0,0 +1,241 @@
+use crate::data_line::return_data_end;
+use crate::{
+	Address, AuthResult, Filter, FilterEntry, FilterKind, FilterPhase, FilterResponse, MailResult,
+	Message, Method, ReportEntry, SubSystem,
+};
+
+macro_rules! closure_filter {
+	(
+		filters { $($(#[$f_attr: meta])* $f_name: ident, $f_has: ident, ($($f_param: ident: $f_type: ty),*);)* }
+		reports { $($(#[$r_attr: meta])* $r_name: ident, $r_has: ident, $r_event: ident, ($($r_param: ident: $r_type: ty),*);)* }
+	) => {
+		/// Filter made of closures, built using a [`FilterBuilder`].
+		pub struct ClosureFilter {
+			report_subsystems: Vec<SubSystem>,
+			on_filter_data_line: Option<Box<dyn FnMut(&FilterEntry, &[u8])>>,
+			on_filter_data_end: Option<Box<dyn FnMut(&FilterEntry)>>,
+			on_message: Option<Box<dyn FnMut(&FilterEntry, Message) -> Vec<u8>>>,
+			$($f_name: Option<Box<dyn FnMut(&FilterEntry, $($f_type),*) -> FilterResponse>>,)*
+			$($r_name: Option<Box<dyn FnMut(&ReportEntry, $($r_type),*)>>,)*
+		}
+
+		impl Default for ClosureFilter {
+			fn default() -> Self {
+				ClosureFilter {
+					report_subsystems: vec![SubSystem::SmtpIn],
+					on_filter_data_line: None,
+					on_filter_data_end: None,
+					on_message: None,
+					$($f_name: None,)*
+					$($r_name: None,)*
+				}
+			}
+		}
+
+		impl FilterBuilder {
+			$(
+				pub fn $f_name<F>(mut self, f: F) -> Self
+				where
+					F: FnMut(&FilterEntry, $($f_type),*) -> FilterResponse + 'static,
+				{
+					self.filter.$f_name = Some(Box::new(f));
+					self
+				}
+			)*
+
+			$(
+				pub fn $r_name<F>(mut self, f: F) -> Self
+				where
+					F: FnMut(&ReportEntry, $($r_type),*) + 'static,
+				{
+					self.filter.$r_name = Some(Box::new(f));
+					self
+				}
+			)*
+		}
+
+		impl Filter for ClosureFilter {
+			$(
+				fn $f_name(&mut self, entry: &FilterEntry, $($f_param: $f_type),*) -> FilterResponse {
+					match &mut self.$f_name {
+						Some(f) => f(entry, $($f_param),*),
+						None => FilterResponse::Proceed,
+					}
+				}
+
+				fn $f_has(&self, subsystem: &SubSystem) -> bool {
+					self.$f_name.is_some() && *subsystem == SubSystem::SmtpIn
+				}
+			)*
+
+			$(
+				fn $r_name(&mut self, entry: &ReportEntry, $($r_param: $r_type),*) {
+					if let Some(f) = &mut self.$r_name {
+						f(entry, $($r_param),*);
+					}
+				}
+
+				fn $r_has(&self, subsystem: &SubSystem) -> bool {
+					self.$r_name.is_some() && self.report_subsystems.contains(subsystem)
+				}
+			)*
+
+			fn on_filter_data_line(&mut self, entry: &FilterEntry, data_line: &[u8]) {
+				if let Some(f) = &mut self.on_filter_data_line {
+					f(entry, data_line);
+				}
+			}
+
+			fn has_filter_data_line(&self, subsystem: &SubSystem) -> bool {
+				self.on_filter_data_line.is_some() && *subsystem == SubSystem::SmtpIn
+			}
+
+			fn on_filter_data_end(&mut self, entry: &FilterEntry) {
+				match &mut self.on_filter_data_end {
+					Some(f) => f(entry),
+					None => return_data_end(entry),
+				}
+			}
+
+			fn on_message(&mut self, entry: &FilterEntry, message: Message) -> Vec<u8> {
+				match &mut self.on_message {
+					Some(f) => f(entry, message),
+					None => message.into_bytes(),
+				}
+			}
+
+			fn has_message(&self, subsystem: &SubSystem) -> bool {
+				self.on_message.is_some() && *subsystem == SubSystem::SmtpIn
+			}
+		}
+	};
+}
+
+/// Builds a filter from closures, for filters whose handlers are only
+/// known at runtime.
+///
+/// Only the handlers which have been set are registered. Filters are
+/// registered for the `smtp-in` subsystem and reports for the
+/// subsystems set using
+/// [`report_subsystems`](FilterBuilder::report_subsystems).
+///
+/// ``` rust,no_run
+/// use opensmtpd::{run_filter, FilterBuilder, FilterResponse, SmtpStatusCode};
+///
+/// let max_rcpt = 42;
+/// let mut nb_rcpt = 0;
+/// let mut filter = FilterBuilder::new()
+///		.on_filter_rcpt_to(move |_entry, _address| {
+///			nb_rcpt += 1;
+///			if nb_rcpt > max_rcpt {
+///				FilterResponse::Reject(SmtpStatusCode::from_number(452))
+///			} else {
+///				FilterResponse::Proceed
+///			}
+///		})
+///		.on_report_tx_commit(|entry, message_id, size| {
+///			println!("{}: {} ({} bytes)", entry.session_id, message_id, size);
+///		})
+///		.build();
+/// run_filter(&mut filter);
+/// ```
+#[derive(Default)]
+pub struct FilterBuilder {
+	filter: ClosureFilter,
+}
+
+impl FilterBuilder {
+	pub fn new() -> Self {
+		FilterBuilder::default()
+	}
+
+	/// Sets the subsystems the reports are registered for. Defaults to
+	/// `smtp-in` only.
+	pub fn report_subsystems(mut self, subsystems: &[SubSystem]) -> Self {
+		self.filter.report_subsystems = subsystems.to_vec();
+		self
+	}
+
+	/// Sets the function called for each data-line, which must send
+	/// the lines back using [`return_data_line`](crate::return_data_line).
+	pub fn on_filter_data_line<F>(mut self, f: F) -> Self
+	where
+		F: FnMut(&FilterEntry, &[u8]) + 'static,
+	{
+		self.filter.on_filter_data_line = Some(Box::new(f));
+		self
+	}
+
+	/// Sets the function called at the end of the message, which must
+	/// call [`return_data_end`](crate::return_data_end). Defaults to
+	/// calling it directly.
+	pub fn on_filter_data_end<F>(mut self, f: F) -> Self
+	where
+		F: FnMut(&FilterEntry) + 'static,
+	{
+		self.filter.on_filter_data_end = Some(Box::new(f));
+		self
+	}
+
+	/// Sets the function called with the complete message instead of
+	/// the individual data-lines, see [`Filter::on_message`].
+	pub fn on_message<F>(mut self, f: F) -> Self
+	where
+		F: FnMut(&FilterEntry, Message) -> Vec<u8> + 'static,
+	{
+		self.filter.on_message = Some(Box::new(f));
+		self
+	}
+
+	pub fn build(self) -> ClosureFilter {
+		self.filter
+	}
+}
+
+for_each_handler!(closure_filter);
+
+#[cfg(test)]
+mod tests {
+	use super::FilterBuilder;
+	use crate::runner::tests::run_lines;
+	use crate::{FilterResponse, SubSystem};
+	use std::cell::RefCell;
+	use std::rc::Rc;
+
+	#[test]
+	fn test_builder() {
+		let commits = Rc::new(RefCell::new(Vec::new()));
+		let commits_handler = Rc::clone(&commits);
+		let mut filter = FilterBuilder::new()
+			.report_subsystems(&[SubSystem::SmtpIn, SubSystem::SmtpOut])
+			.on_filter_helo(|_entry, identity| match identity {
+				"spammer" => FilterResponse::Junk,
+				_ => FilterResponse::Proceed,
+			})
+			.on_report_tx_commit(move |entry, message_id, size| {
+				commits_handler.borrow_mut().push((
+					entry.subsystem.clone(),
+					message_id.to_string(),
+					size,
+				));
+			})
+			.build();
+		let input = "filter|0.5|1576146008.006099|smtp-in|helo|s1|t1|spammer\n\
+			report|0.5|1576146008.006099|smtp-out|tx-commit|s2|m1|4242\n";
+		let lines = run_lines(&mut filter, input);
+		assert_eq!(
+			lines,
+			vec![
+				"register|filter|smtp-in|helo",
+				"register|report|smtp-in|tx-commit",
+				"register|report|smtp-out|tx-commit",
+				"register|ready",
+				"filter-result|s1|t1|junk",
+			]
+		);
+		assert_eq!(
+			*commits.borrow(),
+			vec![(SubSystem::SmtpOut, "m1".to_string(), 4242)]
+		);
+	}
+}
@@ -0,0 +1,511 @@
+use crate::data_line::{return_data_end, return_data_line};
+use crate::io::{Buffer, Output};
+use crate::message::{return_message, MessageBuffers, DEFAULT_MAX_MESSAGE_SIZE};
+use crate::responder::ordered_ids;
+use crate::{
+	Address, AuthResult, Event, Filter, FilterConfig, FilterEntry, FilterKind, FilterPhase,
+	FilterResponse, MailResult, Method, ProtocolVersion, ReportEntry, ShutdownReason, SubSystem,
+};
+use std::panic::{catch_unwind, resume_unwind, AssertUnwindSafe};
+
+macro_rules! chain_filter {
+	(
+		filters { $($(#[$f_attr: meta])* $f_name: ident, $f_has: ident, ($($f_param: ident: $f_type: ty),*);)* }
+		reports { $($(#[$r_attr: meta])* $r_name: ident, $r_has: ident, $r_event: ident, ($($r_param: ident: $r_type: ty),*);)* }
+	) => {
+		impl Filter for FilterChain {
+			fn on_config(&mut self, config: &FilterConfig) {
+				for stage in self.stages.iter_mut() {
+					stage.filter.on_config(config);
+				}
+			}
+
+			fn on_ready(&mut self) {
+				for stage in self.stages.iter_mut() {
+					stage.filter.on_ready();
+				}
+			}
+
+			fn on_shutdown(&mut self, reason: &ShutdownReason) {
+				for stage in self.stages.iter_mut() {
+					stage.filter.on_shutdown(reason);
+				}
+			}
+
+			fn on_panic(&mut self, session_id: &str, message: &str) {
+				for stage in self.stages.iter_mut() {
+					stage.filter.on_panic(session_id, message);
+				}
+			}
+
+			$(
+				fn $f_name(&mut self, entry: &FilterEntry, $($f_param: $f_type),*) -> FilterResponse {
+					for stage in self.stages.iter_mut() {
+						if !stage.filter.$f_has(&entry.subsystem) {
+							continue;
+						}
+						match stage.filter.$f_name(entry, $($f_param),*) {
+							FilterResponse::Proceed => {}
+							response => return response,
+						}
+					}
+					FilterResponse::Proceed
+				}
+
+				fn $f_has(&self, subsystem: &SubSystem) -> bool {
+					self.stages.iter().any(|s| s.filter.$f_has(subsystem))
+				}
+			)*
+
+			fn on_filter_data_line(&mut self, entry: &FilterEntry, data_line: &[u8]) {
+				self.pipe(0, entry, Some(data_line));
+			}
+
+			fn on_filter_data_end(&mut self, entry: &FilterEntry) {
+				self.pipe(0, entry, None);
+			}
+
+			fn has_filter_data_line(&self, subsystem: &SubSystem) -> bool {
+				self.stages.iter().any(|s| s.handles_data(subsystem))
+			}
+
+			$(
+				fn $r_name(&mut self, entry: &ReportEntry, $($r_param: $r_type),*) {
+					// The messages of the sessions which end during DATA
+					// are dropped.
+					let ends_session = Event::$r_event.ends_session();
+					for stage in self.stages.iter_mut() {
+						if ends_session {
+							stage.messages.purge(&entry.session_id);
+						}
+						if stage.filter.$r_has(&entry.subsystem) {
+							stage.filter.$r_name(entry, $(Clone::clone(&$r_param)),*);
+						}
+					}
+				}
+
+				fn $r_has(&self, subsystem: &SubSystem) -> bool {
+					let ends_session = Event::$r_event.ends_session();
+					self.stages.iter().any(|s| {
+						s.filter.$r_has(subsystem) || (ends_session && s.filter.has_message(subsystem))
+					})
+				}
+			)*
+		}
+	};
+}
+
+/// Returns the data-lines written by a stage as (session id, token,
+/// line), the line being `None` for the end of the message.
+fn take_data_lines(
+	capture: &Buffer,
+	version: ProtocolVersion,
+) -> Vec<(String, String, Option<Vec<u8>>)> {
+	let buffer = capture.take();
+	let mut lines = Vec::new();
+	for line in buffer.split(|&c| c == b'\n').filter(|l| !l.is_empty()) {
+		let fields: Vec<&[u8]> = line.splitn(4, |&c| c == b'|').collect();
+		if let [b"filter-dataline", first_id, second_id, data_line] = fields.as_slice() {
+			let data_line = match *data_line {
+				b"." => None,
+				l => Some(l.strip_prefix(b".").unwrap_or(l).to_vec()),
+			};
+			let first_id = String::from_utf8_lossy(first_id);
+			let second_id = String::from_utf8_lossy(second_id);
+			// Swapping the ids again gives back the original order.
+			let (session_id, token) = ordered_ids(version, &first_id, &second_id);
+			lines.push((session_id.to_string(), token.to_string(), data_line));
+		}
+	}
+	lines
+}
+
+struct Stage {
+	filter: Box<dyn Filter>,
+	capture: Buffer,
+	output: Output,
+	messages: MessageBuffers,
+}
+
+impl Stage {
+	fn handles_data(&self, subsystem: &SubSystem) -> bool {
+		self.filter.has_filter_data_line(subsystem) || self.filter.has_message(subsystem)
+	}
+
+	fn process_data(&mut self, entry: &FilterEntry, data_line: Option<&[u8]>) {
+		if self.filter.has_message(&entry.subsystem) {
+			if let Some(message) = self.messages.push(entry, data_line) {
+				let content = self.filter.on_message(entry, message);
+				return_message(entry, &content);
+			}
+			return;
+		}
+		match data_line {
+			Some(data_line) => self.filter.on_filter_data_line(entry, data_line),
+			None => self.filter.on_filter_data_end(entry),
+		}
+	}
+}
+
+/// Several filters run as a single one.
+///
+/// The chain registers every event needed by its filters. Reports are
+/// sent to each filter which registered them. Filter requests go
+/// through the filters in order until one of them returns something
+/// else than [`FilterResponse::Proceed`], which is the response sent
+/// to OpenSMTPD. The data-lines returned by a filter are given to the
+/// next one, the last filter sending them back to OpenSMTPD.
+///
+/// A deferred response ([`FilterResponse::Pending`]) is final as well:
+/// the response sent later by the
+/// [`FilterResponder`](crate::FilterResponder) goes straight to
+/// OpenSMTPD and the next filters are not called for this request.
+///
+/// The data-lines returned by a filter are given to the next one when
+/// its handler returns. Lines returned after that, e.g. from another
+/// thread, are only given to the next filter, in the order they were
+/// returned, when a data-line goes through the filter again.
+///
+/// ``` rust,no_run
+/// use opensmtpd::{run_filter, Filter, FilterChain};
+///
+/// struct Greylist {}
+/// impl Filter for Greylist {}
+///
+/// struct Dkim {}
+/// impl Filter for Dkim {}
+///
+/// let mut chain = FilterChain::new().with(Greylist {}).with(Dkim {});
+/// run_filter(&mut chain);
+/// ```
+pub struct FilterChain {
+	stages: Vec<Stage>,
+	max_message_size: usize,
+}
+
+impl Default for FilterChain {
+	fn default() -> Self {
+		FilterChain {
+			stages: Vec::new(),
+			max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+		}
+	}
+}
+
+impl FilterChain {
+	pub fn new() -> Self {
+		FilterChain::default()
+	}
+
+	/// Adds a filter at the end of the chain.
+	pub fn with<F>(mut self, filter: F) -> Self
+	where
+		F: Filter + 'static,
+	{
+		let capture = Buffer::default();
+		self.stages.push(Stage {
+			filter: Box::new(filter),
+			output: Output::new(capture.clone()),
+			capture,
+			messages: MessageBuffers::new(self.max_message_size),
+		});
+		self
+	}
+
+	/// Sets the maximum size of the messages buffered for the filters
+	/// of the chain using [`on_message`](Filter::on_message).
+	pub fn max_message_size(mut self, size: usize) -> Self {
+		self.max_message_size = size;
+		for stage in self.stages.iter_mut() {
+			stage.messages = MessageBuffers::new(size);
+		}
+		self
+	}
+
+	pub fn len(&self) -> usize {
+		self.stages.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.stages.is_empty()
+	}
+
+	/// Gives a data-line, or the end of the message, to the first filter
+	/// handling them starting at `index`.
+	fn pipe(&mut self, index: usize, entry: &FilterEntry, data_line: Option<&[u8]>) {
+		let pos = self.stages[index..]
+			.iter()
+			.position(|s| s.handles_data(&entry.subsystem));
+		let index = match pos {
+			Some(pos) => index + pos,
+			None => {
+				match data_line {
+					Some(data_line) => return_data_line(entry, data_line),
+					None => return_data_end(entry),
+				}
+				return;
+			}
+		};
+		let stage = &mut self.stages[index];
+		let mut stage_entry = entry.clone();
+		stage_entry.output = stage.output.clone();
+		let res = catch_unwind(AssertUnwindSafe(|| {
+			stage.process_data(&stage_entry, data_line)
+		}));
+		if let Err(payload) = res {
+			// The lines returned before the panic must not be sent with
+			// the next data-line: the runner passes the message through.
+			stage.capture.take();
+			resume_unwind(payload);
+		}
+		for (session_id, token, data_line) in take_data_lines(&stage.capture, entry.version) {
+			let mut next_entry = entry.clone();
+			next_entry.session_id = session_id;
+			next_entry.token = token;
+			self.pipe(index + 1, &next_entry, data_line.as_deref());
+		}
+	}
+}
+
+for_each_handler!(chain_filter);
+
+#[cfg(test)]
+mod tests {
+	use super::FilterChain;
+	use crate::runner::tests::run_lines;
+	use crate::{
+		return_data_end, return_data_line, Filter, FilterEntry, FilterResponder, FilterResponse,
+		Message, ReportEntry, SubSystem,
+	};
+	use std::cell::RefCell;
+	use std::rc::Rc;
+	use std::thread::{self, JoinHandle};
+
+	struct Helo {
+		calls: Rc<RefCell<Vec<&'static str>>>,
+		name: &'static str,
+		junk: &'static str,
+	}
+
+	impl Filter for Helo {
+		fn on_filter_helo(&mut self, entry: &FilterEntry, identity: &str) -> FilterResponse {
+			self.calls.borrow_mut().push(self.name);
+			if identity == "deferred" {
+				FilterResponder::new(entry).respond(FilterResponse::Junk);
+				FilterResponse::Pending
+			} else if identity == self.junk {
+				FilterResponse::Junk
+			} else {
+				FilterResponse::Proceed
+			}
+		}
+
+		fn has_filter_helo(&self, subsystem: &SubSystem) -> bool {
+			*subsystem == SubSystem::SmtpIn
+		}
+
+		fn on_report_link_disconnect(&mut self, _entry: &ReportEntry) {
+			self.calls.borrow_mut().push(self.name);
+		}
+
+		fn has_report_link_disconnect(&self, subsystem: &SubSystem) -> bool {
+			*subsystem == SubSystem::SmtpIn
+		}
+	}
+
+	struct Upper {}
+
+	impl Filter for Upper {
+		fn on_filter_data_line(&mut self, entry: &FilterEntry, data_line: &[u8]) {
+			return_data_line(entry, &data_line.to_ascii_uppercase());
+		}
+
+		fn has_filter_data_line(&self, subsystem: &SubSystem) -> bool {
+			*subsystem == SubSystem::SmtpIn
+		}
+	}
+
+	struct Footer {}
+
+	impl Filter for Footer {
+		fn on_message(&mut self, _entry: &FilterEntry, message: Message) -> Vec<u8> {
+			let mut content = message.into_bytes();
+			content.extend_from_slice(b".footer\r\n");
+			content
+		}
+
+		fn has_message(&self, subsystem: &SubSystem) -> bool {
+			*subsystem == SubSystem::SmtpIn
+		}
+	}
+
+	#[test]
+	fn test_chain() {
+		let calls = Rc::new(RefCell::new(Vec::new()));
+		let mut chain = FilterChain::new()
+			.with(Helo {
+				calls: Rc::clone(&calls),
+				name: "first",
+				junk: "spammer",
+			})
+			.with(Upper {})
+			.with(Footer {})
+			.with(Helo {
+				calls: Rc::clone(&calls),
+				name: "second",
+				junk: "other",
+			});
+		assert_eq!(chain.len(), 4);
+		let input = "filter|0.5|1576146008.006099|smtp-in|helo|s1|t1|spammer\n\
+			filter|0.5|1576146008.006099|smtp-in|helo|s1|t2|other\n\
+			filter|0.5|1576146008.006099|smtp-in|helo|s1|t4|deferred\n\
+			filter|0.5|1576146008.006099|smtp-in|data-line|s1|t3|..dot\n\
+			filter|0.5|1576146008.006099|smtp-in|data-line|s1|t3|text\n\
+			filter|0.5|1576146008.006099|smtp-in|data-line|s1|t3|.\n\
+			report|0.5|1576146008.006099|smtp-in|link-disconnect|s1\n";
+		let lines = run_lines(&mut chain, input);
+		assert_eq!(
+			lines,
+			vec![
+				"register|filter|smtp-in|data-line",
+				"register|filter|smtp-in|helo",
+				"register|report|smtp-in|link-disconnect",
+				"register|report|smtp-in|timeout",
+				"register|ready",
+				"filter-result|s1|t1|junk",
+				"filter-result|s1|t2|junk",
+				"filter-result|s1|t4|junk",
+				"filter-dataline|s1|t3|..DOT",
+				"filter-dataline|s1|t3|TEXT",
+				"filter-dataline|s1|t3|..footer",
+				"filter-dataline|s1|t3|.",
+			]
+		);
+		assert_eq!(
+			*calls.borrow(),
+			vec!["first", "first", "second", "first", "first", "second"]
+		);
+	}
+
+	#[test]
+	fn test_session_end() {
+		let mut chain = FilterChain::new().with(Upper {}).with(Footer {});
+		let input = "filter|0.5|1576146008.006099|smtp-in|data-line|s1|t1|lost\n\
+			report|0.5|1576146008.006099|smtp-in|link-disconnect|s1\n\
+			filter|0.5|1576146008.006099|smtp-in|data-line|s1|t1|kept\n\
+			filter|0.5|1576146008.006099|smtp-in|data-line|s1|t1|.\n";
+		let lines = run_lines(&mut chain, input);
+		assert_eq!(
+			&lines[4..],
+			&[
+				"filter-dataline|s1|t1|KEPT",
+				"filter-dataline|s1|t1|..footer",
+				"filter-dataline|s1|t1|.",
+			]
+		);
+	}
+
+	#[test]
+	fn test_protocol_0_4() {
+		let mut chain = FilterChain::new().with(Upper {}).with(Footer {});
+		let input = "filter|0.4|1576146008.006099|smtp-in|data-line|s1|t1|text\n\
+			filter|0.4|1576146008.006099|smtp-in|data-line|s1|t1|.\n";
+		let lines = run_lines(&mut chain, input);
+		assert_eq!(
+			&lines[4..],
+			&[
+				"filter-dataline|t1|s1|TEXT",
+				"filter-dataline|t1|s1|..footer",
+				"filter-dataline|t1|s1|.",
+			]
+		);
+	}
+
+	/// Returns the data-lines, then panics on the "panic" one.
+	struct PanicLine {}
+
+	impl Filter for PanicLine {
+		fn on_filter_data_line(&mut self, entry: &FilterEntry, data_line: &[u8]) {
+			return_data_line(entry, data_line);
+			if data_line == b"panic" {
+				panic!("invalid data-line");
+			}
+		}
+
+		fn has_filter_data_line(&self, subsystem: &SubSystem) -> bool {
+			*subsystem == SubSystem::SmtpIn
+		}
+	}
+
+	#[test]
+	fn test_panic() {
+		let mut chain = FilterChain::new().with(PanicLine {}).with(Upper {});
+		let input = "filter|0.5|1576146008.006099|smtp-in|data-line|s1|t1|first\n\
+			filter|0.5|1576146008.006099|smtp-in|data-line|s1|t1|panic\n\
+			filter|0.5|1576146008.006099|smtp-in|data-line|s1|t1|.\n\
+			filter|0.5|1576146008.006099|smtp-in|data-line|s1|t2|ok\n\
+			filter|0.5|1576146008.006099|smtp-in|data-line|s1|t2|.\n";
+		let lines = run_lines(&mut chain, input);
+		assert_eq!(
+			&lines[2..],
+			&[
+				"filter-dataline|s1|t1|FIRST",
+				"filter-dataline|s1|t1|panic",
+				"filter-dataline|s1|t1|.",
+				"filter-dataline|s1|t2|OK",
+				"filter-dataline|s1|t2|.",
+			]
+		);
+	}
+
+	/// Returns each data-line from another thread, once the handler
+	/// returned.
+	#[derive(Default)]
+	struct Later {
+		pending: Option<JoinHandle<()>>,
+	}
+
+	impl Later {
+		fn wait(&mut self) {
+			if let Some(pending) = self.pending.take() {
+				pending.join().unwrap();
+			}
+		}
+	}
+
+	impl Filter for Later {
+		fn on_filter_data_line(&mut self, entry: &FilterEntry, data_line: &[u8]) {
+			self.wait();
+			let (entry, data_line) = (entry.clone(), data_line.to_vec());
+			self.pending = Some(thread::spawn(move || return_data_line(&entry, &data_line)));
+		}
+
+		fn on_filter_data_end(&mut self, entry: &FilterEntry) {
+			self.wait();
+			return_data_end(entry);
+		}
+
+		fn has_filter_data_line(&self, subsystem: &SubSystem) -> bool {
+			*subsystem == SubSystem::SmtpIn
+		}
+	}
+
+	#[test]
+	fn test_late_data_lines() {
+		// Each line is given to the next filter with the following one,
+		// in the order they were returned.
+		let mut chain = FilterChain::new().with(Later::default()).with(Upper {});
+		let input = "filter|0.5|1576146008.006099|smtp-in|data-line|s1|t1|first\n\
+			filter|0.5|1576146008.006099|smtp-in|data-line|s1|t1|second\n\
+			filter|0.5|1576146008.006099|smtp-in|data-line|s1|t1|.\n";
+		let lines = run_lines(&mut chain, input);
+		assert_eq!(
+			&lines[2..],
+			&[
+				"filter-dataline|s1|t1|FIRST",
+				"filter-dataline|s1|t1|SECOND",
+				"filter-dataline|s1|t1|.",
+			]
+		);
+	}
+}
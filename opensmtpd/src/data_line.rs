@@ -1,16 +1,82 @@
+use crate::responder::ordered_ids;
 use crate::FilterEntry;
-use std::io::{self, Write};
 
+/// Sends a line of the message back to OpenSMTPD.
+///
+/// Lines starting with a dot are stuffed, hence this function cannot
+/// be used to end the message: use [`return_data_end`] instead. The
+/// content is sent byte for byte, except for the line feeds: a final
+/// one is dropped and the others split the content into several lines.
 pub fn return_data_line(entry: &FilterEntry, data_line: &[u8]) {
-	let mut data_line = data_line.to_vec();
-	data_line.retain(|&c| c != 0x0d && c != 0x0a);
-	print!("filter-dataline|{}|{}|", entry.session_id, entry.token);
-	io::stdout().write_all(&data_line).unwrap();
-	println!();
+	let data_line = data_line.strip_suffix(b"\n").unwrap_or(data_line);
+	for line in data_line.split(|&c| c == b'\n') {
+		if line.first() == Some(&b'.') {
+			let mut stuffed = Vec::with_capacity(line.len() + 1);
+			stuffed.push(b'.');
+			stuffed.extend_from_slice(line);
+			send_data_line(entry, &stuffed);
+		} else {
+			send_data_line(entry, line);
+		}
+	}
+}
+
+/// Ends the message sent back to OpenSMTPD.
+pub fn return_data_end(entry: &FilterEntry) {
+	send_data_line(entry, b".");
+}
+
+fn send_data_line(entry: &FilterEntry, data_line: &[u8]) {
+	let (first_id, second_id) = ordered_ids(entry.version, &entry.session_id, &entry.token);
+	let mut line = format!("filter-dataline|{}|{}|", first_id, second_id).into_bytes();
+	line.extend_from_slice(data_line);
+	if let Err(e) = entry.output.write_line(&line) {
+		entry.output.report_error(e);
+		return;
+	}
 	log::trace!(
 		"Sent filter-dataline (session:id: {}, token: {}){}",
 		entry.session_id,
 		entry.token,
-		crate::error::get_pretty_hex(&data_line)
+		crate::error::get_pretty_hex(data_line)
 	);
 }
+
+#[cfg(test)]
+mod tests {
+	use super::return_data_line;
+	use crate::io::{Buffer, Output};
+	use crate::{FilterEntry, FilterPhase, ProtocolVersion, SubSystem, TimeVal};
+
+	fn entry(output: &Buffer) -> FilterEntry {
+		FilterEntry {
+			version: ProtocolVersion::V0_5,
+			timestamp: TimeVal { sec: 0, usec: 0 },
+			subsystem: SubSystem::SmtpIn,
+			phase: FilterPhase::DataLine,
+			session_id: "s".to_string(),
+			token: "t".to_string(),
+			output: Output::new(output.clone()),
+		}
+	}
+
+	#[test]
+	fn test_return_data_line() {
+		let output = Buffer::default();
+		let entry = entry(&output);
+		return_data_line(&entry, b"caf\xe9\r");
+		return_data_line(&entry, b".hidden\n");
+		return_data_line(&entry, b"first\n.second\r\nthird");
+		return_data_line(&entry, b"");
+		assert_eq!(
+			output.take(),
+			b"filter-dataline|s|t|caf\xe9\r\n\
+			filter-dataline|s|t|..hidden\n\
+			filter-dataline|s|t|first\n\
+			filter-dataline|s|t|..second\r\n\
+			filter-dataline|s|t|third\n\
+			filter-dataline|s|t|\n"
+				.to_vec()
+		);
+	}
+}
@@ -1,3 +1,4 @@
+use std::fmt;
 use std::net::SocketAddr;
 use std::path::PathBuf;
 
@@ -7,13 +8,13 @@ pub enum Address {
 	UnixSocket(PathBuf),
 }
 
-impl ToString for Address {
-	fn to_string(&self) -> String {
+impl fmt::Display for Address {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
 		match self {
-			Address::Ip(a) => a.to_string(),
-			Address::UnixSocket(a) => match a.clone().into_os_string().into_string() {
-				Ok(s) => s,
-				Err(_) => String::new(),
+			Address::Ip(a) => write!(f, "{}", a),
+			Address::UnixSocket(a) => match a.to_str() {
+				Some(s) => write!(f, "{}", s),
+				None => Ok(()),
 			},
 		}
 	}
@@ -1,3 +1,5 @@
+use crate::Error;
+use std::fmt;
 use std::str::FromStr;
 
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -7,25 +9,26 @@ pub enum AuthResult {
 	Error,
 }
 
-impl ToString for AuthResult {
-	fn to_string(&self) -> String {
-		match self {
-			AuthResult::Pass => String::from("pass"),
-			AuthResult::Fail => String::from("fail"),
-			AuthResult::Error => String::from("error"),
-		}
+impl fmt::Display for AuthResult {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let s = match self {
+			AuthResult::Pass => "pass",
+			AuthResult::Fail => "fail",
+			AuthResult::Error => "error",
+		};
+		write!(f, "{}", s)
 	}
 }
 
 impl FromStr for AuthResult {
-	type Err = ();
+	type Err = Error;
 
 	fn from_str(s: &str) -> Result<Self, Self::Err> {
 		match s {
 			"pass" => Ok(AuthResult::Pass),
 			"fail" => Ok(AuthResult::Fail),
 			"error" => Ok(AuthResult::Error),
-			_ => Err(()),
+			_ => Err(Error::invalid_value("auth result", s)),
 		}
 	}
 }
@@ -1,3 +1,5 @@
+use crate::Error;
+use std::fmt;
 use std::str::FromStr;
 
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -23,34 +25,42 @@ pub enum Event {
 	Timeout,
 }
 
-impl ToString for Event {
-	fn to_string(&self) -> String {
-		match self {
-			Event::LinkAuth => String::from("link-auth"),
-			Event::LinkConnect => String::from("link-connect"),
-			Event::LinkDisconnect => String::from("link-disconnect"),
-			Event::LinkGreeting => String::from("link-greeting"),
-			Event::LinkIdentify => String::from("link-identify"),
-			Event::LinkTls => String::from("link-tls"),
-			Event::TxBegin => String::from("tx-begin"),
-			Event::TxMail => String::from("tx-mail"),
-			Event::TxReset => String::from("tx-reset"),
-			Event::TxRcpt => String::from("tx-rcpt"),
-			Event::TxEnvelope => String::from("tx-envelope"),
-			Event::TxData => String::from("tx-data"),
-			Event::TxCommit => String::from("tx-commit"),
-			Event::TxRollback => String::from("tx-rollback"),
-			Event::ProtocolClient => String::from("protocol-client"),
-			Event::ProtocolServer => String::from("protocol-server"),
-			Event::FilterResponse => String::from("filter-response"),
-			Event::FilterReport => String::from("filter-report"),
-			Event::Timeout => String::from("timeout"),
-		}
+impl Event {
+	/// Returns whether the report is the last one of its session.
+	pub(crate) fn ends_session(&self) -> bool {
+		matches!(self, Event::LinkDisconnect | Event::Timeout)
+	}
+}
+
+impl fmt::Display for Event {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let s = match self {
+			Event::LinkAuth => "link-auth",
+			Event::LinkConnect => "link-connect",
+			Event::LinkDisconnect => "link-disconnect",
+			Event::LinkGreeting => "link-greeting",
+			Event::LinkIdentify => "link-identify",
+			Event::LinkTls => "link-tls",
+			Event::TxBegin => "tx-begin",
+			Event::TxMail => "tx-mail",
+			Event::TxReset => "tx-reset",
+			Event::TxRcpt => "tx-rcpt",
+			Event::TxEnvelope => "tx-envelope",
+			Event::TxData => "tx-data",
+			Event::TxCommit => "tx-commit",
+			Event::TxRollback => "tx-rollback",
+			Event::ProtocolClient => "protocol-client",
+			Event::ProtocolServer => "protocol-server",
+			Event::FilterResponse => "filter-response",
+			Event::FilterReport => "filter-report",
+			Event::Timeout => "timeout",
+		};
+		write!(f, "{}", s)
 	}
 }
 
 impl FromStr for Event {
-	type Err = ();
+	type Err = Error;
 
 	fn from_str(s: &str) -> Result<Self, Self::Err> {
 		match s {
@@ -73,7 +83,7 @@ impl FromStr for Event {
 			"filter-response" => Ok(Event::FilterResponse),
 			"filter-report" => Ok(Event::FilterReport),
 			"timeout" => Ok(Event::Timeout),
-			_ => Err(()),
+			_ => Err(Error::UnknownEvent(s.to_string())),
 		}
 	}
 }
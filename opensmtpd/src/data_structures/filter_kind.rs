@@ -1,3 +1,5 @@
+use crate::Error;
+use std::fmt;
 use std::str::FromStr;
 
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -6,23 +8,24 @@ pub enum FilterKind {
 	Proc,
 }
 
-impl ToString for FilterKind {
-	fn to_string(&self) -> String {
-		match self {
-			FilterKind::Builtin => String::from("builtin"),
-			FilterKind::Proc => String::from("proc"),
-		}
+impl fmt::Display for FilterKind {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let s = match self {
+			FilterKind::Builtin => "builtin",
+			FilterKind::Proc => "proc",
+		};
+		write!(f, "{}", s)
 	}
 }
 
 impl FromStr for FilterKind {
-	type Err = ();
+	type Err = Error;
 
 	fn from_str(s: &str) -> Result<Self, Self::Err> {
 		match s {
 			"builtin" => Ok(FilterKind::Builtin),
 			"proc" => Ok(FilterKind::Proc),
-			_ => Err(()),
+			_ => Err(Error::invalid_value("filter kind", s)),
 		}
 	}
 }
@@ -1,6 +1,8 @@
+use crate::Error;
+use std::fmt;
 use std::str::FromStr;
 
-#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub enum FilterPhase {
 	Connect,
 	Helo,
@@ -14,25 +16,26 @@ pub enum FilterPhase {
 	Commit,
 }
 
-impl ToString for FilterPhase {
-	fn to_string(&self) -> String {
-		match self {
-			FilterPhase::Connect => String::from("connect"),
-			FilterPhase::Helo => String::from("helo"),
-			FilterPhase::Ehlo => String::from("ehlo"),
-			FilterPhase::StartTls => String::from("starttls"),
-			FilterPhase::Auth => String::from("auth"),
-			FilterPhase::MailFrom => String::from("mail-from"),
-			FilterPhase::RcptTo => String::from("rcpt-to"),
-			FilterPhase::Data => String::from("data"),
-			FilterPhase::DataLine => String::from("data-line"),
-			FilterPhase::Commit => String::from("commit"),
-		}
+impl fmt::Display for FilterPhase {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let s = match self {
+			FilterPhase::Connect => "connect",
+			FilterPhase::Helo => "helo",
+			FilterPhase::Ehlo => "ehlo",
+			FilterPhase::StartTls => "starttls",
+			FilterPhase::Auth => "auth",
+			FilterPhase::MailFrom => "mail-from",
+			FilterPhase::RcptTo => "rcpt-to",
+			FilterPhase::Data => "data",
+			FilterPhase::DataLine => "data-line",
+			FilterPhase::Commit => "commit",
+		};
+		write!(f, "{}", s)
 	}
 }
 
 impl FromStr for FilterPhase {
-	type Err = ();
+	type Err = Error;
 
 	fn from_str(s: &str) -> Result<Self, Self::Err> {
 		match s {
@@ -46,7 +49,7 @@ impl FromStr for FilterPhase {
 			"data" => Ok(FilterPhase::Data),
 			"data-line" => Ok(FilterPhase::DataLine),
 			"commit" => Ok(FilterPhase::Commit),
-			_ => Err(()),
+			_ => Err(Error::UnknownPhase(s.to_string())),
 		}
 	}
 }
@@ -1,4 +1,5 @@
 use crate::SmtpStatusCode;
+use std::fmt;
 
 #[derive(Clone, Debug)]
 pub enum FilterResponse {
@@ -8,17 +9,21 @@ pub enum FilterResponse {
 	Disconnect(SmtpStatusCode),
 	Rewrite(String),
 	Report(String),
+	/// The response will be sent later using a
+	/// [`FilterResponder`](crate::FilterResponder).
+	Pending,
 }
 
-impl ToString for FilterResponse {
-	fn to_string(&self) -> String {
+impl fmt::Display for FilterResponse {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
 		match self {
-			FilterResponse::Proceed => String::from("proceed"),
-			FilterResponse::Junk => String::from("junk"),
-			FilterResponse::Reject(e) => format!("reject|{}", e.to_string()),
-			FilterResponse::Disconnect(e) => format!("disconnect|{}", e.to_string()),
-			FilterResponse::Rewrite(s) => format!("rewrite|{}", s),
-			FilterResponse::Report(s) => format!("report|{}", s),
+			FilterResponse::Proceed => write!(f, "proceed"),
+			FilterResponse::Junk => write!(f, "junk"),
+			FilterResponse::Reject(e) => write!(f, "reject|{}", e),
+			FilterResponse::Disconnect(e) => write!(f, "disconnect|{}", e),
+			FilterResponse::Rewrite(s) => write!(f, "rewrite|{}", s),
+			FilterResponse::Report(s) => write!(f, "report|{}", s),
+			FilterResponse::Pending => write!(f, "pending"),
 		}
 	}
 }
@@ -1,3 +1,5 @@
+use crate::Error;
+use std::fmt;
 use std::str::FromStr;
 
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -7,25 +9,26 @@ pub enum MailResult {
 	TempFail,
 }
 
-impl ToString for MailResult {
-	fn to_string(&self) -> String {
-		match self {
-			MailResult::Ok => String::from("ok"),
-			MailResult::PermFail => String::from("permfail"),
-			MailResult::TempFail => String::from("tempfail"),
-		}
+impl fmt::Display for MailResult {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let s = match self {
+			MailResult::Ok => "ok",
+			MailResult::PermFail => "permfail",
+			MailResult::TempFail => "tempfail",
+		};
+		write!(f, "{}", s)
 	}
 }
 
 impl FromStr for MailResult {
-	type Err = ();
+	type Err = Error;
 
 	fn from_str(s: &str) -> Result<Self, Self::Err> {
 		match s {
 			"ok" => Ok(MailResult::Ok),
 			"permfail" => Ok(MailResult::PermFail),
 			"tempfail" => Ok(MailResult::TempFail),
-			_ => Err(()),
+			_ => Err(Error::invalid_value("mail result", s)),
 		}
 	}
 }
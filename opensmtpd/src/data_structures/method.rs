@@ -1,3 +1,5 @@
+use crate::Error;
+use std::fmt;
 use std::str::FromStr;
 
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -6,23 +8,24 @@ pub enum Method {
 	Ehlo,
 }
 
-impl ToString for Method {
-	fn to_string(&self) -> String {
-		match self {
-			Method::Helo => String::from("HELO"),
-			Method::Ehlo => String::from("EHLO"),
-		}
+impl fmt::Display for Method {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let s = match self {
+			Method::Helo => "HELO",
+			Method::Ehlo => "EHLO",
+		};
+		write!(f, "{}", s)
 	}
 }
 
 impl FromStr for Method {
-	type Err = ();
+	type Err = Error;
 
 	fn from_str(s: &str) -> Result<Self, Self::Err> {
 		match s {
 			"HELO" => Ok(Method::Helo),
 			"EHLO" => Ok(Method::Ehlo),
-			_ => Err(()),
+			_ => Err(Error::invalid_value("method", s)),
 		}
 	}
 }
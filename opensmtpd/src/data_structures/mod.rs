@@ -6,6 +6,9 @@ pub(crate) mod filter_phase;
 pub(crate) mod filter_response;
 pub(crate) mod mail_result;
 pub(crate) mod method;
+pub(crate) mod protocol_version;
+pub(crate) mod shutdown_reason;
 pub(crate) mod smtp_status;
 pub(crate) mod subsystem;
 pub(crate) mod timeval;
+pub(crate) mod transaction;
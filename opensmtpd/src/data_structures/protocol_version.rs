@@ -0,0 +1,44 @@
+use crate::Error;
+use std::fmt;
+use std::str::FromStr;
+
+/// Version of the filter protocol used by OpenSMTPD.
+///
+/// Some events changed their parameters between versions, each line
+/// is therefore parsed according to the version it carries. Versions
+/// newer than the latest known one are parsed using the latest known
+/// layout.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct ProtocolVersion {
+	pub major: u32,
+	pub minor: u32,
+}
+
+impl ProtocolVersion {
+	pub const V0_4: ProtocolVersion = ProtocolVersion::new(0, 4);
+	pub const V0_5: ProtocolVersion = ProtocolVersion::new(0, 5);
+	pub const V0_6: ProtocolVersion = ProtocolVersion::new(0, 6);
+	pub const V0_7: ProtocolVersion = ProtocolVersion::new(0, 7);
+
+	pub const fn new(major: u32, minor: u32) -> Self {
+		ProtocolVersion { major, minor }
+	}
+}
+
+impl fmt::Display for ProtocolVersion {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}.{}", self.major, self.minor)
+	}
+}
+
+impl FromStr for ProtocolVersion {
+	type Err = Error;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let err = || Error::invalid_value("protocol version", s);
+		let mut parts = s.splitn(2, '.');
+		let major = parts.next().ok_or_else(err)?.parse().map_err(|_| err())?;
+		let minor = parts.next().ok_or_else(err)?.parse().map_err(|_| err())?;
+		Ok(ProtocolVersion::new(major, minor))
+	}
+}
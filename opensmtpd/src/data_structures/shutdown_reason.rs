@@ -0,0 +1,26 @@
+use crate::Error;
+use std::fmt;
+use std::io;
+
+/// Reason why the filter stopped processing its input.
+#[derive(Debug)]
+pub enum ShutdownReason {
+	/// The input has been closed, which is what OpenSMTPD does when
+	/// the filter is no longer needed.
+	EndOfInput,
+	/// An I/O error occurred while reading the input or writing the
+	/// output.
+	Io(io::Error),
+	/// The input does not follow the filter protocol.
+	Protocol(Error),
+}
+
+impl fmt::Display for ShutdownReason {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			ShutdownReason::EndOfInput => write!(f, "end of input"),
+			ShutdownReason::Io(e) => write!(f, "I/O error: {}", e),
+			ShutdownReason::Protocol(e) => write!(f, "protocol error: {}", e),
+		}
+	}
+}
@@ -1,3 +1,5 @@
+use std::fmt;
+
 #[derive(Clone, Debug)]
 pub struct SmtpStatusCode {
 	pub number: usize,
@@ -109,8 +111,8 @@ impl SmtpStatusCode {
 	}
 }
 
-impl ToString for SmtpStatusCode {
-	fn to_string(&self) -> String {
-		format!("{} {}", self.number, self.text)
+impl fmt::Display for SmtpStatusCode {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{} {}", self.number, self.text)
 	}
 }
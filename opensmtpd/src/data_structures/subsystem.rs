@@ -1,25 +1,31 @@
+use crate::Error;
+use std::fmt;
 use std::str::FromStr;
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum SubSystem {
 	SmtpIn,
+	SmtpOut,
 }
 
-impl ToString for SubSystem {
-	fn to_string(&self) -> String {
-		match self {
-			SubSystem::SmtpIn => String::from("smtp-in"),
-		}
+impl fmt::Display for SubSystem {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let s = match self {
+			SubSystem::SmtpIn => "smtp-in",
+			SubSystem::SmtpOut => "smtp-out",
+		};
+		write!(f, "{}", s)
 	}
 }
 
 impl FromStr for SubSystem {
-	type Err = ();
+	type Err = Error;
 
 	fn from_str(s: &str) -> Result<Self, Self::Err> {
 		match s {
 			"smtp-in" => Ok(SubSystem::SmtpIn),
-			_ => Err(()),
+			"smtp-out" => Ok(SubSystem::SmtpOut),
+			_ => Err(Error::UnknownSubsystem(s.to_string())),
 		}
 	}
 }
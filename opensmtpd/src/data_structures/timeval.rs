@@ -1,11 +1,13 @@
-#[derive(Debug, Eq, PartialEq)]
+use std::fmt;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct TimeVal {
 	pub sec: i64,
 	pub usec: i64,
 }
 
-impl ToString for TimeVal {
-	fn to_string(&self) -> String {
-		format!("{}.{}", self.sec, self.usec)
+impl fmt::Display for TimeVal {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}.{}", self.sec, self.usec)
 	}
 }
@@ -0,0 +1,58 @@
+use crate::MailResult;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TransactionState {
+	/// The transaction has begun and is not finalized yet.
+	InProgress,
+	/// The message has been accepted.
+	Committed,
+	/// The message has been refused.
+	RolledBack,
+}
+
+/// State of the current transaction of a session.
+///
+/// A transaction starts with the `tx-begin` report and is populated
+/// using the following `tx-*` reports. It is finalized on `tx-commit`
+/// or `tx-rollback` and removed from the session on `tx-reset`.
+#[derive(Clone, Debug)]
+pub struct Transaction {
+	pub message_id: String,
+	pub state: TransactionState,
+	/// Sender address, once accepted.
+	pub mail_from: Option<String>,
+	/// Accepted recipients.
+	pub rcpt_to: Vec<String>,
+	/// Recipients refused by the server.
+	pub rejected_rcpt_to: Vec<(String, MailResult)>,
+	pub envelope_ids: Vec<String>,
+	/// Size of the message, set on commit.
+	pub size: Option<usize>,
+}
+
+impl Transaction {
+	pub(crate) fn new(message_id: &str) -> Self {
+		Transaction {
+			message_id: message_id.to_string(),
+			state: TransactionState::InProgress,
+			mail_from: None,
+			rcpt_to: Vec::new(),
+			rejected_rcpt_to: Vec::new(),
+			envelope_ids: Vec::new(),
+			size: None,
+		}
+	}
+
+	pub(crate) fn set_mail_from(&mut self, result: MailResult, address: &str) {
+		if result == MailResult::Ok {
+			self.mail_from = Some(address.to_string());
+		}
+	}
+
+	pub(crate) fn add_rcpt_to(&mut self, result: MailResult, address: &str) {
+		match result {
+			MailResult::Ok => self.rcpt_to.push(address.to_string()),
+			_ => self.rejected_rcpt_to.push((address.to_string(), result)),
+		}
+	}
+}
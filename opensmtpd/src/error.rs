@@ -1,13 +1,150 @@
-use nom::error::Error;
+use crate::{Event, FilterPhase, SubSystem};
 use nom::Err;
 use pretty_hex::pretty_hex;
+use std::any::Any;
+use std::fmt;
+use std::io;
+use std::sync::Arc;
 
-fn error_to_string(e: Error<&[u8]>) -> String {
-	format!(
-		"parsing error: {:?}: input:{}",
-		e.code,
-		get_pretty_hex(&e.input)
-	)
+/// Function called for each error which does not stop the filter.
+pub(crate) type ErrorHook = Arc<dyn Fn(&Error) + Send + Sync>;
+
+/// Error which may occur while running a filter.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+	/// An I/O error occurred while reading the input or writing the
+	/// output.
+	Io(io::Error),
+	/// The handshake sent by OpenSMTPD is invalid.
+	Handshake(String),
+	/// The subsystem of a line is not supported.
+	UnknownSubsystem(String),
+	/// The event of a report line is not supported.
+	UnknownEvent(String),
+	/// The phase of a filter line is not supported.
+	UnknownPhase(String),
+	/// A line, or its parameters, cannot be parsed.
+	Malformed { line: Vec<u8>, message: String },
+	/// A value cannot be converted into a data structure.
+	InvalidValue { kind: &'static str, value: String },
+	/// A handler panicked while processing an event of the given
+	/// session.
+	Panic { session_id: String, message: String },
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Error::Io(e) => write!(f, "I/O error: {}", e),
+			Error::Handshake(e) => write!(f, "invalid handshake: {}", e),
+			Error::UnknownSubsystem(s) => write!(f, "unknown subsystem: {}", s),
+			Error::UnknownEvent(s) => write!(f, "unknown event: {}", s),
+			Error::UnknownPhase(s) => write!(f, "unknown phase: {}", s),
+			Error::Malformed { line, message } => write!(
+				f,
+				"malformed line: {}: {}",
+				message,
+				String::from_utf8_lossy(line)
+			),
+			Error::InvalidValue { kind, value } => write!(f, "invalid {}: {}", kind, value),
+			Error::Panic {
+				session_id,
+				message,
+			} => write!(
+				f,
+				"handler panicked (session id: {}): {}",
+				session_id, message
+			),
+		}
+	}
+}
+
+impl std::error::Error for Error {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		match self {
+			Error::Io(e) => Some(e),
+			_ => None,
+		}
+	}
+}
+
+impl From<io::Error> for Error {
+	fn from(e: io::Error) -> Self {
+		Error::Io(e)
+	}
+}
+
+impl Error {
+	pub(crate) fn invalid_value(kind: &'static str, value: &str) -> Self {
+		Error::InvalidValue {
+			kind,
+			value: value.to_string(),
+		}
+	}
+}
+
+fn nom_err_message(line: &[u8], e: Err<nom::error::Error<&[u8]>>) -> String {
+	match e {
+		Err::Incomplete(_) => String::from("incomplete line"),
+		Err::Error(er) | Err::Failure(er) => format!(
+			"{:?} error at byte {}",
+			er.code,
+			line.len() - er.input.len()
+		),
+	}
+}
+
+pub(crate) fn handshake_error(input: &[u8], e: Err<nom::error::Error<&[u8]>>) -> Error {
+	let message = nom_err_message(input, e);
+	log::debug!("invalid handshake: {}:{}", message, get_pretty_hex(input));
+	Error::Handshake(message)
+}
+
+/// Returns the error corresponding to a line which cannot be parsed.
+///
+/// Since the parsers do not tell why a line has been rejected, the
+/// subsystem and the event or phase are checked in order to report
+/// the unsupported ones.
+pub(crate) fn line_error(line: &[u8], e: Err<nom::error::Error<&[u8]>>) -> Error {
+	let message = nom_err_message(line, e);
+	let line = line.strip_suffix(b"\n").unwrap_or(line);
+	let fields: Vec<String> = line
+		.splitn(6, |&c| c == b'|')
+		.map(|f| String::from_utf8_lossy(f).into_owned())
+		.collect();
+	if let [kind, _, _, subsystem, name, ..] = fields.as_slice() {
+		if subsystem.parse::<SubSystem>().is_err() {
+			return Error::UnknownSubsystem(subsystem.to_string());
+		}
+		match kind.as_str() {
+			"report" if name.parse::<Event>().is_err() => {
+				return Error::UnknownEvent(name.to_string());
+			}
+			"filter" if name.parse::<FilterPhase>().is_err() => {
+				return Error::UnknownPhase(name.to_string());
+			}
+			_ => {}
+		}
+	}
+	Error::Malformed {
+		line: line.to_vec(),
+		message,
+	}
+}
+
+pub(crate) fn panic_error(session_id: &str, payload: Box<dyn Any + Send>) -> Error {
+	let message = match payload.downcast::<String>() {
+		Ok(msg) => *msg,
+		Err(payload) => match payload.downcast::<&str>() {
+			Ok(msg) => msg.to_string(),
+			Err(_) => String::from("unknown panic payload"),
+		},
+	};
+	Error::Panic {
+		session_id: session_id.to_string(),
+		message,
+	}
 }
 
 pub(crate) fn get_pretty_hex(input: &[u8]) -> String {
@@ -18,10 +155,35 @@ pub(crate) fn get_pretty_hex(input: &[u8]) -> String {
 	s
 }
 
-pub(crate) fn nom_err_to_string(e: Err<Error<&[u8]>>) -> String {
-	match e {
-		Err::Incomplete(_) => e.to_string(),
-		Err::Error(er) => error_to_string(er),
-		Err::Failure(er) => error_to_string(er),
+#[cfg(test)]
+mod tests {
+	use super::{line_error, Error};
+	use crate::parsers::entry::parse_entry;
+
+	fn error(line: &[u8]) -> Error {
+		match parse_entry(line) {
+			Ok(_) => panic!("the line has been parsed"),
+			Err(e) => line_error(line, e),
+		}
+	}
+
+	#[test]
+	fn test_line_error() {
+		assert!(matches!(
+			error(b"report|0.5|1576146008.006099|smtp-foo|link-disconnect|7641df9771b4ed00\n"),
+			Error::UnknownSubsystem(s) if s == "smtp-foo"
+		));
+		assert!(matches!(
+			error(b"report|0.5|1576146008.006099|smtp-in|link-foo|7641df9771b4ed00\n"),
+			Error::UnknownEvent(s) if s == "link-foo"
+		));
+		assert!(matches!(
+			error(b"filter|0.5|1576146008.006099|smtp-in|foo|7641df9771b4ed00|1ef1c203cc576e5d\n"),
+			Error::UnknownPhase(s) if s == "foo"
+		));
+		assert!(matches!(
+			error(b"report|0.5|invalid|smtp-in|link-disconnect|7641df9771b4ed00\n"),
+			Error::Malformed { line, .. } if line == b"report|0.5|invalid|smtp-in|link-disconnect|7641df9771b4ed00"
+		));
 	}
 }
@@ -1,253 +1,127 @@
 use crate::{
-	Address, AuthResult, FilterEntry, FilterKind, FilterPhase, FilterResponse, MailResult, Method,
-	ReportEntry,
+	return_data_end, Address, AuthResult, FilterConfig, FilterEntry, FilterKind, FilterPhase,
+	FilterResponse, MailResult, Message, Method, ReportEntry, ShutdownReason, SubSystem,
 };
 
-pub trait Filter {
-	fn on_filter_auth(&mut self, _entry: &FilterEntry, _auth: &str) -> FilterResponse {
-		FilterResponse::Proceed
-	}
-	#[doc(hidden)]
-	fn has_filter_auth(&self) -> bool {
-		false
-	}
-
-	fn on_filter_commit(&mut self, _entry: &FilterEntry) -> FilterResponse {
-		FilterResponse::Proceed
-	}
-	#[doc(hidden)]
-	fn has_filter_commit(&self) -> bool {
-		false
-	}
-
-	fn on_filter_connect(
-		&mut self,
-		_entry: &FilterEntry,
-		_rdns: &str,
-		_fcrdns: &str,
-		_src: &Address,
-		_dest: &Address,
-	) -> FilterResponse {
-		FilterResponse::Proceed
-	}
-	#[doc(hidden)]
-	fn has_filter_connect(&self) -> bool {
-		false
-	}
-
-	fn on_filter_data(&mut self, _entry: &FilterEntry) -> FilterResponse {
-		FilterResponse::Proceed
-	}
-	#[doc(hidden)]
-	fn has_filter_data(&self) -> bool {
-		false
-	}
-
-	fn on_filter_data_line(&mut self, _entry: &FilterEntry, _data_line: &[u8]) {}
-	#[doc(hidden)]
-	fn has_filter_data_line(&self) -> bool {
-		false
-	}
-
-	fn on_filter_ehlo(&mut self, _entry: &FilterEntry, _identity: &str) -> FilterResponse {
-		FilterResponse::Proceed
-	}
-	#[doc(hidden)]
-	fn has_filter_ehlo(&self) -> bool {
-		false
-	}
-
-	fn on_filter_helo(&mut self, _entry: &FilterEntry, _identity: &str) -> FilterResponse {
-		FilterResponse::Proceed
-	}
-	#[doc(hidden)]
-	fn has_filter_helo(&self) -> bool {
-		false
-	}
-
-	fn on_filter_mail_from(&mut self, _entry: &FilterEntry, _address: &str) -> FilterResponse {
-		FilterResponse::Proceed
-	}
-	#[doc(hidden)]
-	fn has_filter_mail_from(&self) -> bool {
-		false
-	}
-
-	fn on_filter_rcpt_to(&mut self, _entry: &FilterEntry, _address: &str) -> FilterResponse {
-		FilterResponse::Proceed
-	}
-	#[doc(hidden)]
-	fn has_filter_rcpt_to(&self) -> bool {
-		false
-	}
-
-	fn on_filter_starttls(&mut self, _entry: &FilterEntry, _tls_string: &str) -> FilterResponse {
-		FilterResponse::Proceed
-	}
-	#[doc(hidden)]
-	fn has_filter_starttls(&self) -> bool {
-		false
-	}
-
-	fn on_report_link_auth(&mut self, _entry: &ReportEntry, _username: &str, _result: AuthResult) {}
-	#[doc(hidden)]
-	fn has_report_link_auth(&self) -> bool {
-		false
-	}
-
-	fn on_report_link_connect(
-		&mut self,
-		_entry: &ReportEntry,
-		_rdns: &str,
-		_fcrdns: &str,
-		_src: &Address,
-		_dest: &Address,
-	) {
-	}
-	#[doc(hidden)]
-	fn has_report_link_connect(&self) -> bool {
-		false
-	}
-
-	fn on_report_link_disconnect(&mut self, _entry: &ReportEntry) {}
-	#[doc(hidden)]
-	fn has_report_link_disconnect(&self) -> bool {
-		false
-	}
-
-	fn on_report_link_greeting(&mut self, _entry: &ReportEntry, _hostname: &str) {}
-	#[doc(hidden)]
-	fn has_report_link_greeting(&self) -> bool {
-		false
-	}
-
-	fn on_report_link_identify(&mut self, _entry: &ReportEntry, _method: Method, _identity: &str) {}
-	#[doc(hidden)]
-	fn has_report_link_identify(&self) -> bool {
-		false
-	}
-
-	fn on_report_link_tls(&mut self, _entry: &ReportEntry, _tls_string: &str) {}
-	#[doc(hidden)]
-	fn has_report_link_tls(&self) -> bool {
-		false
-	}
-
-	fn on_report_tx_begin(&mut self, _entry: &ReportEntry, _message_id: &str) {}
-	#[doc(hidden)]
-	fn has_report_tx_begin(&self) -> bool {
-		false
-	}
-
-	fn on_report_tx_mail(
-		&mut self,
-		_entry: &ReportEntry,
-		_message_id: &str,
-		_result: MailResult,
-		_address: &str,
-	) {
-	}
-	#[doc(hidden)]
-	fn has_report_tx_mail(&self) -> bool {
-		false
-	}
-
-	fn on_report_tx_reset(&mut self, _entry: &ReportEntry, _message_id: &Option<String>) {}
-	#[doc(hidden)]
-	fn has_report_tx_reset(&self) -> bool {
-		false
-	}
-
-	fn on_report_tx_rcpt(
-		&mut self,
-		_entry: &ReportEntry,
-		_message_id: &str,
-		_result: MailResult,
-		_address: &str,
-	) {
-	}
-	#[doc(hidden)]
-	fn has_report_tx_rcpt(&self) -> bool {
-		false
-	}
-
-	fn on_report_tx_envelope(
-		&mut self,
-		_entry: &ReportEntry,
-		_message_id: &str,
-		_envelope_id: &str,
-	) {
-	}
-	#[doc(hidden)]
-	fn has_report_tx_envelope(&self) -> bool {
-		false
-	}
-
-	fn on_report_tx_data(&mut self, _entry: &ReportEntry, _message_id: &str, _result: MailResult) {}
-	#[doc(hidden)]
-	fn has_report_tx_data(&self) -> bool {
-		false
-	}
-
-	fn on_report_tx_commit(
-		&mut self,
-		_entry: &ReportEntry,
-		_message_id: &str,
-		_message_size: usize,
-	) {
-	}
-	#[doc(hidden)]
-	fn has_report_tx_commit(&self) -> bool {
-		false
-	}
-
-	fn on_report_tx_rollback(&mut self, _entry: &ReportEntry, _message_id: &str) {}
-	#[doc(hidden)]
-	fn has_report_tx_rollback(&self) -> bool {
-		false
-	}
-
-	fn on_report_protocol_client(&mut self, _entry: &ReportEntry, _command: &str) {}
-	#[doc(hidden)]
-	fn has_report_protocol_client(&self) -> bool {
-		false
-	}
-
-	fn on_report_protocol_server(&mut self, _entry: &ReportEntry, _response: &str) {}
-	#[doc(hidden)]
-	fn has_report_protocol_server(&self) -> bool {
-		false
-	}
-
-	fn on_report_filter_response(
-		&mut self,
-		_entry: &ReportEntry,
-		_phase: FilterPhase,
-		_response: &str,
-		_param: &Option<String>,
-	) {
-	}
-	#[doc(hidden)]
-	fn has_report_filter_response(&self) -> bool {
-		false
-	}
-
-	fn on_report_filter_report(
-		&mut self,
-		_entry: &ReportEntry,
-		_filter_kind: FilterKind,
-		_name: &str,
-		_message: &str,
-	) {
-	}
-	#[doc(hidden)]
-	fn has_report_filter_report(&self) -> bool {
-		false
-	}
+macro_rules! filter_trait {
+	(
+		filters { $($(#[$f_attr: meta])* $f_name: ident, $f_has: ident, ($($f_param: ident: $f_type: ty),*);)* }
+		reports { $($(#[$r_attr: meta])* $r_name: ident, $r_has: ident, $r_event: ident, ($($r_param: ident: $r_type: ty),*);)* }
+	) => {
+		pub trait Filter {
+			/// Called with the configuration sent by OpenSMTPD during the
+			/// handshake, before the filter registers its events.
+			fn on_config(&mut self, _config: &FilterConfig) {}
+
+			/// Called once the handshake with OpenSMTPD is done, before any
+			/// report or filter request is processed.
+			fn on_ready(&mut self) {}
+
+			/// Called when the filter stops processing its input, before
+			/// returning from the runner.
+			fn on_shutdown(&mut self, _reason: &ShutdownReason) {}
+
+			/// Called after a handler panicked while processing an event of the
+			/// given session. The panic has been caught and the filter keeps
+			/// processing the other events.
+			fn on_panic(&mut self, _session_id: &str, _message: &str) {}
+
+			$(
+				$(#[$f_attr])*
+				#[allow(unused_variables)]
+				fn $f_name(&mut self, entry: &FilterEntry, $($f_param: $f_type),*) -> FilterResponse {
+					FilterResponse::Proceed
+				}
+				#[doc(hidden)]
+				fn $f_has(&self, _subsystem: &SubSystem) -> bool {
+					false
+				}
+			)*
+
+			fn on_filter_data_line(&mut self, _entry: &FilterEntry, _data_line: &[u8]) {}
+			#[doc(hidden)]
+			fn has_filter_data_line(&self, _subsystem: &SubSystem) -> bool {
+				false
+			}
+
+			/// Called once every data-line of the message has been received.
+			/// The default implementation ends the message sent back to
+			/// OpenSMTPD.
+			fn on_filter_data_end(&mut self, entry: &FilterEntry) {
+				return_data_end(entry);
+			}
+
+			/// Called with the complete message instead of the individual
+			/// data-lines. The returned content is sent back to OpenSMTPD.
+			fn on_message(&mut self, _entry: &FilterEntry, message: Message) -> Vec<u8> {
+				message.into_bytes()
+			}
+			#[doc(hidden)]
+			fn has_message(&self, _subsystem: &SubSystem) -> bool {
+				false
+			}
+
+			$(
+				$(#[$r_attr])*
+				#[allow(unused_variables)]
+				fn $r_name(&mut self, entry: &ReportEntry, $($r_param: $r_type),*) {}
+				#[doc(hidden)]
+				fn $r_has(&self, _subsystem: &SubSystem) -> bool {
+					false
+				}
+			)*
+		}
+	};
+}
 
-	fn on_report_timeout(&mut self, _entry: &ReportEntry) {}
-	#[doc(hidden)]
-	fn has_report_timeout(&self) -> bool {
-		false
-	}
+for_each_handler!(filter_trait);
+
+#[cfg(test)]
+mod tests {
+	use crate::{
+		Address, AuthResult, Filter, FilterEntry, FilterKind, FilterPhase, FilterResponse,
+		MailResult, Message, Method, ReportEntry, SubSystem,
+	};
+
+	// Every handler of the table goes through the `filter` attribute,
+	// which rejects the names and signatures it does not know.
+	macro_rules! every_handler {
+		(
+			filters { $($(#[$f_attr: meta])* $f_name: ident, $f_has: ident, ($($f_param: ident: $f_type: ty),*);)* }
+			reports { $($(#[$r_attr: meta])* $r_name: ident, $r_has: ident, $r_event: ident, ($($r_param: ident: $r_type: ty),*);)* }
+		) => {
+			struct Everything {}
+
+			#[crate::filter(smtp_in, smtp_out)]
+			#[allow(unused_variables)]
+			impl Filter for Everything {
+				$(
+					fn $f_name(&mut self, entry: &FilterEntry, $($f_param: $f_type),*) -> FilterResponse {
+						FilterResponse::Proceed
+					}
+				)*
+
+				fn on_filter_data_line(&mut self, entry: &FilterEntry, data_line: &[u8]) {}
+
+				fn on_message(&mut self, entry: &FilterEntry, message: Message) -> Vec<u8> {
+					message.into_bytes()
+				}
+
+				$(
+					fn $r_name(&mut self, entry: &ReportEntry, $($r_param: $r_type),*) {}
+				)*
+			}
+
+			#[test]
+			fn test_handler_table() {
+				let filter = Everything {};
+				$(assert!(filter.$f_has(&SubSystem::SmtpIn), stringify!($f_name));)*
+				$(assert!(filter.$r_has(&SubSystem::SmtpOut), stringify!($r_name));)*
+				assert!(filter.has_filter_data_line(&SubSystem::SmtpIn));
+				assert!(filter.has_message(&SubSystem::SmtpIn));
+			}
+		};
+	}
+
+	for_each_handler!(every_handler);
 }
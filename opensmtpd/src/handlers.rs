@@ -0,0 +1,52 @@
+// Table of the handlers shared by the filter traits and the filters
+// wrapping other filters. The given macro is called with the filter
+// requests answered with a `FilterResponse` and the reports, as:
+//
+// filters { name, has_name, (param: type, ...); ... }
+// reports { name, has_name, Event, (param: type, ...); ... }
+//
+// The data-line and message handlers differ at each site and are not
+// part of the table. The `opensmtpd_derive` crate keeps its own copy,
+// which its tests compare with this one.
+macro_rules! for_each_handler {
+	($callback: ident) => {
+		$callback! {
+			filters {
+				on_filter_auth, has_filter_auth, (auth: &str);
+				on_filter_commit, has_filter_commit, ();
+				on_filter_connect, has_filter_connect, (rdns: &str, fcrdns: &str, src: &Address, dest: &Address);
+				on_filter_data, has_filter_data, ();
+				/// Invalid UTF-8 in the identity is replaced by U+FFFD.
+				on_filter_ehlo, has_filter_ehlo, (identity: &str);
+				/// Invalid UTF-8 in the identity is replaced by U+FFFD.
+				on_filter_helo, has_filter_helo, (identity: &str);
+				/// Invalid UTF-8 in the address is replaced by U+FFFD.
+				on_filter_mail_from, has_filter_mail_from, (address: &str);
+				/// Invalid UTF-8 in the address is replaced by U+FFFD.
+				on_filter_rcpt_to, has_filter_rcpt_to, (address: &str);
+				on_filter_starttls, has_filter_starttls, (tls_string: &str);
+			}
+			reports {
+				on_report_link_auth, has_report_link_auth, LinkAuth, (username: &str, result: AuthResult);
+				on_report_link_connect, has_report_link_connect, LinkConnect, (rdns: &str, fcrdns: &str, src: &Address, dest: &Address);
+				on_report_link_disconnect, has_report_link_disconnect, LinkDisconnect, ();
+				on_report_link_greeting, has_report_link_greeting, LinkGreeting, (hostname: &str);
+				on_report_link_identify, has_report_link_identify, LinkIdentify, (method: Method, identity: &str);
+				on_report_link_tls, has_report_link_tls, LinkTls, (tls_string: &str);
+				on_report_tx_begin, has_report_tx_begin, TxBegin, (message_id: &str);
+				on_report_tx_mail, has_report_tx_mail, TxMail, (message_id: &str, result: MailResult, address: &str);
+				on_report_tx_reset, has_report_tx_reset, TxReset, (message_id: &Option<String>);
+				on_report_tx_rcpt, has_report_tx_rcpt, TxRcpt, (message_id: &str, result: MailResult, address: &str);
+				on_report_tx_envelope, has_report_tx_envelope, TxEnvelope, (message_id: &str, envelope_id: &str);
+				on_report_tx_data, has_report_tx_data, TxData, (message_id: &str, result: MailResult);
+				on_report_tx_commit, has_report_tx_commit, TxCommit, (message_id: &str, message_size: usize);
+				on_report_tx_rollback, has_report_tx_rollback, TxRollback, (message_id: &str);
+				on_report_protocol_client, has_report_protocol_client, ProtocolClient, (command: &str);
+				on_report_protocol_server, has_report_protocol_server, ProtocolServer, (response: &str);
+				on_report_filter_response, has_report_filter_response, FilterResponse, (phase: FilterPhase, response: &str, param: &Option<String>);
+				on_report_filter_report, has_report_filter_report, FilterReport, (filter_kind: FilterKind, name: &str, message: &str);
+				on_report_timeout, has_report_timeout, Timeout, ();
+			}
+		}
+	};
+}
@@ -0,0 +1,429 @@
+use crate::data_line::return_data_line;
+use crate::FilterEntry;
+use std::mem;
+
+/// A single header field.
+///
+/// The raw value is kept as received, including the line breaks of
+/// folded headers, so unmodified headers are sent back unchanged.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Header {
+	name: String,
+	value: Vec<u8>,
+}
+
+impl Header {
+	pub fn new(name: &str, value: &str) -> Self {
+		let mut header = Header {
+			name: String::new(),
+			value: Vec::new(),
+		};
+		header.set_name(name);
+		header.set_value(value);
+		header
+	}
+
+	fn parse(line: &[u8]) -> Option<Self> {
+		let pos = line.iter().position(|&c| c == b':')?;
+		let name = &line[..pos];
+		if name.is_empty() || !name.iter().all(|&c| is_name_char(c)) {
+			return None;
+		}
+		Some(Header {
+			name: String::from_utf8_lossy(name).into_owned(),
+			value: line[pos + 1..].to_vec(),
+		})
+	}
+
+	pub fn name(&self) -> &str {
+		&self.name
+	}
+
+	/// Checks whether the header has the given name, case-insensitively.
+	pub fn is(&self, name: &str) -> bool {
+		self.name.eq_ignore_ascii_case(name)
+	}
+
+	/// Returns the unfolded value, without the surrounding whitespaces.
+	pub fn value(&self) -> String {
+		let value: Vec<u8> = self
+			.value
+			.iter()
+			.filter(|&&c| c != b'\r' && c != b'\n')
+			.copied()
+			.collect();
+		String::from_utf8_lossy(&value)
+			.trim_matches(|c: char| c.is_ascii_whitespace())
+			.to_string()
+	}
+
+	/// Returns the value as received, after the colon.
+	pub fn raw_value(&self) -> &[u8] {
+		&self.value
+	}
+
+	/// Sets the name. Characters which are not allowed in a header
+	/// name are removed.
+	pub fn set_name(&mut self, name: &str) {
+		self.name = name
+			.chars()
+			.filter(|&c| c.is_ascii() && is_name_char(c as u8))
+			.collect();
+	}
+
+	/// Sets the value. Line breaks are removed so the value cannot be
+	/// used to inject other headers.
+	pub fn set_value(&mut self, value: &str) {
+		self.value = Vec::with_capacity(value.len() + 1);
+		self.value.push(b' ');
+		self.value
+			.extend(value.bytes().filter(|&c| c != b'\r' && c != b'\n'));
+	}
+
+	fn lines(&self) -> Vec<Vec<u8>> {
+		let mut first = self.name.as_bytes().to_vec();
+		first.push(b':');
+		let mut lines = self.value.split(|&c| c == b'\n');
+		if let Some(line) = lines.next() {
+			first.extend_from_slice(line.strip_suffix(b"\r").unwrap_or(line));
+		}
+		let mut ret = vec![first];
+		ret.extend(lines.map(|l| l.strip_suffix(b"\r").unwrap_or(l).to_vec()));
+		ret
+	}
+
+	fn append_line(&mut self, line: &[u8]) {
+		self.value.extend_from_slice(b"\r\n");
+		self.value.extend_from_slice(line);
+	}
+}
+
+fn is_name_char(c: u8) -> bool {
+	(33..=126).contains(&c) && c != b':'
+}
+
+fn is_continuation(line: &[u8]) -> bool {
+	matches!(line.first(), Some(b' ') | Some(b'\t'))
+}
+
+/// Header section of a message.
+///
+/// Names are compared case-insensitively and the headers are kept in
+/// the order they were received.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Headers {
+	headers: Vec<Header>,
+}
+
+impl Headers {
+	pub fn new() -> Self {
+		Headers::default()
+	}
+
+	/// Splits a message into its headers and its body.
+	///
+	/// The blank line separating the headers from the body is not part
+	/// of the returned body.
+	pub fn parse(message: &[u8]) -> (Self, &[u8]) {
+		let mut reader = HeaderReader::new();
+		let mut offset = 0;
+		while offset < message.len() {
+			let end = match message[offset..].iter().position(|&c| c == b'\n') {
+				Some(pos) => offset + pos + 1,
+				None => message.len(),
+			};
+			let line = &message[offset..end];
+			let line = line.strip_suffix(b"\n").unwrap_or(line);
+			let line = line.strip_suffix(b"\r").unwrap_or(line);
+			if let HeaderEvent::EndOfHeaders(headers) = reader.push(line) {
+				let body = if line.is_empty() {
+					&message[end..]
+				} else {
+					&message[offset..]
+				};
+				return (headers, body);
+			}
+			offset = end;
+		}
+		(reader.headers, &message[message.len()..])
+	}
+
+	pub fn len(&self) -> usize {
+		self.headers.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.headers.is_empty()
+	}
+
+	pub fn iter(&self) -> std::slice::Iter<'_, Header> {
+		self.headers.iter()
+	}
+
+	pub fn iter_mut(&mut self) -> std::slice::IterMut<'_, Header> {
+		self.headers.iter_mut()
+	}
+
+	/// Returns the first header with the given name.
+	pub fn get(&self, name: &str) -> Option<&Header> {
+		self.headers.iter().find(|h| h.is(name))
+	}
+
+	/// Returns every header with the given name.
+	pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Header> {
+		self.headers.iter().filter(move |h| h.is(name))
+	}
+
+	pub fn contains(&self, name: &str) -> bool {
+		self.get(name).is_some()
+	}
+
+	/// Adds a header after the existing ones.
+	pub fn add(&mut self, name: &str, value: &str) {
+		self.headers.push(Header::new(name, value));
+	}
+
+	/// Adds a header before the existing ones, which is where trace
+	/// headers such as `Received` are expected.
+	pub fn prepend(&mut self, name: &str, value: &str) {
+		self.headers.insert(0, Header::new(name, value));
+	}
+
+	/// Replaces every header with the given name by a single one, at
+	/// the position of the first of them. The header is added if it
+	/// does not exist.
+	pub fn set(&mut self, name: &str, value: &str) {
+		match self.headers.iter().position(|h| h.is(name)) {
+			Some(pos) => {
+				self.headers[pos].set_value(value);
+				let mut i = 0;
+				self.headers.retain(|h| {
+					i += 1;
+					i - 1 == pos || !h.is(name)
+				});
+			}
+			None => self.add(name, value),
+		}
+	}
+
+	/// Removes every header with the given name and returns how many
+	/// were removed.
+	pub fn remove(&mut self, name: &str) -> usize {
+		let len = self.headers.len();
+		self.headers.retain(|h| !h.is(name));
+		len - self.headers.len()
+	}
+
+	/// Renames every header with the given name and returns how many
+	/// were renamed.
+	pub fn rename(&mut self, name: &str, new_name: &str) -> usize {
+		let mut nb = 0;
+		for header in self.headers.iter_mut().filter(|h| h.is(name)) {
+			header.set_name(new_name);
+			nb += 1;
+		}
+		nb
+	}
+
+	pub fn retain<F>(&mut self, f: F)
+	where
+		F: FnMut(&Header) -> bool,
+	{
+		self.headers.retain(f);
+	}
+
+	/// Returns the headers as CRLF-terminated lines, without the blank
+	/// line separating them from the body.
+	pub fn to_bytes(&self) -> Vec<u8> {
+		let mut ret = Vec::new();
+		for line in self.headers.iter().flat_map(|h| h.lines()) {
+			ret.extend_from_slice(&line);
+			ret.extend_from_slice(b"\r\n");
+		}
+		ret
+	}
+}
+
+impl<'a> IntoIterator for &'a Headers {
+	type Item = &'a Header;
+	type IntoIter = std::slice::Iter<'a, Header>;
+
+	fn into_iter(self) -> Self::IntoIter {
+		self.headers.iter()
+	}
+}
+
+/// Sends the headers back to OpenSMTPD as data-lines.
+///
+/// The blank line separating the headers from the body is not sent.
+pub fn return_headers(entry: &FilterEntry, headers: &Headers) {
+	for line in headers.iter().flat_map(|h| h.lines()) {
+		return_data_line(entry, &line);
+	}
+}
+
+/// What a data-line has been used for by a [`HeaderReader`].
+#[derive(Debug)]
+pub enum HeaderEvent {
+	/// The line is part of the headers and has been kept by the reader.
+	Header,
+	/// The headers are complete. The current line, which is either the
+	/// blank separator or the first line of the body, has not been
+	/// consumed.
+	EndOfHeaders(Headers),
+	/// The line belongs to the body.
+	Body,
+}
+
+/// State machine separating the headers from the body in the
+/// data-lines of a message.
+///
+/// Once [`end`](HeaderReader::end) is called, the reader is ready for
+/// the next message.
+///
+/// ``` rust
+/// use opensmtpd::{
+///		return_data_end, return_data_line, return_headers, FilterEntry, HeaderEvent, HeaderReader,
+///		SessionContext, SessionFilter,
+/// };
+/// use opensmtpd_derive::register;
+///
+/// struct RmXOriginatingIp {}
+///
+/// impl SessionFilter for RmXOriginatingIp {
+///		type Session = HeaderReader;
+///
+///		#[register]
+///		fn on_filter_data_line(
+///			&mut self,
+///			entry: &FilterEntry,
+///			reader: &mut SessionContext<HeaderReader>,
+///			data_line: &[u8],
+///		) {
+///			match reader.push(data_line) {
+///				HeaderEvent::Header => {}
+///				HeaderEvent::EndOfHeaders(mut headers) => {
+///					headers.remove("X-Originating-Ip");
+///					return_headers(entry, &headers);
+///					return_data_line(entry, data_line);
+///				}
+///				HeaderEvent::Body => return_data_line(entry, data_line),
+///			}
+///		}
+///
+///		fn on_filter_data_end(&mut self, entry: &FilterEntry, reader: &mut SessionContext<HeaderReader>) {
+///			if let Some(mut headers) = reader.end() {
+///				headers.remove("X-Originating-Ip");
+///				return_headers(entry, &headers);
+///			}
+///			return_data_end(entry);
+///		}
+/// }
+/// ```
+#[derive(Debug, Default)]
+pub struct HeaderReader {
+	headers: Headers,
+	in_body: bool,
+}
+
+impl HeaderReader {
+	pub fn new() -> Self {
+		HeaderReader::default()
+	}
+
+	pub fn push(&mut self, line: &[u8]) -> HeaderEvent {
+		if self.in_body {
+			return HeaderEvent::Body;
+		}
+		if is_continuation(line) {
+			if let Some(header) = self.headers.headers.last_mut() {
+				header.append_line(line);
+				return HeaderEvent::Header;
+			}
+		} else if let Some(header) = Header::parse(line) {
+			self.headers.headers.push(header);
+			return HeaderEvent::Header;
+		}
+		self.in_body = true;
+		HeaderEvent::EndOfHeaders(mem::take(&mut self.headers))
+	}
+
+	/// Resets the reader at the end of the message. The headers are
+	/// returned if the end of the headers has not been reached, which
+	/// happens when the message has no body.
+	pub fn end(&mut self) -> Option<Headers> {
+		let headers = mem::take(&mut self.headers);
+		match mem::replace(&mut self.in_body, false) {
+			true => None,
+			false => Some(headers),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{HeaderEvent, HeaderReader, Headers};
+
+	const MESSAGE: &[u8] = b"Received: from a\r\n\tby b\r\nSubject: test\r\nX-Test: 1\r\nx-test: 2\r\n\r\nBody: text\r\n";
+
+	#[test]
+	fn test_parse() {
+		let (headers, body) = Headers::parse(MESSAGE);
+		assert_eq!(headers.len(), 4);
+		let received = headers.get("received").unwrap();
+		assert_eq!(received.name(), "Received");
+		assert_eq!(received.value(), "from a\tby b");
+		assert_eq!(received.raw_value(), b" from a\r\n\tby b");
+		assert_eq!(headers.get_all("X-TEST").count(), 2);
+		assert_eq!(body, b"Body: text\r\n");
+		assert_eq!(headers.to_bytes(), &MESSAGE[..MESSAGE.len() - 14]);
+	}
+
+	#[test]
+	fn test_parse_no_body() {
+		let (headers, body) = Headers::parse(b"Subject: test\r\n");
+		assert_eq!(headers.len(), 1);
+		assert!(body.is_empty());
+		let (headers, body) = Headers::parse(b"not a header\r\n");
+		assert!(headers.is_empty());
+		assert_eq!(body, b"not a header\r\n");
+	}
+
+	#[test]
+	fn test_edit() {
+		let (mut headers, _) = Headers::parse(MESSAGE);
+		headers.prepend("Authentication-Results", "example.org; none");
+		assert_eq!(headers.remove("subject"), 1);
+		assert_eq!(headers.rename("x-test", "X-Old-Test"), 2);
+		headers.set("x-old-test", "3\r\nBcc: injected");
+		headers.add("X-Filter", "ok");
+		headers.add("X-\u{141}ódź", "invalid");
+		assert_eq!(
+			headers.to_bytes(),
+			b"Authentication-Results: example.org; none\r\n\
+			Received: from a\r\n\tby b\r\n\
+			X-Old-Test: 3Bcc: injected\r\n\
+			X-Filter: ok\r\n\
+			X-d: invalid\r\n"
+				.to_vec()
+		);
+	}
+
+	#[test]
+	fn test_reader() {
+		let mut reader = HeaderReader::new();
+		assert!(matches!(reader.push(b"Subject: test"), HeaderEvent::Header));
+		assert!(matches!(reader.push(b" folded"), HeaderEvent::Header));
+		match reader.push(b"") {
+			HeaderEvent::EndOfHeaders(headers) => {
+				assert_eq!(headers.get("subject").unwrap().value(), "test folded");
+			}
+			e => panic!("unexpected event: {:?}", e),
+		}
+		assert!(matches!(reader.push(b"Subject: body"), HeaderEvent::Body));
+		assert!(reader.end().is_none());
+		assert!(matches!(reader.push(b".dot: header"), HeaderEvent::Header));
+		let headers = reader.end().unwrap();
+		assert_eq!(headers.get(".dot").unwrap().value(), "header");
+	}
+}
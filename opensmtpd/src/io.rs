@@ -1,32 +1,67 @@
-use crate::error::get_pretty_hex;
-use std::io::{self, ErrorKind, Read};
-use std::sync::mpsc::Sender;
+use crate::error::{get_pretty_hex, ErrorHook};
+use crate::{Direction, Error, FilterResponse, ShutdownReason};
+use std::fmt;
+use std::io::{self, ErrorKind, Read, Write};
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "async")]
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+#[cfg(feature = "async")]
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+#[cfg(feature = "async")]
+use tokio::sync::oneshot;
 
-pub(crate) fn read_stdin(tx: &Sender<Vec<u8>>) {
-	if let Err(e) = do_read_stdin(tx) {
-		log::error!("{}", e);
-	}
+pub(crate) enum Input {
+	Line(Vec<u8>),
+	Closed(ShutdownReason),
+}
+
+pub(crate) fn read_input<R, F>(input: R, recorder: Option<Recorder>, mut send: F)
+where
+	R: Read,
+	F: FnMut(Input) -> bool,
+{
+	let mut send = |input: Input| {
+		if let (Some(recorder), Input::Line(line)) = (&recorder, &input) {
+			recorder.record(Direction::Inbound, line);
+		}
+		send(input)
+	};
+	let reason = match do_read_input(input, &mut send) {
+		Ok(()) => ShutdownReason::EndOfInput,
+		Err(e) => ShutdownReason::Io(e),
+	};
+	send(Input::Closed(reason));
 }
 
-fn do_read_stdin(tx: &Sender<Vec<u8>>) -> Result<(), String> {
+fn do_read_input<R, F>(mut input: R, send: &mut F) -> io::Result<()>
+where
+	R: Read,
+	F: FnMut(Input) -> bool,
+{
 	let mut read_buffer: [u8; crate::BUFFER_SIZE] = [0; crate::BUFFER_SIZE];
 	let mut line_buffer: Vec<u8> = Vec::with_capacity(crate::BUFFER_SIZE);
-	let mut stdin = io::stdin();
 	loop {
 		read_buffer.copy_from_slice(&[0; crate::BUFFER_SIZE]);
-		let len = match stdin.read(&mut read_buffer) {
+		let len = match input.read(&mut read_buffer) {
 			Ok(n) => n,
 			Err(e) => match e.kind() {
 				ErrorKind::Interrupted => {
 					continue;
 				}
 				_ => {
-					return Err(e.to_string());
+					return Err(e);
 				}
 			},
 		};
 		if len == 0 {
-			return Err(String::from("unable to read on stdin"));
+			if !line_buffer.is_empty() {
+				log::warn!(
+					"input closed on an incomplete line:{}",
+					get_pretty_hex(&line_buffer)
+				);
+			}
+			return Ok(());
 		}
 		line_buffer.extend_from_slice(&read_buffer[..len]);
 		while let Some(id) = line_buffer.iter().position(|i| *i == b'\n') {
@@ -34,8 +69,241 @@ fn do_read_stdin(tx: &Sender<Vec<u8>>) -> Result<(), String> {
 			let mut line = Vec::with_capacity(pos);
 			line.extend_from_slice(&line_buffer[..pos]);
 			log::trace!("new line:{}", get_pretty_hex(&line));
-			tx.send(line).unwrap();
+			if !send(Input::Line(line)) {
+				return Ok(());
+			}
 			line_buffer.drain(..pos);
 		}
 	}
 }
+
+/// Shared handle on the stream the filter writes its lines to.
+///
+/// Each line is written and flushed while holding the lock, so lines
+/// sent from different threads cannot interleave. When no stream has
+/// been set, the standard output is used.
+#[derive(Clone, Default)]
+pub(crate) struct Output {
+	sink: Sink,
+	recorder: Option<Recorder>,
+	on_error: Option<ErrorHook>,
+	fail_response: Option<FilterResponse>,
+}
+
+#[derive(Clone, Default)]
+enum Sink {
+	#[default]
+	Stdout,
+	Stream(Arc<Mutex<Box<dyn Write + Send>>>),
+	#[cfg(feature = "async")]
+	Writer(UnboundedSender<WriterMessage>),
+}
+
+#[cfg(feature = "async")]
+enum WriterMessage {
+	Line(Vec<u8>),
+	Flush(oneshot::Sender<()>),
+}
+
+impl Output {
+	pub(crate) fn new<W>(output: W) -> Self
+	where
+		W: Write + Send + 'static,
+	{
+		Output {
+			sink: Sink::Stream(Arc::new(Mutex::new(Box::new(output)))),
+			recorder: None,
+			on_error: None,
+			fail_response: None,
+		}
+	}
+
+	/// Records every line written from now on.
+	pub(crate) fn record(&mut self, recorder: Recorder) {
+		self.recorder = Some(recorder);
+	}
+
+	/// Sets the hook the write errors are reported to.
+	pub(crate) fn on_error(&mut self, on_error: ErrorHook) {
+		self.on_error = Some(on_error);
+	}
+
+	/// Sets the response sent for the requests which cannot be answered
+	/// by the filter.
+	pub(crate) fn set_fail_response(&mut self, response: FilterResponse) {
+		self.fail_response = Some(response);
+	}
+
+	/// Returns the response sent for the requests which cannot be
+	/// answered by the filter, which defaults to proceeding.
+	pub(crate) fn fail_response(&self) -> FilterResponse {
+		self.fail_response
+			.clone()
+			.unwrap_or(FilterResponse::Proceed)
+	}
+
+	/// Logs an error which occurred while writing a line and reports it
+	/// to the hook.
+	pub(crate) fn report_error(&self, error: io::Error) {
+		let error = Error::Io(error);
+		log::error!("{}", error);
+		if let Some(on_error) = &self.on_error {
+			on_error(&error);
+		}
+	}
+
+	pub(crate) fn write_line(&self, line: &[u8]) -> io::Result<()> {
+		if let Some(recorder) = &self.recorder {
+			recorder.record(Direction::Outbound, line);
+		}
+		match &self.sink {
+			Sink::Stdout => {
+				let stdout = io::stdout();
+				let mut handle = stdout.lock();
+				write_line_to(&mut handle, line)
+			}
+			Sink::Stream(inner) => {
+				let mut handle = match inner.lock() {
+					Ok(h) => h,
+					Err(e) => e.into_inner(),
+				};
+				write_line_to(&mut *handle, line)
+			}
+			#[cfg(feature = "async")]
+			Sink::Writer(tx) => tx
+				.send(WriterMessage::Line(line.to_vec()))
+				.map_err(|_| io::Error::new(ErrorKind::BrokenPipe, "the output writer stopped")),
+		}
+	}
+}
+
+#[cfg(feature = "async")]
+impl Output {
+	/// Moves the writes to a dedicated thread, so the tasks of an
+	/// asynchronous filter never block on a slow stream.
+	///
+	/// The errors occurring while writing are reported by that thread.
+	pub(crate) fn spawn_writer(&self) -> Self {
+		let (tx, mut rx) = unbounded_channel();
+		let stream = Output {
+			sink: self.sink.clone(),
+			recorder: None,
+			on_error: self.on_error.clone(),
+			fail_response: None,
+		};
+		thread::spawn(move || {
+			while let Some(message) = rx.blocking_recv() {
+				match message {
+					WriterMessage::Line(line) => {
+						if let Err(e) = stream.write_line(&line) {
+							stream.report_error(e);
+						}
+					}
+					WriterMessage::Flush(done) => {
+						let _ = done.send(());
+					}
+				}
+			}
+		});
+		Output {
+			sink: Sink::Writer(tx),
+			recorder: self.recorder.clone(),
+			on_error: self.on_error.clone(),
+			fail_response: self.fail_response.clone(),
+		}
+	}
+
+	/// Waits until the lines sent so far have been written.
+	pub(crate) async fn flush(&self) {
+		if let Sink::Writer(tx) = &self.sink {
+			let (done, written) = oneshot::channel();
+			if tx.send(WriterMessage::Flush(done)).is_ok() {
+				let _ = written.await;
+			}
+		}
+	}
+}
+
+impl fmt::Debug for Output {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self.sink {
+			Sink::Stdout => write!(f, "Output(stdout)"),
+			Sink::Stream(_) => write!(f, "Output(custom)"),
+			#[cfg(feature = "async")]
+			Sink::Writer(_) => write!(f, "Output(writer)"),
+		}
+	}
+}
+
+fn write_line_to<W>(handle: &mut W, line: &[u8]) -> io::Result<()>
+where
+	W: Write + ?Sized,
+{
+	handle.write_all(line)?;
+	handle.write_all(b"\n")?;
+	handle.flush()
+}
+
+/// Writes the lines exchanged with OpenSMTPD to a transcript, which
+/// can be read back using a [`Transcript`](crate::Transcript).
+///
+/// Each line is prefixed with the time it was received or sent and its
+/// direction: `1576146008.006099 < config|ready`.
+#[derive(Clone)]
+pub(crate) struct Recorder {
+	inner: Arc<Mutex<Box<dyn Write + Send>>>,
+}
+
+impl Recorder {
+	pub(crate) fn new<W>(transcript: W) -> Self
+	where
+		W: Write + Send + 'static,
+	{
+		Recorder {
+			inner: Arc::new(Mutex::new(Box::new(transcript))),
+		}
+	}
+
+	pub(crate) fn record(&self, direction: Direction, line: &[u8]) {
+		let line = line.strip_suffix(b"\n").unwrap_or(line);
+		let now = SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.unwrap_or_default();
+		let mut entry = format!(
+			"{}.{:06} {} ",
+			now.as_secs(),
+			now.subsec_micros(),
+			direction
+		)
+		.into_bytes();
+		entry.extend_from_slice(line);
+		let mut handle = match self.inner.lock() {
+			Ok(h) => h,
+			Err(e) => e.into_inner(),
+		};
+		if let Err(e) = write_line_to(&mut *handle, &entry) {
+			log::error!("unable to record a line: {}", e);
+		}
+	}
+}
+
+/// In-memory stream the lines of a filter are written to.
+#[derive(Clone, Default)]
+pub(crate) struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+impl Buffer {
+	/// Returns the content written so far and empties the buffer.
+	pub(crate) fn take(&self) -> Vec<u8> {
+		std::mem::take(&mut *self.0.lock().unwrap())
+	}
+}
+
+impl Write for Buffer {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		self.0.lock().unwrap().write(buf)
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		Ok(())
+	}
+}
@@ -0,0 +1,446 @@
+use crate::{
+	Address, AuthResult, Filter, FilterConfig, FilterEntry, FilterKind, FilterPhase,
+	FilterResponse, MailResult, Message, Method, ReportEntry, ShutdownReason, SubSystem,
+};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+macro_rules! layered_filter {
+	(
+		filters { $($(#[$f_attr: meta])* $f_name: ident, $f_has: ident, ($($f_param: ident: $f_type: ty),*);)* }
+		reports { $($(#[$r_attr: meta])* $r_name: ident, $r_has: ident, $r_event: ident, ($($r_param: ident: $r_type: ty),*);)* }
+	) => {
+		impl<L, F> Filter for Layered<L, F>
+		where
+			L: Layer,
+			F: Filter,
+		{
+			fn on_config(&mut self, config: &FilterConfig) {
+				self.inner.on_config(config);
+			}
+
+			fn on_ready(&mut self) {
+				self.inner.on_ready();
+			}
+
+			fn on_shutdown(&mut self, reason: &ShutdownReason) {
+				self.inner.on_shutdown(reason);
+			}
+
+			fn on_panic(&mut self, session_id: &str, message: &str) {
+				self.inner.on_panic(session_id, message);
+			}
+
+			$(
+				fn $f_name(&mut self, entry: &FilterEntry, $($f_param: $f_type),*) -> FilterResponse {
+					if let Some(response) = self.layer.on_filter(entry) {
+						return response;
+					}
+					match self.inner.$f_name(entry, $($f_param),*) {
+						FilterResponse::Pending => FilterResponse::Pending,
+						response => self.layer.on_response(entry, response),
+					}
+				}
+
+				fn $f_has(&self, subsystem: &SubSystem) -> bool {
+					self.inner.$f_has(subsystem)
+				}
+			)*
+
+			fn on_filter_data_line(&mut self, entry: &FilterEntry, data_line: &[u8]) {
+				self.inner.on_filter_data_line(entry, data_line);
+			}
+
+			fn has_filter_data_line(&self, subsystem: &SubSystem) -> bool {
+				self.inner.has_filter_data_line(subsystem)
+			}
+
+			fn on_filter_data_end(&mut self, entry: &FilterEntry) {
+				self.inner.on_filter_data_end(entry);
+			}
+
+			fn on_message(&mut self, entry: &FilterEntry, message: Message) -> Vec<u8> {
+				self.inner.on_message(entry, message)
+			}
+
+			fn has_message(&self, subsystem: &SubSystem) -> bool {
+				self.inner.has_message(subsystem)
+			}
+
+			$(
+				fn $r_name(&mut self, entry: &ReportEntry, $($r_param: $r_type),*) {
+					if self.layer.on_report(entry) {
+						self.inner.$r_name(entry, $($r_param),*);
+					}
+				}
+
+				fn $r_has(&self, subsystem: &SubSystem) -> bool {
+					self.inner.$r_has(subsystem)
+				}
+			)*
+		}
+	};
+}
+
+/// Code run around the handlers of a filter.
+///
+/// A layer sees every filter request and report registered by the
+/// filter it wraps. It may answer a filter request itself, in which
+/// case the inner filter is not called, or modify the response of the
+/// inner filter. Layers are stacked by wrapping a [`Layered`] filter
+/// into another one, the outermost layer being called first.
+///
+/// ``` rust,no_run
+/// use opensmtpd::{run_filter, Filter, FilterEntry, FilterResponse, Layer, LogLayer};
+///
+/// struct Trusted {}
+///
+/// impl Layer for Trusted {
+///		fn on_filter(&mut self, entry: &FilterEntry) -> Option<FilterResponse> {
+///			match entry.session_id.as_str() {
+///				"42" => Some(FilterResponse::Proceed),
+///				_ => None,
+///			}
+///		}
+/// }
+///
+/// struct MyFilter {}
+/// impl Filter for MyFilter {}
+///
+/// let mut filter = LogLayer::default().layer(Trusted {}.layer(MyFilter {}));
+/// run_filter(&mut filter);
+/// ```
+pub trait Layer {
+	/// Called before the inner filter handles a filter request. When a
+	/// response is returned, it is sent without calling the inner filter.
+	fn on_filter(&mut self, _entry: &FilterEntry) -> Option<FilterResponse> {
+		None
+	}
+
+	/// Called with the response of the inner filter, returns the
+	/// response to send.
+	///
+	/// Not called when the inner filter defers its response using
+	/// [`FilterResponse::Pending`]: the response sent later by the
+	/// [`FilterResponder`](crate::FilterResponder) does not go through
+	/// the layer.
+	fn on_response(&mut self, _entry: &FilterEntry, response: FilterResponse) -> FilterResponse {
+		response
+	}
+
+	/// Called before the inner filter handles a report. The report is
+	/// not given to the inner filter when `false` is returned.
+	fn on_report(&mut self, _entry: &ReportEntry) -> bool {
+		true
+	}
+
+	/// Wraps a filter into this layer.
+	fn layer<F>(self, inner: F) -> Layered<Self, F>
+	where
+		Self: Sized,
+		F: Filter,
+	{
+		Layered::new(self, inner)
+	}
+}
+
+/// A filter wrapped into a [`Layer`].
+///
+/// Data-lines and messages are given to the inner filter as-is.
+pub struct Layered<L, F> {
+	layer: L,
+	inner: F,
+}
+
+impl<L, F> Layered<L, F>
+where
+	L: Layer,
+	F: Filter,
+{
+	pub fn new(layer: L, inner: F) -> Self {
+		Layered { layer, inner }
+	}
+
+	pub fn layer(&self) -> &L {
+		&self.layer
+	}
+
+	pub fn inner(&self) -> &F {
+		&self.inner
+	}
+
+	pub fn inner_mut(&mut self) -> &mut F {
+		&mut self.inner
+	}
+
+	pub fn into_inner(self) -> F {
+		self.inner
+	}
+}
+
+for_each_handler!(layered_filter);
+
+/// Layer logging every filter request, its response and every report.
+pub struct LogLayer {
+	level: log::Level,
+}
+
+impl Default for LogLayer {
+	fn default() -> Self {
+		LogLayer {
+			level: log::Level::Info,
+		}
+	}
+}
+
+impl LogLayer {
+	pub fn new(level: log::Level) -> Self {
+		LogLayer { level }
+	}
+}
+
+impl Layer for LogLayer {
+	fn on_filter(&mut self, entry: &FilterEntry) -> Option<FilterResponse> {
+		log::log!(
+			self.level,
+			"{}: {}: filter {}",
+			entry.subsystem,
+			entry.session_id,
+			entry.phase
+		);
+		None
+	}
+
+	fn on_response(&mut self, entry: &FilterEntry, response: FilterResponse) -> FilterResponse {
+		log::log!(
+			self.level,
+			"{}: {}: {}: {}",
+			entry.subsystem,
+			entry.session_id,
+			entry.phase,
+			response
+		);
+		response
+	}
+
+	fn on_report(&mut self, entry: &ReportEntry) -> bool {
+		log::log!(
+			self.level,
+			"{}: {}: report {}",
+			entry.subsystem,
+			entry.session_id,
+			entry.event
+		);
+		true
+	}
+}
+
+/// Time spent by a filter in a given phase.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct PhaseLatency {
+	pub count: u64,
+	pub total: Duration,
+	pub max: Duration,
+}
+
+impl PhaseLatency {
+	pub fn mean(&self) -> Duration {
+		match self.count {
+			0 => Duration::default(),
+			n => self.total / n as u32,
+		}
+	}
+
+	fn add(&mut self, duration: Duration) {
+		self.count += 1;
+		self.total += duration;
+		self.max = self.max.max(duration);
+	}
+}
+
+/// Layer measuring the time spent by the inner filter in each phase.
+///
+/// Each measure is logged at the debug level and the statistics are
+/// available using [`latencies`](LatencyLayer::latencies). The requests
+/// answered with [`FilterResponse::Pending`] are not measured, since
+/// their response does not go through the layer.
+#[derive(Default)]
+pub struct LatencyLayer {
+	/// Session id and token of the request being measured.
+	start: Option<(String, String, Instant)>,
+	latencies: HashMap<FilterPhase, PhaseLatency>,
+}
+
+impl LatencyLayer {
+	pub fn new() -> Self {
+		LatencyLayer::default()
+	}
+
+	pub fn latencies(&self) -> &HashMap<FilterPhase, PhaseLatency> {
+		&self.latencies
+	}
+}
+
+impl Layer for LatencyLayer {
+	fn on_filter(&mut self, entry: &FilterEntry) -> Option<FilterResponse> {
+		self.start = Some((
+			entry.session_id.clone(),
+			entry.token.clone(),
+			Instant::now(),
+		));
+		None
+	}
+
+	fn on_response(&mut self, entry: &FilterEntry, response: FilterResponse) -> FilterResponse {
+		// A start left by a request which did not come back through the
+		// layer, e.g. a pending one, is not used for this response.
+		let start = match self.start.take() {
+			Some((session_id, token, start))
+				if session_id == entry.session_id && token == entry.token =>
+			{
+				Some(start)
+			}
+			_ => None,
+		};
+		if let Some(start) = start {
+			let duration = start.elapsed();
+			log::debug!(
+				"{}: {}: {} took {:?}",
+				entry.subsystem,
+				entry.session_id,
+				entry.phase,
+				duration
+			);
+			self.latencies
+				.entry(entry.phase.clone())
+				.or_default()
+				.add(duration);
+		}
+		response
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{LatencyLayer, Layer, LogLayer};
+	use crate::io::Output;
+	use crate::runner::tests::run_lines;
+	use crate::{
+		Filter, FilterEntry, FilterPhase, FilterResponder, FilterResponse, ProtocolVersion,
+		ReportEntry, SmtpStatusCode, SubSystem, TimeVal,
+	};
+
+	#[derive(Default)]
+	struct Helo {
+		disconnects: usize,
+	}
+
+	impl Filter for Helo {
+		fn on_filter_helo(&mut self, entry: &FilterEntry, identity: &str) -> FilterResponse {
+			match identity {
+				"spammer" => FilterResponse::Junk,
+				"deferred" => {
+					FilterResponder::new(entry).respond(FilterResponse::Junk);
+					FilterResponse::Pending
+				}
+				_ => FilterResponse::Proceed,
+			}
+		}
+
+		fn has_filter_helo(&self, subsystem: &SubSystem) -> bool {
+			*subsystem == SubSystem::SmtpIn
+		}
+
+		fn on_report_link_disconnect(&mut self, _entry: &ReportEntry) {
+			self.disconnects += 1;
+		}
+
+		fn has_report_link_disconnect(&self, subsystem: &SubSystem) -> bool {
+			*subsystem == SubSystem::SmtpIn
+		}
+	}
+
+	/// Lets the trusted session through and turns junk into a rejection.
+	struct Policy {}
+
+	impl Layer for Policy {
+		fn on_filter(&mut self, entry: &FilterEntry) -> Option<FilterResponse> {
+			match entry.session_id.as_str() {
+				"trusted" => Some(FilterResponse::Proceed),
+				_ => None,
+			}
+		}
+
+		fn on_response(
+			&mut self,
+			_entry: &FilterEntry,
+			response: FilterResponse,
+		) -> FilterResponse {
+			assert!(!matches!(response, FilterResponse::Pending));
+			match response {
+				FilterResponse::Junk => FilterResponse::Reject(SmtpStatusCode::from_number(550)),
+				r => r,
+			}
+		}
+
+		fn on_report(&mut self, entry: &ReportEntry) -> bool {
+			entry.session_id != "trusted"
+		}
+	}
+
+	#[test]
+	fn test_layers() {
+		let mut filter =
+			LogLayer::default().layer(LatencyLayer::new().layer(Policy {}.layer(Helo::default())));
+		let input = "filter|0.5|1576146008.006099|smtp-in|helo|s1|t1|spammer\n\
+			filter|0.5|1576146008.006099|smtp-in|helo|trusted|t2|spammer\n\
+			filter|0.5|1576146008.006099|smtp-in|helo|s2|t3|example.org\n\
+			filter|0.5|1576146008.006099|smtp-in|helo|s3|t4|deferred\n\
+			report|0.5|1576146008.006099|smtp-in|link-disconnect|s1\n\
+			report|0.5|1576146008.006099|smtp-in|link-disconnect|trusted\n";
+		let lines = run_lines(&mut filter, input);
+		assert_eq!(
+			lines,
+			vec![
+				"register|filter|smtp-in|helo",
+				"register|report|smtp-in|link-disconnect",
+				"register|ready",
+				"filter-result|s1|t1|reject|550 Requested action not taken: mailbox unavailable",
+				"filter-result|trusted|t2|proceed",
+				"filter-result|s2|t3|proceed",
+				"filter-result|s3|t4|junk",
+			]
+		);
+		let latency = filter.inner().layer();
+		assert_eq!(latency.latencies()[&FilterPhase::Helo].count, 3);
+		assert_eq!(filter.inner().inner().inner().disconnects, 1);
+	}
+
+	fn entry(phase: FilterPhase, token: &str) -> FilterEntry {
+		FilterEntry {
+			version: ProtocolVersion::V0_5,
+			timestamp: TimeVal { sec: 0, usec: 0 },
+			subsystem: SubSystem::SmtpIn,
+			phase,
+			session_id: "s1".to_string(),
+			token: token.to_string(),
+			output: Output::default(),
+		}
+	}
+
+	#[test]
+	fn test_latency_pending() {
+		let mut layer = LatencyLayer::new();
+		let helo = entry(FilterPhase::Helo, "t1");
+		assert!(layer.on_filter(&helo).is_none());
+		// The helo request is pending, the next response is for another
+		// request which did not go through on_filter.
+		let mail_from = entry(FilterPhase::MailFrom, "t2");
+		layer.on_response(&mail_from, FilterResponse::Proceed);
+		assert!(layer.latencies().is_empty());
+		assert!(layer.on_filter(&mail_from).is_none());
+		layer.on_response(&mail_from, FilterResponse::Proceed);
+		assert_eq!(layer.latencies()[&FilterPhase::MailFrom].count, 1);
+		assert!(!layer.latencies().contains_key(&FilterPhase::Helo));
+	}
+}
@@ -14,7 +14,55 @@
 //! corresponding events and filter requests.
 //!
 //! The second and last step is to call the [`run_filter`] function
-//! with a mutable reference of your filter object.
+//! with a mutable reference of your filter object. This function
+//! reads on the standard input and writes on the standard output. If
+//! you need to use other streams, use a [`FilterRunner`] instead.
+//!
+//! ## Subsystems
+//!
+//! By default, the [`opensmtpd_derive::register`] attribute registers
+//! the method for the `smtp-in` subsystem only. Reports are also
+//! available for the `smtp-out` subsystem, in which case the
+//! subsystems have to be listed: `#[register(smtp_in, smtp_out)]`.
+//! The subsystem an event comes from is available in the
+//! [`ReportEntry`]. Filters are only available for `smtp-in`.
+//!
+//! Instead of using `register` on each method, the [`filter`]
+//! attribute may be placed on the whole implementation. It registers
+//! every handler, accepts the same subsystems and reports unknown
+//! handlers or wrong signatures at compile time.
+//!
+//! ``` rust
+//! use opensmtpd::{run_filter, Filter, FilterEntry, FilterResponse, ReportEntry};
+//!
+//! struct NoSpammer {}
+//!
+//! #[opensmtpd::filter]
+//! impl Filter for NoSpammer {
+//!		fn on_filter_helo(&mut self, _entry: &FilterEntry, identity: &str) -> FilterResponse {
+//!			if identity == "spammer" {
+//!				FilterResponse::Junk
+//!			} else {
+//!				FilterResponse::Proceed
+//!			}
+//!		}
+//!
+//!		fn on_report_link_disconnect(&mut self, _entry: &ReportEntry) {}
+//! }
+//! ```
+//!
+//! ## Lifecycle
+//!
+//! The [`on_config`](Filter::on_config) method receives the
+//! [`FilterConfig`] sent by OpenSMTPD during the handshake, such as
+//! its version and the SMTP session timeout. The
+//! [`on_ready`](Filter::on_ready) method is called once the
+//! handshake with OpenSMTPD is done. When the input is closed, which
+//! is what OpenSMTPD does when it stops, or if an error prevents the
+//! filter from reading it, the [`on_shutdown`](Filter::on_shutdown)
+//! method is called and [`run_filter`] returns the corresponding
+//! [`ShutdownReason`]. This is the place where you can save your
+//! data, close your connections and so on.
 //!
 //! ## Reports
 //!
@@ -43,8 +91,96 @@
 //! triggered: you can store all of the data-lines, edit them and
 //! then call [`return_data_line`] on each.
 //!
-//! The last data-line you will receive is a single dot. The last one
-//! you return must also be a single dot.
+//! The dot-stuffing is handled by the library: the data-lines you
+//! receive are unstuffed and the ones you return are stuffed when
+//! needed. Once the message has been received,
+//! [`on_filter_data_end`](Filter::on_filter_data_end) is called. You
+//! must then end the message you return using [`return_data_end`],
+//! which is what the default implementation does.
+//!
+//! Filters needing the whole message may register
+//! [`on_message`](Filter::on_message) instead. The data-lines are then
+//! buffered by the library and the handler is called once with the
+//! complete [`Message`]. The returned content is sent back with the
+//! lines stuffed and the final dot added. The maximum size of the
+//! buffered messages can be set using
+//! [`FilterRunner::max_message_size`].
+//!
+//! The [`HeaderReader`] separates the headers from the body in the
+//! data-lines, while [`Headers::parse`] does the same on a complete
+//! message. The resulting [`Headers`] may be edited and sent back
+//! using [`return_headers`].
+//!
+//! Multipart messages can be parsed into a tree of [`MimePart`], which
+//! decodes the transfer encodings and can be serialized back once
+//! modified.
+//!
+//! ## Asynchronous filters
+//!
+//! When the `async` feature is enabled, filters may implement the
+//! [`AsyncFilter`] trait instead of [`Filter`] and be run with the
+//! `run_async_filter` function inside a tokio runtime.
+//!
+//! ## Deferred responses
+//!
+//! Filters that cannot take their decision right away may create a
+//! [`FilterResponder`] from the [`FilterEntry`] and return
+//! [`FilterResponse::Pending`]. The responder can be moved to another
+//! thread and used later to send the response, while other sessions
+//! keep being processed.
+//!
+//! ## Sessions
+//!
+//! Filters that need to keep some state for each SMTP session may
+//! implement [`SessionFilter`] instead of [`Filter`]. The state, an
+//! associated `Session` type, is created when the client connects,
+//! passed to every handler of that session and dropped when the client
+//! disconnects. Such a filter is run by wrapping it into [`Sessions`].
+//! The session also keeps track of the current [`Transaction`], built
+//! from the `tx-*` reports.
+//!
+//! ## Runtime registration
+//!
+//! Filters whose handlers are only known at runtime, e.g. from a
+//! configuration file, may be built from closures using a
+//! [`FilterBuilder`]. Only the handlers which have been set are
+//! registered.
+//!
+//! ## Chains
+//!
+//! Several filters may be run in a single process using a
+//! [`FilterChain`]. Filter requests go through each filter in order
+//! until one of them does not proceed, while reports are sent to all of
+//! them and data-lines are piped from one filter to the next.
+//!
+//! ## Layers
+//!
+//! Code shared by several filters, such as logging or allowlists, may
+//! be written as a [`Layer`] wrapping a filter. A layer may answer a
+//! filter request instead of the filter it wraps, change its response or
+//! drop reports. The [`LogLayer`] and [`LatencyLayer`] are provided.
+//!
+//! ## Testing
+//!
+//! The [`testing`] module provides a simulated OpenSMTPD which performs
+//! the handshake with a filter and drives SMTP sessions, so filters can
+//! be tested without running OpenSMTPD.
+//!
+//! The lines exchanged with OpenSMTPD in production may be recorded
+//! using [`FilterRunner::record`]. The resulting [`Transcript`] can be
+//! replayed on a filter to check its responses did not change.
+//!
+//! ## Errors and panics
+//!
+//! A line which cannot be processed does not stop the filter: the
+//! [`Error`] is logged and passed to the hook set with
+//! [`FilterRunner::on_error`]. If the line is a filter request, it is
+//! answered with [`FilterRunner::fail_response`] so the session does not
+//! hang. Likewise, a panicking handler is caught, [`Filter::on_panic`]
+//! is called and the request is answered with
+//! [`FilterRunner::panic_response`]. When a data-line or message handler
+//! panics, the message is sent back unmodified from the line being
+//! processed, so it is neither lost nor truncated.
 //!
 //! # Examples
 //!
@@ -74,36 +210,45 @@
 //!
 //! The following filter removes the `X-Originating-Ip` header.
 //!
-//! Be careful, this is not production-ready since it does not
-//! support long headers and cannot differentiate a header from
-//! the body's content.
-//!
 //! ``` rust
-//! use opensmtpd::{return_data_line, run_filter, Filter, FilterEntry};
+//! use opensmtpd::{return_data_end, return_data_line, return_headers, run_filter, FilterEntry};
+//! use opensmtpd::{HeaderEvent, HeaderReader, SessionContext, SessionFilter, Sessions};
 //! use opensmtpd_derive::register;
 //!
-//! pub const HEADER_NAME: &str = "x-originating-ip:";
-//! pub const HEADER_LEN: usize = 17;
-//!
 //! struct RmXOriginatingIp {}
 //!
-//! impl Filter for RmXOriginatingIp {
+//! impl SessionFilter for RmXOriginatingIp {
+//!		type Session = HeaderReader;
+//!
 //!		#[register]
-//!		fn on_filter_data_line(&mut self, entry: &FilterEntry, data_line: &[u8]) {
-//!			if data_line.len() >= HEADER_LEN {
-//!				let head_start = data_line[..HEADER_LEN].to_vec();
-//!				if let Ok(s) = String::from_utf8(head_start) {
-//!					if s.to_lowercase() == HEADER_NAME {
-//!						return;
-//!					}
+//!		fn on_filter_data_line(
+//!			&mut self,
+//!			entry: &FilterEntry,
+//!			reader: &mut SessionContext<HeaderReader>,
+//!			data_line: &[u8],
+//!		) {
+//!			match reader.push(data_line) {
+//!				HeaderEvent::Header => {}
+//!				HeaderEvent::EndOfHeaders(mut headers) => {
+//!					headers.remove("X-Originating-Ip");
+//!					return_headers(entry, &headers);
+//!					return_data_line(entry, data_line);
 //!				}
+//!				HeaderEvent::Body => return_data_line(entry, data_line),
+//!			}
+//!		}
+//!
+//!		fn on_filter_data_end(&mut self, entry: &FilterEntry, reader: &mut SessionContext<HeaderReader>) {
+//!			if let Some(mut headers) = reader.end() {
+//!				headers.remove("X-Originating-Ip");
+//!				return_headers(entry, &headers);
 //!			}
-//!			return_data_line(entry, data_line);
+//!			return_data_end(entry);
 //!		}
 //! }
 //!
 //! fn main() {
-//!		let mut my_filter = RmXOriginatingIp {};
+//!		let mut my_filter = Sessions::new(RmXOriginatingIp {});
 //!		run_filter(&mut my_filter);
 //! }
 //! ```
@@ -132,15 +277,39 @@
 //! man =(curl -sSf "https://raw.githubusercontent.com/OpenSMTPD/OpenSMTPD/master/usr.sbin/smtpd/smtpd-filters.7")
 //! ```
 
+// The examples are indented with tabs, as the rest of the code.
+#![allow(clippy::tabs_in_doc_comments)]
+
+extern crate self as opensmtpd;
+
+#[macro_use]
+mod handlers;
+#[cfg(feature = "async")]
+mod async_filter;
+mod builder;
+mod chain;
 mod data_line;
 mod data_structures;
 mod error;
 mod filter;
+mod headers;
 mod io;
+mod layer;
+mod message;
+mod mime;
 mod parsers;
 mod process;
+mod replay;
+mod responder;
+mod runner;
+mod session;
+pub mod testing;
 
-pub use crate::data_line::return_data_line;
+#[cfg(feature = "async")]
+pub use crate::async_filter::AsyncFilter;
+pub use crate::builder::{ClosureFilter, FilterBuilder};
+pub use crate::chain::FilterChain;
+pub use crate::data_line::{return_data_end, return_data_line};
 pub use crate::data_structures::address::Address;
 pub use crate::data_structures::auth_result::AuthResult;
 pub use crate::data_structures::event::Event;
@@ -149,136 +318,71 @@ pub use crate::data_structures::filter_phase::FilterPhase;
 pub use crate::data_structures::filter_response::FilterResponse;
 pub use crate::data_structures::mail_result::MailResult;
 pub use crate::data_structures::method::Method;
+pub use crate::data_structures::protocol_version::ProtocolVersion;
+pub use crate::data_structures::shutdown_reason::ShutdownReason;
 pub use crate::data_structures::smtp_status::SmtpStatusCode;
 pub use crate::data_structures::subsystem::SubSystem;
 pub use crate::data_structures::timeval::TimeVal;
+pub use crate::data_structures::transaction::{Transaction, TransactionState};
+pub use crate::error::Error;
 pub use crate::filter::Filter;
+pub use crate::headers::{return_headers, Header, HeaderEvent, HeaderReader, Headers};
+pub use crate::layer::{LatencyLayer, Layer, Layered, LogLayer, PhaseLatency};
+pub use crate::message::{return_message, Message};
+pub use crate::mime::content_type::ContentType;
+pub use crate::mime::encoding::TransferEncoding;
+pub use crate::mime::MimePart;
 pub use crate::parsers::entry::{FilterEntry, ReportEntry};
-
-use crate::parsers::handshake::parse_handshake;
-use std::sync::mpsc::channel;
-use std::thread;
+pub use crate::parsers::handshake::FilterConfig;
+pub use crate::replay::{Direction, Mismatch, Transcript, TranscriptEntry};
+pub use crate::responder::FilterResponder;
+pub use crate::runner::FilterRunner;
+pub use crate::session::{SessionContext, SessionFilter, Sessions};
+#[cfg(feature = "async")]
+pub use async_trait::async_trait;
+pub use opensmtpd_derive::{filter, register};
 
 const BUFFER_SIZE: usize = 4096;
 
-macro_rules! recv {
-	($rx: ident) => {
-		match $rx.recv() {
-			Ok(b) => b,
-			Err(e) => {
-				log::error!("{}", e);
-				return;
-			}
-		}
-	};
-}
-
-pub fn run_filter<T>(user_object: &mut T)
+pub fn run_filter<T>(user_object: &mut T) -> ShutdownReason
 where
 	T: Filter,
 {
-	// IO init
-	let (tx, rx) = channel::<Vec<u8>>();
-	thread::spawn(move || {
-		io::read_stdin(&tx);
-	});
-
-	// Handshake
-	let mut handshake_buffer: Vec<u8> = Vec::with_capacity(BUFFER_SIZE);
-	let handshake = loop {
-		let buffer = recv!(rx);
-		handshake_buffer.extend_from_slice(&buffer);
-		if let Ok((_, handshake)) = parse_handshake(&handshake_buffer) {
-			break handshake;
-		}
-	};
-	handshake_reply(user_object, handshake.subsystem);
-
-	// Read and process input
-	loop {
-		let buffer = recv!(rx);
-		if let Err(msg) = process::line(user_object, &buffer) {
-			log::error!("{}", msg);
-		}
-	}
-}
-
-macro_rules! handshake_register {
-	($obj: ident, $func: ident, $subsystem: expr, $type: expr, $name: expr) => {
-		if $obj.$func() {
-			println!("register|{}|{}|{}", $type, $subsystem.to_string(), $name);
-			log::trace!(
-				"{} {} for {} registered",
-				$type,
-				$name,
-				$subsystem.to_string()
-			);
-		}
-	};
+	FilterRunner::new().run(user_object)
 }
 
-fn handshake_reply<T>(obj: &mut T, ss: SubSystem)
+/// Asynchronous version of [`run_filter`].
+///
+/// The filter requests are processed in separate tokio tasks, hence
+/// this function must be called from within a tokio runtime.
+///
+/// ``` rust,no_run
+/// use opensmtpd::{async_trait, run_async_filter, AsyncFilter, FilterEntry, FilterResponse};
+/// use opensmtpd_derive::register;
+///
+/// struct MyFilter {}
+///
+/// #[async_trait]
+/// impl AsyncFilter for MyFilter {
+///		#[register]
+///		async fn on_filter_rcpt_to(&self, _entry: &FilterEntry, _address: &str) -> FilterResponse {
+///			// Some asynchronous lookup here.
+///			FilterResponse::Proceed
+///		}
+/// }
+///
+/// fn main() {
+///		// Any tokio runtime may be used, e.g. using `#[tokio::main]`.
+///		let runtime = tokio::runtime::Builder::new_current_thread()
+///			.build()
+///			.unwrap();
+///		runtime.block_on(run_async_filter(MyFilter {}));
+/// }
+/// ```
+#[cfg(feature = "async")]
+pub async fn run_async_filter<T>(user_object: T) -> ShutdownReason
 where
-	T: Filter,
+	T: AsyncFilter + 'static,
 {
-	// Filters
-	handshake_register!(obj, has_filter_auth, ss, "filter", "auth");
-	handshake_register!(obj, has_filter_commit, ss, "filter", "commit");
-	handshake_register!(obj, has_filter_connect, ss, "filter", "connect");
-	handshake_register!(obj, has_filter_data, ss, "filter", "data");
-	handshake_register!(obj, has_filter_data_line, ss, "filter", "data-line");
-	handshake_register!(obj, has_filter_ehlo, ss, "filter", "ehlo");
-	handshake_register!(obj, has_filter_helo, ss, "filter", "helo");
-	handshake_register!(obj, has_filter_mail_from, ss, "filter", "mail-from");
-	handshake_register!(obj, has_filter_rcpt_to, ss, "filter", "rcpt-to");
-	handshake_register!(obj, has_filter_starttls, ss, "filter", "starttls");
-
-	// Reports
-	handshake_register!(obj, has_report_link_auth, ss, "report", "link-auth");
-	handshake_register!(obj, has_report_link_connect, ss, "report", "link-connect");
-	handshake_register!(
-		obj,
-		has_report_link_disconnect,
-		ss,
-		"report",
-		"link-disconnect"
-	);
-	handshake_register!(obj, has_report_link_greeting, ss, "report", "link-greeting");
-	handshake_register!(obj, has_report_link_identify, ss, "report", "link-identify");
-	handshake_register!(obj, has_report_link_tls, ss, "report", "link-tls");
-	handshake_register!(obj, has_report_tx_begin, ss, "report", "tx-begin");
-	handshake_register!(obj, has_report_tx_mail, ss, "report", "tx-mail");
-	handshake_register!(obj, has_report_tx_reset, ss, "report", "tx-reset");
-	handshake_register!(obj, has_report_tx_rcpt, ss, "report", "tx-rcpt");
-	handshake_register!(obj, has_report_tx_envelope, ss, "report", "tx-envelope");
-	handshake_register!(obj, has_report_tx_data, ss, "report", "tx-data");
-	handshake_register!(obj, has_report_tx_commit, ss, "report", "tx-commit");
-	handshake_register!(obj, has_report_tx_rollback, ss, "report", "tx-rollback");
-	handshake_register!(
-		obj,
-		has_report_protocol_client,
-		ss,
-		"report",
-		"protocol-client"
-	);
-	handshake_register!(
-		obj,
-		has_report_protocol_server,
-		ss,
-		"report",
-		"protocol-server"
-	);
-	handshake_register!(
-		obj,
-		has_report_filter_response,
-		ss,
-		"report",
-		"filter-response"
-	);
-	handshake_register!(obj, has_report_filter_report, ss, "report", "filter-report");
-	handshake_register!(obj, has_report_timeout, ss, "report", "timeout");
-
-	// Ready
-	println!("register|ready");
-	log::trace!("register ready");
+	FilterRunner::new().run_async(user_object).await
 }
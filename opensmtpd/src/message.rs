@@ -0,0 +1,358 @@
+use crate::data_line::{return_data_end, return_data_line};
+use crate::FilterEntry;
+use std::collections::HashMap;
+
+/// Default maximum size of a buffered message, which is also the
+/// default `max-message-size` of OpenSMTPD.
+pub(crate) const DEFAULT_MAX_MESSAGE_SIZE: usize = 35 * 1024 * 1024;
+
+/// Complete message received through the data-lines.
+///
+/// The lines are terminated by CRLF.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Message {
+	content: Vec<u8>,
+}
+
+impl Message {
+	fn from_lines(lines: &[Vec<u8>]) -> Self {
+		let size = lines.iter().map(|l| l.len() + 2).sum();
+		let mut content = Vec::with_capacity(size);
+		for line in lines {
+			content.extend_from_slice(line);
+			content.extend_from_slice(b"\r\n");
+		}
+		Message { content }
+	}
+
+	pub fn as_bytes(&self) -> &[u8] {
+		&self.content
+	}
+
+	pub fn into_bytes(self) -> Vec<u8> {
+		self.content
+	}
+
+	pub fn len(&self) -> usize {
+		self.content.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.content.is_empty()
+	}
+}
+
+impl From<Vec<u8>> for Message {
+	fn from(content: Vec<u8>) -> Self {
+		Message { content }
+	}
+}
+
+/// Sends a complete message back to OpenSMTPD.
+///
+/// The message is split into lines, which may be terminated either by
+/// LF or CRLF, and the end of the message is sent.
+pub fn return_message(entry: &FilterEntry, message: &[u8]) {
+	let message = message.strip_suffix(b"\n").unwrap_or(message);
+	if !message.is_empty() {
+		for line in message.split(|&c| c == b'\n') {
+			let line = line.strip_suffix(b"\r").unwrap_or(line);
+			return_data_line(entry, line);
+		}
+	}
+	return_data_end(entry);
+}
+
+enum Buffer {
+	Lines(Vec<Vec<u8>>, usize),
+	/// The message is sent back unmodified, either because it is too
+	/// large or because its handler panicked.
+	PassThrough,
+}
+
+/// Data-lines of the messages being received, by session and token.
+pub(crate) struct MessageBuffers {
+	max_size: usize,
+	buffers: HashMap<(String, String), Buffer>,
+}
+
+impl MessageBuffers {
+	pub(crate) fn new(max_size: usize) -> Self {
+		MessageBuffers {
+			max_size,
+			buffers: HashMap::new(),
+		}
+	}
+
+	/// Adds a data-line to the message and returns it once complete,
+	/// which is signaled by `None`.
+	///
+	/// Once the maximum size is exceeded, the lines are sent back
+	/// unmodified and the message is never returned.
+	pub(crate) fn push(
+		&mut self,
+		entry: &FilterEntry,
+		data_line: Option<&[u8]>,
+	) -> Option<Message> {
+		let key = (entry.session_id.clone(), entry.token.clone());
+		let data_line = match data_line {
+			Some(l) => l,
+			None => {
+				return match self.buffers.remove(&key) {
+					Some(Buffer::Lines(lines, _)) => Some(Message::from_lines(&lines)),
+					Some(Buffer::PassThrough) => {
+						return_data_end(entry);
+						None
+					}
+					None => Some(Message::default()),
+				};
+			}
+		};
+		let buffer = self
+			.buffers
+			.entry(key)
+			.or_insert_with(|| Buffer::Lines(Vec::new(), 0));
+		match buffer {
+			Buffer::Lines(lines, size) => {
+				*size += data_line.len() + 2;
+				if *size <= self.max_size {
+					lines.push(data_line.to_vec());
+					return None;
+				}
+				log::warn!(
+					"message exceeds {} bytes, sending it unmodified (session id: {}, token: {})",
+					self.max_size,
+					entry.session_id,
+					entry.token
+				);
+				for line in lines.iter() {
+					return_data_line(entry, line);
+				}
+				*buffer = Buffer::PassThrough;
+			}
+			Buffer::PassThrough => {}
+		}
+		return_data_line(entry, data_line);
+		None
+	}
+
+	/// Returns whether the data-line belongs to a message sent back
+	/// unmodified, in which case it has been sent and must not be given
+	/// to the handlers.
+	pub(crate) fn skip(&mut self, entry: &FilterEntry, data_line: Option<&[u8]>) -> bool {
+		let key = (entry.session_id.clone(), entry.token.clone());
+		if !matches!(self.buffers.get(&key), Some(Buffer::PassThrough)) {
+			return false;
+		}
+		self.push(entry, data_line);
+		true
+	}
+
+	/// Sends back unmodified the data-line whose handler panicked and
+	/// the rest of the message, so the message is not truncated.
+	pub(crate) fn pass_through(&mut self, entry: &FilterEntry, data_line: Option<&[u8]>) {
+		let key = (entry.session_id.clone(), entry.token.clone());
+		self.buffers.insert(key, Buffer::PassThrough);
+		self.push(entry, data_line);
+	}
+
+	/// Drops the messages of a session which ended while they were
+	/// being received.
+	pub(crate) fn purge(&mut self, session_id: &str) {
+		self.buffers.retain(|(s, _), _| s != session_id);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::Message;
+	use crate::runner::tests::{run_lines, run_with};
+	use crate::{return_data_line, Filter, FilterEntry, FilterRunner};
+	use opensmtpd_derive::register;
+
+	struct Upper {}
+
+	impl Filter for Upper {
+		#[register]
+		fn on_message(&mut self, _entry: &FilterEntry, message: Message) -> Vec<u8> {
+			if message.as_bytes().starts_with(b"panic") {
+				panic!("invalid message");
+			}
+			let mut content = message.as_bytes().to_ascii_uppercase();
+			content.extend_from_slice(b".end\r\n");
+			content
+		}
+	}
+
+	fn run(max_size: usize, input: &str) -> Vec<String> {
+		run_with(
+			FilterRunner::new().max_message_size(max_size),
+			&mut Upper {},
+			input,
+		)
+	}
+
+	const MESSAGE: &str = "filter|0.5|1576146008.006099|smtp-in|data-line|s1|t1|Subject: test\n\
+		filter|0.5|1576146008.006099|smtp-in|data-line|s2|t2|other\n\
+		filter|0.5|1576146008.006099|smtp-in|data-line|s1|t1|\n\
+		filter|0.5|1576146008.006099|smtp-in|data-line|s1|t1|..hidden\n\
+		filter|0.5|1576146008.006099|smtp-in|data-line|s1|t1|.\n";
+
+	#[test]
+	fn test_on_message() {
+		let lines = run(1024, MESSAGE);
+		assert_eq!(
+			lines,
+			vec![
+				"register|filter|smtp-in|data-line",
+				"register|report|smtp-in|link-disconnect",
+				"register|report|smtp-in|timeout",
+				"register|ready",
+				"filter-dataline|s1|t1|SUBJECT: TEST",
+				"filter-dataline|s1|t1|",
+				"filter-dataline|s1|t1|..HIDDEN",
+				"filter-dataline|s1|t1|..end",
+				"filter-dataline|s1|t1|.",
+			]
+		);
+	}
+
+	#[test]
+	fn test_max_message_size() {
+		let lines = run(16, MESSAGE);
+		assert_eq!(
+			&lines[4..],
+			&[
+				"filter-dataline|s1|t1|Subject: test",
+				"filter-dataline|s1|t1|",
+				"filter-dataline|s1|t1|..hidden",
+				"filter-dataline|s1|t1|.",
+			]
+		);
+	}
+
+	#[test]
+	fn test_session_end() {
+		let input = "filter|0.5|1576146008.006099|smtp-in|data-line|s1|t1|lost\n\
+			filter|0.5|1576146008.006099|smtp-in|data-line|s2|t2|lost\n\
+			report|0.5|1576146008.006099|smtp-in|link-disconnect|s1\n\
+			report|0.5|1576146008.006099|smtp-in|timeout|s2\n\
+			filter|0.5|1576146008.006099|smtp-in|data-line|s1|t1|kept\n\
+			filter|0.5|1576146008.006099|smtp-in|data-line|s1|t1|.\n\
+			filter|0.5|1576146008.006099|smtp-in|data-line|s2|t2|.\n";
+		let lines = run(1024, input);
+		assert_eq!(
+			&lines[4..],
+			&[
+				"filter-dataline|s1|t1|KEPT",
+				"filter-dataline|s1|t1|..end",
+				"filter-dataline|s1|t1|.",
+				"filter-dataline|s2|t2|..end",
+				"filter-dataline|s2|t2|.",
+			]
+		);
+	}
+
+	#[test]
+	fn test_message_panic() {
+		let input = "filter|0.5|1576146008.006099|smtp-in|data-line|s1|t1|panic\n\
+			filter|0.5|1576146008.006099|smtp-in|data-line|s1|t1|..hidden\n\
+			filter|0.5|1576146008.006099|smtp-in|data-line|s1|t1|.\n\
+			filter|0.5|1576146008.006099|smtp-in|data-line|s1|t2|ok\n\
+			filter|0.5|1576146008.006099|smtp-in|data-line|s1|t2|.\n";
+		let lines = run(1024, input);
+		assert_eq!(
+			&lines[4..],
+			&[
+				"filter-dataline|s1|t1|panic",
+				"filter-dataline|s1|t1|..hidden",
+				"filter-dataline|s1|t1|.",
+				"filter-dataline|s1|t2|OK",
+				"filter-dataline|s1|t2|..end",
+				"filter-dataline|s1|t2|.",
+			]
+		);
+	}
+
+	struct PanicLine {}
+
+	impl Filter for PanicLine {
+		#[register]
+		fn on_filter_data_line(&mut self, entry: &FilterEntry, data_line: &[u8]) {
+			if data_line == b"panic" {
+				panic!("invalid data-line");
+			}
+			return_data_line(entry, data_line);
+		}
+	}
+
+	#[test]
+	fn test_data_line_panic() {
+		let input = "filter|0.5|1576146008.006099|smtp-in|data-line|s1|t1|first\n\
+			filter|0.5|1576146008.006099|smtp-in|data-line|s1|t1|panic\n\
+			filter|0.5|1576146008.006099|smtp-in|data-line|s1|t1|unmodified\n\
+			filter|0.5|1576146008.006099|smtp-in|data-line|s1|t1|.\n\
+			filter|0.5|1576146008.006099|smtp-in|data-line|s1|t2|ok\n\
+			filter|0.5|1576146008.006099|smtp-in|data-line|s1|t2|.\n";
+		let lines = run_lines(&mut PanicLine {}, input);
+		assert_eq!(
+			&lines[2..],
+			&[
+				"filter-dataline|s1|t1|first",
+				"filter-dataline|s1|t1|panic",
+				"filter-dataline|s1|t1|unmodified",
+				"filter-dataline|s1|t1|.",
+				"filter-dataline|s1|t2|ok",
+				"filter-dataline|s1|t2|.",
+			]
+		);
+	}
+
+	#[cfg(feature = "async")]
+	struct AsyncPanic {}
+
+	#[cfg(feature = "async")]
+	#[crate::async_trait]
+	impl crate::AsyncFilter for AsyncPanic {
+		#[register]
+		async fn on_message(&self, _entry: &FilterEntry, message: Message) -> Vec<u8> {
+			if message.as_bytes().starts_with(b"panic") {
+				panic!("invalid message");
+			}
+			message.into_bytes()
+		}
+	}
+
+	#[cfg(feature = "async")]
+	#[test]
+	fn test_async_message_panic() {
+		let input = "filter|0.5|1576146008.006099|smtp-in|data-line|s1|t1|panic\n\
+			filter|0.5|1576146008.006099|smtp-in|data-line|s1|t1|.\n";
+		let lines = crate::runner::tests::run_async_lines(AsyncPanic {}, input);
+		assert_eq!(
+			&lines[4..],
+			&["filter-dataline|s1|t1|panic", "filter-dataline|s1|t1|."]
+		);
+	}
+
+	#[test]
+	fn test_from_lines() {
+		let lines = vec![
+			b"Subject: test".to_vec(),
+			b"".to_vec(),
+			b".hidden".to_vec(),
+			b"body".to_vec(),
+		];
+		let message = Message::from_lines(&lines);
+		assert_eq!(
+			message.as_bytes(),
+			b"Subject: test\r\n\r\n.hidden\r\nbody\r\n"
+		);
+	}
+
+	#[test]
+	fn test_empty() {
+		let message = Message::from_lines(&[]);
+		assert!(message.is_empty());
+	}
+}
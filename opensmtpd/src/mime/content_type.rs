@@ -0,0 +1,281 @@
+use super::encoding::{decode_base64, decode_quoted_printable};
+use std::fmt;
+
+/// Value of a `Content-Type` header.
+///
+/// The type, subtype and parameter names are lowercase.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ContentType {
+	pub mime_type: String,
+	pub subtype: String,
+	pub parameters: Vec<(String, String)>,
+}
+
+impl Default for ContentType {
+	fn default() -> Self {
+		ContentType {
+			mime_type: String::from("text"),
+			subtype: String::from("plain"),
+			parameters: vec![(String::from("charset"), String::from("us-ascii"))],
+		}
+	}
+}
+
+impl fmt::Display for ContentType {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}/{}", self.mime_type, self.subtype)?;
+		for (name, value) in self.parameters.iter() {
+			if value.chars().all(is_token_char) && !value.is_empty() {
+				write!(f, "; {}={}", name, value)?;
+			} else {
+				let value = value.replace('\\', "\\\\").replace('"', "\\\"");
+				write!(f, "; {}=\"{}\"", name, value)?;
+			}
+		}
+		Ok(())
+	}
+}
+
+impl ContentType {
+	/// Parses a header value, falling back to `text/plain` if the
+	/// type is invalid.
+	pub fn parse(value: &str) -> Self {
+		let (value, parameters) = parse_parameters(value);
+		let mut parts = value.splitn(2, '/');
+		match (parts.next(), parts.next()) {
+			(Some(t), Some(s)) if !t.trim().is_empty() && !s.trim().is_empty() => ContentType {
+				mime_type: t.trim().to_ascii_lowercase(),
+				subtype: s.trim().to_ascii_lowercase(),
+				parameters,
+			},
+			_ => ContentType::default(),
+		}
+	}
+
+	/// Returns the type and subtype, such as `text/plain`.
+	pub fn essence(&self) -> String {
+		format!("{}/{}", self.mime_type, self.subtype)
+	}
+
+	pub fn is_multipart(&self) -> bool {
+		self.mime_type == "multipart"
+	}
+
+	pub fn parameter(&self, name: &str) -> Option<&str> {
+		self.parameters
+			.iter()
+			.find(|(n, _)| n.eq_ignore_ascii_case(name))
+			.map(|(_, v)| v.as_str())
+	}
+
+	pub fn set_parameter(&mut self, name: &str, value: &str) {
+		let name = name.to_ascii_lowercase();
+		match self.parameters.iter_mut().find(|(n, _)| *n == name) {
+			Some((_, v)) => *v = value.to_string(),
+			None => self.parameters.push((name, value.to_string())),
+		}
+	}
+}
+
+fn is_token_char(c: char) -> bool {
+	c.is_ascii_graphic() && !"()<>@,;:\\\"/[]?=".contains(c)
+}
+
+/// Splits a structured header value into its main value and its
+/// parameters. Parameters using the RFC 2231 syntax are decoded.
+pub(crate) fn parse_parameters(input: &str) -> (String, Vec<(String, String)>) {
+	let mut segments = split_unquoted(input, ';').into_iter();
+	let value = segments.next().unwrap_or_default().trim().to_string();
+	let mut parameters: Vec<(String, String)> = Vec::new();
+	let mut sections: Vec<(String, usize, String)> = Vec::new();
+	for segment in segments {
+		let mut parts = segment.splitn(2, '=');
+		let name = parts.next().unwrap_or_default().trim().to_ascii_lowercase();
+		let value = unquote(parts.next().unwrap_or_default().trim());
+		if name.is_empty() {
+			continue;
+		}
+		let (name, extended) = match name.strip_suffix('*') {
+			Some(n) => (n.to_string(), true),
+			None => (name, false),
+		};
+		let (name, section) = match name.split_once('*') {
+			Some((n, i)) => match i.parse::<usize>() {
+				Ok(i) => (n.to_string(), Some(i)),
+				Err(_) => (name, None),
+			},
+			None => (name, None),
+		};
+		let value = match extended {
+			true => decode_extended_value(&value, section.unwrap_or(0) > 0),
+			false => value,
+		};
+		match section {
+			Some(i) => sections.push((name, i, value)),
+			None => parameters.push((name, value)),
+		}
+	}
+	sections.sort_by_key(|(_, i, _)| *i);
+	for (name, _, value) in sections {
+		match parameters.iter_mut().find(|(n, _)| *n == name) {
+			Some((_, v)) => v.push_str(&value),
+			None => parameters.push((name, value)),
+		}
+	}
+	(value, parameters)
+}
+
+fn split_unquoted(input: &str, separator: char) -> Vec<String> {
+	let mut ret = vec![String::new()];
+	let mut in_quotes = false;
+	let mut escaped = false;
+	for c in input.chars() {
+		let current = ret.last_mut().unwrap();
+		if escaped {
+			escaped = false;
+		} else if c == '\\' && in_quotes {
+			escaped = true;
+		} else if c == '"' {
+			in_quotes = !in_quotes;
+		} else if c == separator && !in_quotes {
+			ret.push(String::new());
+			continue;
+		}
+		current.push(c);
+	}
+	ret
+}
+
+fn unquote(value: &str) -> String {
+	match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+		Some(v) => {
+			let mut ret = String::with_capacity(v.len());
+			let mut escaped = false;
+			for c in v.chars() {
+				if c == '\\' && !escaped {
+					escaped = true;
+					continue;
+				}
+				escaped = false;
+				ret.push(c);
+			}
+			ret
+		}
+		None => value.to_string(),
+	}
+}
+
+// RFC 2231: charset'language'percent-encoded-value, the charset and
+// language being only present on the first section.
+fn decode_extended_value(value: &str, is_continuation: bool) -> String {
+	let value = match is_continuation {
+		true => value,
+		false => value.splitn(3, '\'').last().unwrap_or_default(),
+	};
+	let bytes = value.as_bytes();
+	let mut ret = Vec::with_capacity(bytes.len());
+	let mut i = 0;
+	while i < bytes.len() {
+		let hex = bytes
+			.get(i + 1..i + 3)
+			.and_then(|h| std::str::from_utf8(h).ok())
+			.and_then(|h| u8::from_str_radix(h, 16).ok());
+		match (bytes[i], hex) {
+			(b'%', Some(c)) => {
+				ret.push(c);
+				i += 3;
+			}
+			(c, _) => {
+				ret.push(c);
+				i += 1;
+			}
+		}
+	}
+	String::from_utf8_lossy(&ret).into_owned()
+}
+
+/// Decodes the RFC 2047 encoded-words of an unstructured value. The
+/// charset is ignored and the content is assumed to be UTF-8.
+pub(crate) fn decode_encoded_words(input: &str) -> String {
+	let mut ret = String::with_capacity(input.len());
+	let mut rest = input;
+	let mut last_was_word = false;
+	while let Some(start) = rest.find("=?") {
+		let word = rest[start + 2..].splitn(4, '?').collect::<Vec<&str>>();
+		let decoded = match word.as_slice() {
+			[_, encoding, text, tail] if tail.starts_with('=') => {
+				let text = text.as_bytes();
+				let decoded = match encoding.to_ascii_lowercase().as_str() {
+					"b" => Some(decode_base64(text)),
+					"q" => {
+						let text: Vec<u8> = text
+							.iter()
+							.map(|&c| if c == b'_' { b' ' } else { c })
+							.collect();
+						Some(decode_quoted_printable(&text))
+					}
+					_ => None,
+				};
+				decoded.map(|d| (d, rest.len() - tail.len() + 1))
+			}
+			_ => None,
+		};
+		match decoded {
+			Some((decoded, end)) => {
+				let before = &rest[..start];
+				if !(last_was_word && before.trim().is_empty()) {
+					ret.push_str(before);
+				}
+				ret.push_str(&String::from_utf8_lossy(&decoded));
+				rest = &rest[end..];
+				last_was_word = true;
+			}
+			None => {
+				ret.push_str(&rest[..start + 2]);
+				rest = &rest[start + 2..];
+				last_was_word = false;
+			}
+		}
+	}
+	ret.push_str(rest);
+	ret
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_content_type() {
+		let ct = ContentType::parse("Multipart/Mixed; boundary=\"a;b \\\"c\\\"\"; charset=UTF-8");
+		assert_eq!(ct.essence(), "multipart/mixed");
+		assert!(ct.is_multipart());
+		assert_eq!(ct.parameter("BOUNDARY"), Some("a;b \"c\""));
+		assert_eq!(ct.parameter("charset"), Some("UTF-8"));
+		assert_eq!(
+			ct.to_string(),
+			"multipart/mixed; boundary=\"a;b \\\"c\\\"\"; charset=UTF-8"
+		);
+		assert_eq!(ContentType::parse("invalid"), ContentType::default());
+	}
+
+	#[test]
+	fn test_rfc2231() {
+		let (value, params) =
+			parse_parameters("attachment; filename*0*=utf-8''caf%C3%A9; filename*1=\".txt\"");
+		assert_eq!(value, "attachment");
+		assert_eq!(
+			params,
+			vec![("filename".to_string(), "café.txt".to_string())]
+		);
+	}
+
+	#[test]
+	fn test_encoded_words() {
+		assert_eq!(
+			decode_encoded_words("=?UTF-8?B?Y2Fmw6k=?= =?utf-8?Q?_cr=C3=A8me?=.txt"),
+			"café crème.txt"
+		);
+		assert_eq!(decode_encoded_words("plain =? text"), "plain =? text");
+	}
+}
@@ -0,0 +1,248 @@
+use crate::Error;
+use std::fmt;
+use std::str::FromStr;
+
+/// Maximum length of a line, excluding the CRLF (RFC 5321).
+pub(crate) const MAX_LINE_LENGTH: usize = 998;
+const ENCODED_LINE_LENGTH: usize = 76;
+const BASE64_CHARS: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub enum TransferEncoding {
+	#[default]
+	SevenBit,
+	EightBit,
+	Binary,
+	QuotedPrintable,
+	Base64,
+	Other(String),
+}
+
+impl fmt::Display for TransferEncoding {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			TransferEncoding::SevenBit => write!(f, "7bit"),
+			TransferEncoding::EightBit => write!(f, "8bit"),
+			TransferEncoding::Binary => write!(f, "binary"),
+			TransferEncoding::QuotedPrintable => write!(f, "quoted-printable"),
+			TransferEncoding::Base64 => write!(f, "base64"),
+			TransferEncoding::Other(s) => write!(f, "{}", s),
+		}
+	}
+}
+
+impl FromStr for TransferEncoding {
+	type Err = Error;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let s = s.trim().to_ascii_lowercase();
+		match s.as_str() {
+			"7bit" => Ok(TransferEncoding::SevenBit),
+			"8bit" => Ok(TransferEncoding::EightBit),
+			"binary" => Ok(TransferEncoding::Binary),
+			"quoted-printable" => Ok(TransferEncoding::QuotedPrintable),
+			"base64" => Ok(TransferEncoding::Base64),
+			"" => Err(Error::invalid_value("transfer encoding", &s)),
+			_ => Ok(TransferEncoding::Other(s)),
+		}
+	}
+}
+
+impl TransferEncoding {
+	pub(crate) fn decode(&self, input: &[u8]) -> Vec<u8> {
+		match self {
+			TransferEncoding::QuotedPrintable => decode_quoted_printable(input),
+			TransferEncoding::Base64 => decode_base64(input),
+			_ => input.to_vec(),
+		}
+	}
+
+	pub(crate) fn encode(&self, input: &[u8]) -> Vec<u8> {
+		match self {
+			TransferEncoding::QuotedPrintable => encode_quoted_printable(input),
+			TransferEncoding::Base64 => encode_base64(input),
+			_ => input.to_vec(),
+		}
+	}
+}
+
+pub(crate) fn has_long_lines(input: &[u8]) -> bool {
+	input
+		.split(|&c| c == b'\n')
+		.any(|l| l.strip_suffix(b"\r").unwrap_or(l).len() > MAX_LINE_LENGTH)
+}
+
+fn base64_value(c: u8) -> Option<u8> {
+	match c {
+		b'A'..=b'Z' => Some(c - b'A'),
+		b'a'..=b'z' => Some(c - b'a' + 26),
+		b'0'..=b'9' => Some(c - b'0' + 52),
+		b'+' => Some(62),
+		b'/' => Some(63),
+		_ => None,
+	}
+}
+
+/// Decodes base64, ignoring any character outside of the alphabet.
+pub(crate) fn decode_base64(input: &[u8]) -> Vec<u8> {
+	let mut ret = Vec::with_capacity(input.len() * 3 / 4);
+	let mut buffer: u32 = 0;
+	let mut nb_bits = 0;
+	for v in input.iter().filter_map(|&c| base64_value(c)) {
+		buffer = (buffer << 6) | u32::from(v);
+		nb_bits += 6;
+		if nb_bits >= 8 {
+			nb_bits -= 8;
+			ret.push((buffer >> nb_bits) as u8);
+		}
+	}
+	ret
+}
+
+fn encode_base64_line(input: &[u8], ret: &mut Vec<u8>) {
+	for chunk in input.chunks(3) {
+		let b = [
+			chunk[0],
+			chunk.get(1).copied().unwrap_or(0),
+			chunk.get(2).copied().unwrap_or(0),
+		];
+		let n = (u32::from(b[0]) << 16) | (u32::from(b[1]) << 8) | u32::from(b[2]);
+		for i in 0..4 {
+			if i <= chunk.len() {
+				ret.push(BASE64_CHARS[((n >> (18 - 6 * i)) & 0x3f) as usize]);
+			} else {
+				ret.push(b'=');
+			}
+		}
+	}
+}
+
+/// Encodes base64 in CRLF-terminated lines of 76 characters.
+pub(crate) fn encode_base64(input: &[u8]) -> Vec<u8> {
+	let mut ret = Vec::with_capacity(input.len() * 4 / 3 + input.len() / 27 + 4);
+	for line in input.chunks(ENCODED_LINE_LENGTH / 4 * 3) {
+		encode_base64_line(line, &mut ret);
+		ret.extend_from_slice(b"\r\n");
+	}
+	ret
+}
+
+fn hex_value(c: u8) -> Option<u8> {
+	(c as char).to_digit(16).map(|v| v as u8)
+}
+
+pub(crate) fn decode_quoted_printable(input: &[u8]) -> Vec<u8> {
+	let mut ret = Vec::with_capacity(input.len());
+	let mut i = 0;
+	while i < input.len() {
+		let rest = &input[i + 1..];
+		if input[i] != b'=' {
+			ret.push(input[i]);
+			i += 1;
+		} else if rest.starts_with(b"\r\n") {
+			i += 3;
+		} else if rest.starts_with(b"\n") {
+			i += 2;
+		} else if let (Some(h), Some(l)) = (
+			rest.first().and_then(|&c| hex_value(c)),
+			rest.get(1).and_then(|&c| hex_value(c)),
+		) {
+			ret.push(h << 4 | l);
+			i += 3;
+		} else {
+			ret.push(b'=');
+			i += 1;
+		}
+	}
+	ret
+}
+
+/// Encodes quoted-printable, line breaks being kept as hard breaks.
+pub(crate) fn encode_quoted_printable(input: &[u8]) -> Vec<u8> {
+	let mut ret = Vec::with_capacity(input.len() * 3 / 2);
+	let mut lines = input.split(|&c| c == b'\n').peekable();
+	while let Some(line) = lines.next() {
+		let is_last = lines.peek().is_none();
+		let line = line.strip_suffix(b"\r").unwrap_or(line);
+		if is_last && line.is_empty() {
+			break;
+		}
+		let mut len = 0;
+		for (i, &c) in line.iter().enumerate() {
+			let is_line_end = i + 1 == line.len();
+			let literal = match c {
+				b' ' | b'\t' => !is_line_end,
+				b'=' => false,
+				33..=126 => true,
+				_ => false,
+			};
+			let size = if literal { 1 } else { 3 };
+			if len + size > ENCODED_LINE_LENGTH - 1 {
+				ret.extend_from_slice(b"=\r\n");
+				len = 0;
+			}
+			if literal {
+				ret.push(c);
+			} else {
+				ret.extend_from_slice(format!("={:02X}", c).as_bytes());
+			}
+			len += size;
+		}
+		if !is_last {
+			ret.extend_from_slice(b"\r\n");
+		}
+	}
+	ret
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_base64() {
+		assert_eq!(encode_base64(b""), b"");
+		assert_eq!(encode_base64(b"f"), b"Zg==\r\n");
+		assert_eq!(encode_base64(b"fo"), b"Zm8=\r\n");
+		assert_eq!(encode_base64(b"foobar"), b"Zm9vYmFy\r\n");
+		assert_eq!(decode_base64(b"Zm9v\r\nYmFy\r\n"), b"foobar");
+		assert_eq!(decode_base64(b"Zm8="), b"fo");
+		let data: Vec<u8> = (0..=255).collect();
+		let encoded = encode_base64(&data);
+		assert!(encoded
+			.split(|&c| c == b'\n')
+			.all(|l| l.len() <= ENCODED_LINE_LENGTH + 1));
+		assert_eq!(decode_base64(&encoded), data);
+	}
+
+	#[test]
+	fn test_quoted_printable() {
+		assert_eq!(
+			decode_quoted_printable(b"caf=C3=A9 =3D=\r\nsoft\r\nhard"),
+			"café =soft\r\nhard".as_bytes()
+		);
+		assert_eq!(
+			encode_quoted_printable("café =\r\nend \r\n".as_bytes()),
+			b"caf=C3=A9 =3D\r\nend=20\r\n"
+		);
+		let long = "a".repeat(200);
+		let encoded = encode_quoted_printable(long.as_bytes());
+		assert!(encoded
+			.split(|&c| c == b'\n')
+			.all(|l| l.len() <= ENCODED_LINE_LENGTH + 1));
+		assert_eq!(decode_quoted_printable(&encoded), long.as_bytes());
+	}
+
+	#[test]
+	fn test_transfer_encoding() {
+		assert_eq!(
+			"Base64".parse::<TransferEncoding>().unwrap(),
+			TransferEncoding::Base64
+		);
+		assert_eq!(
+			"x-uuencode".parse::<TransferEncoding>().unwrap(),
+			TransferEncoding::Other("x-uuencode".to_string())
+		);
+		assert!("".parse::<TransferEncoding>().is_err());
+	}
+}
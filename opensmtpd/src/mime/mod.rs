@@ -0,0 +1,437 @@
+pub(crate) mod content_type;
+pub(crate) mod encoding;
+
+use crate::{ContentType, Headers, TransferEncoding};
+use content_type::{decode_encoded_words, parse_parameters};
+use encoding::has_long_lines;
+use std::borrow::Cow;
+
+// Parts nested deeper are kept as opaque content.
+const MAX_DEPTH: usize = 32;
+
+#[derive(Clone, Debug)]
+enum Body {
+	Raw(Vec<u8>),
+	Decoded(Vec<u8>),
+	Multipart {
+		preamble: Option<Vec<u8>>,
+		parts: Vec<MimePart>,
+		epilogue: Vec<u8>,
+	},
+}
+
+/// Part of a MIME message, the root part being the message itself.
+///
+/// The content type and the transfer encoding are read from the
+/// current headers. The content of a part which has not been modified
+/// is serialized back as it was received.
+///
+/// ``` rust
+/// use opensmtpd::{Filter, FilterEntry, Message, MimePart};
+/// use opensmtpd_derive::register;
+///
+/// struct NoExe {}
+///
+/// impl Filter for NoExe {
+///		#[register]
+///		fn on_message(&mut self, _entry: &FilterEntry, message: Message) -> Vec<u8> {
+///			let mut root = MimePart::parse(message.as_bytes());
+///			root.retain_parts(|part| match part.filename() {
+///				Some(name) => !name.to_lowercase().ends_with(".exe"),
+///				None => true,
+///			});
+///			root.to_bytes()
+///		}
+/// }
+/// ```
+#[derive(Clone, Debug)]
+pub struct MimePart {
+	headers: Headers,
+	body: Body,
+}
+
+impl MimePart {
+	/// Creates a part, choosing a transfer encoding suitable for its
+	/// content.
+	pub fn new(content_type: ContentType, content: Vec<u8>) -> Self {
+		let encoding = if content_type.mime_type != "text" {
+			TransferEncoding::Base64
+		} else if content.is_ascii() && !has_long_lines(&content) {
+			TransferEncoding::SevenBit
+		} else {
+			TransferEncoding::QuotedPrintable
+		};
+		let mut headers = Headers::new();
+		headers.add("Content-Type", &content_type.to_string());
+		headers.add("Content-Transfer-Encoding", &encoding.to_string());
+		MimePart {
+			headers,
+			body: Body::Decoded(content),
+		}
+	}
+
+	pub fn parse(content: &[u8]) -> Self {
+		MimePart::parse_depth(content, 0)
+	}
+
+	fn parse_depth(content: &[u8], depth: usize) -> Self {
+		let (headers, body) = Headers::parse(content);
+		let mut part = MimePart {
+			headers,
+			body: Body::Raw(Vec::new()),
+		};
+		let content_type = part.content_type();
+		let multipart = match content_type.parameter("boundary") {
+			Some(b) if content_type.is_multipart() && depth < MAX_DEPTH => split_multipart(body, b),
+			_ => None,
+		};
+		part.body = match multipart {
+			Some((preamble, parts, epilogue)) => Body::Multipart {
+				preamble: preamble.map(|p| p.to_vec()),
+				parts: parts
+					.into_iter()
+					.map(|p| MimePart::parse_depth(p, depth + 1))
+					.collect(),
+				epilogue: epilogue.to_vec(),
+			},
+			None => Body::Raw(body.to_vec()),
+		};
+		part
+	}
+
+	pub fn headers(&self) -> &Headers {
+		&self.headers
+	}
+
+	pub fn headers_mut(&mut self) -> &mut Headers {
+		&mut self.headers
+	}
+
+	/// Returns the content type, `text/plain` being the default.
+	pub fn content_type(&self) -> ContentType {
+		match self.headers.get("Content-Type") {
+			Some(h) => ContentType::parse(&h.value()),
+			None => ContentType::default(),
+		}
+	}
+
+	/// Returns the transfer encoding, `7bit` being the default.
+	pub fn transfer_encoding(&self) -> TransferEncoding {
+		self.headers
+			.get("Content-Transfer-Encoding")
+			.and_then(|h| h.value().parse().ok())
+			.unwrap_or_default()
+	}
+
+	/// Returns the disposition type, such as `inline` or `attachment`.
+	pub fn disposition(&self) -> Option<String> {
+		self.headers
+			.get("Content-Disposition")
+			.map(|h| parse_parameters(&h.value()).0.to_ascii_lowercase())
+	}
+
+	pub fn is_attachment(&self) -> bool {
+		self.disposition().as_deref() == Some("attachment")
+	}
+
+	/// Returns the decoded file name, taken from the
+	/// `Content-Disposition` header or from the `name` parameter of the
+	/// content type.
+	pub fn filename(&self) -> Option<String> {
+		let filename = self
+			.headers
+			.get("Content-Disposition")
+			.and_then(|h| {
+				let (_, params) = parse_parameters(&h.value());
+				params.into_iter().find(|(n, _)| n == "filename")
+			})
+			.map(|(_, v)| v);
+		filename
+			.or_else(|| self.content_type().parameter("name").map(|n| n.to_string()))
+			.map(|f| decode_encoded_words(&f))
+	}
+
+	pub fn is_multipart(&self) -> bool {
+		matches!(self.body, Body::Multipart { .. })
+	}
+
+	/// Returns the sub-parts of a multipart, which is empty for other
+	/// parts.
+	pub fn parts(&self) -> &[MimePart] {
+		match &self.body {
+			Body::Multipart { parts, .. } => parts,
+			_ => &[],
+		}
+	}
+
+	pub fn parts_mut(&mut self) -> Option<&mut Vec<MimePart>> {
+		match &mut self.body {
+			Body::Multipart { parts, .. } => Some(parts),
+			_ => None,
+		}
+	}
+
+	/// Iterates over this part and all its sub-parts, depth-first.
+	pub fn iter(&self) -> impl Iterator<Item = &MimePart> {
+		let mut stack = vec![self];
+		std::iter::from_fn(move || {
+			let part = stack.pop()?;
+			stack.extend(part.parts().iter().rev());
+			Some(part)
+		})
+	}
+
+	/// Removes the sub-parts, at any depth, for which the closure
+	/// returns `false`.
+	pub fn retain_parts<F>(&mut self, mut f: F)
+	where
+		F: FnMut(&MimePart) -> bool,
+	{
+		self.retain_parts_ref(&mut f);
+	}
+
+	fn retain_parts_ref<F>(&mut self, f: &mut F)
+	where
+		F: FnMut(&MimePart) -> bool,
+	{
+		if let Some(parts) = self.parts_mut() {
+			parts.retain(|p| f(p));
+			for part in parts.iter_mut() {
+				part.retain_parts_ref(f);
+			}
+		}
+	}
+
+	/// Returns the decoded content of a part which is not a multipart.
+	pub fn body(&self) -> Option<Cow<'_, [u8]>> {
+		match &self.body {
+			Body::Raw(b) => Some(Cow::Owned(self.transfer_encoding().decode(b))),
+			Body::Decoded(b) => Some(Cow::Borrowed(b)),
+			Body::Multipart { .. } => None,
+		}
+	}
+
+	/// Replaces the content of the part. The content will be encoded
+	/// using the part's transfer encoding.
+	pub fn set_body(&mut self, content: Vec<u8>) {
+		self.body = Body::Decoded(content);
+	}
+
+	/// Serializes the part. Modified content is encoded so that no line
+	/// exceeds the SMTP limit of 998 characters, switching to
+	/// quoted-printable if needed.
+	pub fn to_bytes(&self) -> Vec<u8> {
+		let mut headers = Cow::Borrowed(&self.headers);
+		let body = match &self.body {
+			Body::Raw(b) => Cow::Borrowed(b),
+			Body::Decoded(b) => {
+				let mut encoding = self.transfer_encoding();
+				let needs_encoding = match encoding {
+					TransferEncoding::SevenBit => !b.is_ascii() || has_long_lines(b),
+					// Unknown encodings are written as-is, like binary.
+					TransferEncoding::EightBit
+					| TransferEncoding::Binary
+					| TransferEncoding::Other(_) => has_long_lines(b),
+					_ => false,
+				};
+				if needs_encoding {
+					encoding = TransferEncoding::QuotedPrintable;
+					headers
+						.to_mut()
+						.set("Content-Transfer-Encoding", &encoding.to_string());
+				}
+				Cow::Owned(encoding.encode(b))
+			}
+			Body::Multipart {
+				preamble,
+				parts,
+				epilogue,
+			} => {
+				let boundary = self
+					.content_type()
+					.parameter("boundary")
+					.unwrap_or_default()
+					.to_string();
+				let delimiter = format!("--{}", boundary).into_bytes();
+				let mut ret = Vec::new();
+				if let Some(preamble) = preamble {
+					ret.extend_from_slice(preamble);
+					ret.extend_from_slice(b"\r\n");
+				}
+				for part in parts {
+					ret.extend_from_slice(&delimiter);
+					ret.extend_from_slice(b"\r\n");
+					ret.extend_from_slice(&part.to_bytes());
+					ret.extend_from_slice(b"\r\n");
+				}
+				ret.extend_from_slice(&delimiter);
+				ret.extend_from_slice(b"--\r\n");
+				ret.extend_from_slice(epilogue);
+				Cow::Owned(ret)
+			}
+		};
+		let mut ret = headers.to_bytes();
+		ret.extend_from_slice(b"\r\n");
+		ret.extend_from_slice(&body);
+		ret
+	}
+}
+
+type Multipart<'a> = (Option<&'a [u8]>, Vec<&'a [u8]>, &'a [u8]);
+
+// The line break preceding a delimiter belongs to the delimiter.
+fn split_multipart<'a>(body: &'a [u8], boundary: &str) -> Option<Multipart<'a>> {
+	let delimiter = format!("--{}", boundary).into_bytes();
+	let mut preamble = None;
+	let mut parts = Vec::new();
+	let mut part_start = None;
+	let mut offset = 0;
+	while offset < body.len() {
+		let end = match body[offset..].iter().position(|&c| c == b'\n') {
+			Some(pos) => offset + pos + 1,
+			None => body.len(),
+		};
+		let line = trim_end(&body[offset..end]);
+		if let Some(rest) = line.strip_prefix(delimiter.as_slice()) {
+			if rest.is_empty() || rest == b"--" {
+				let before = strip_line_break(&body[..offset]);
+				match part_start {
+					Some(start) => parts.push(strip_line_break(&body[start..offset])),
+					None if offset > 0 => preamble = Some(before),
+					None => {}
+				}
+				if rest == b"--" {
+					return Some((preamble, parts, &body[end..]));
+				}
+				part_start = Some(end);
+			}
+		}
+		offset = end;
+	}
+	let start = part_start?;
+	parts.push(&body[start..]);
+	Some((preamble, parts, &body[body.len()..]))
+}
+
+/// Removes the trailing whitespaces, including the line break.
+fn trim_end(mut input: &[u8]) -> &[u8] {
+	while let [rest @ .., last] = input {
+		if !last.is_ascii_whitespace() {
+			break;
+		}
+		input = rest;
+	}
+	input
+}
+
+fn strip_line_break(input: &[u8]) -> &[u8] {
+	let input = input.strip_suffix(b"\n").unwrap_or(input);
+	input.strip_suffix(b"\r").unwrap_or(input)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::MimePart;
+	use crate::{ContentType, TransferEncoding};
+
+	const MESSAGE: &[u8] = b"From: a@example.org\r\n\
+		Content-Type: multipart/mixed; boundary=\"outer\"\r\n\
+		\r\n\
+		This is a MIME message.\r\n\
+		--outer\r\n\
+		Content-Type: multipart/alternative; boundary=inner\r\n\
+		\r\n\
+		--inner\r\n\
+		Content-Type: text/plain; charset=utf-8\r\n\
+		Content-Transfer-Encoding: quoted-printable\r\n\
+		\r\n\
+		caf=C3=A9\r\n\
+		--inner\r\n\
+		Content-Type: text/html\r\n\
+		\r\n\
+		<p>cafe</p>\r\n\
+		--inner--\r\n\
+		\r\n\
+		--outer\r\n\
+		Content-Type: application/octet-stream; name=\"=?utf-8?Q?r=C3=A9sum=C3=A9.exe?=\"\r\n\
+		Content-Disposition: attachment\r\n\
+		Content-Transfer-Encoding: base64\r\n\
+		\r\n\
+		TVqQAA==\r\n\
+		--outer--\r\n\
+		epilogue\r\n";
+
+	#[test]
+	fn test_parse() {
+		let root = MimePart::parse(MESSAGE);
+		assert!(root.is_multipart());
+		assert_eq!(root.parts().len(), 2);
+		let types: Vec<String> = root.iter().map(|p| p.content_type().essence()).collect();
+		assert_eq!(
+			types,
+			vec![
+				"multipart/mixed",
+				"multipart/alternative",
+				"text/plain",
+				"text/html",
+				"application/octet-stream",
+			]
+		);
+		let text = &root.parts()[0].parts()[0];
+		assert_eq!(text.transfer_encoding(), TransferEncoding::QuotedPrintable);
+		assert_eq!(text.body().unwrap().as_ref(), "café".as_bytes());
+		let attachment = &root.parts()[1];
+		assert!(attachment.is_attachment());
+		assert_eq!(attachment.filename().as_deref(), Some("résumé.exe"));
+		assert_eq!(attachment.body().unwrap().as_ref(), b"MZ\x90\x00");
+	}
+
+	#[test]
+	fn test_round_trip() {
+		let root = MimePart::parse(MESSAGE);
+		assert_eq!(root.to_bytes(), MESSAGE);
+	}
+
+	#[test]
+	fn test_edit() {
+		let mut root = MimePart::parse(MESSAGE);
+		root.retain_parts(|p| !p.is_attachment());
+		let text = &mut root.parts_mut().unwrap()[0].parts_mut().unwrap()[0];
+		text.set_body(format!("{}\r\n", "é".repeat(600)).into_bytes());
+		let mut ct = ContentType::parse("text/plain");
+		ct.set_parameter("charset", "utf-8");
+		root.parts_mut()
+			.unwrap()
+			.push(MimePart::new(ct, b"disclaimer\r\n".to_vec()));
+
+		let serialized = root.to_bytes();
+		assert!(serialized.split(|&c| c == b'\n').all(|l| l.len() <= 78));
+		let root = MimePart::parse(&serialized);
+		assert_eq!(root.parts().len(), 2);
+		let text = &root.parts()[0].parts()[0];
+		assert_eq!(
+			text.body().unwrap().as_ref(),
+			format!("{}\r\n", "é".repeat(600)).as_bytes()
+		);
+		let disclaimer = &root.parts()[1];
+		assert_eq!(disclaimer.transfer_encoding(), TransferEncoding::SevenBit);
+		assert_eq!(disclaimer.body().unwrap().as_ref(), b"disclaimer\r\n");
+	}
+
+	#[test]
+	fn test_long_lines() {
+		let mut part = MimePart::parse(b"Content-Type: text/plain\r\n\r\nshort\r\n");
+		part.set_body("a".repeat(2000).into_bytes());
+		let serialized = part.to_bytes();
+		assert!(serialized.split(|&c| c == b'\n').all(|l| l.len() <= 78));
+		let part = MimePart::parse(&serialized);
+		assert_eq!(part.transfer_encoding(), TransferEncoding::QuotedPrintable);
+		assert_eq!(part.body().unwrap().as_ref(), "a".repeat(2000).as_bytes());
+
+		let mut part = MimePart::parse(b"Content-Transfer-Encoding: x-custom\r\n\r\nshort\r\n");
+		part.set_body("a".repeat(2000).into_bytes());
+		let part = MimePart::parse(&part.to_bytes());
+		assert_eq!(part.transfer_encoding(), TransferEncoding::QuotedPrintable);
+		assert_eq!(part.body().unwrap().as_ref(), "a".repeat(2000).as_bytes());
+	}
+}
@@ -1,6 +1,8 @@
 use super::{parse_data_structure, parse_delimiter, parse_string_parameter};
+use crate::io::Output;
 use crate::Event;
 use crate::FilterPhase;
+use crate::ProtocolVersion;
 use crate::SubSystem;
 use crate::TimeVal;
 use nom::branch::alt;
@@ -9,21 +11,24 @@ use nom::character::streaming::digit1;
 use nom::combinator::map_res;
 use nom::IResult;
 
+#[derive(Clone, Debug)]
 pub struct ReportEntry {
-	pub version: String,
+	pub version: ProtocolVersion,
 	pub timestamp: TimeVal,
 	pub subsystem: SubSystem,
 	pub event: Event,
 	pub session_id: String,
 }
 
+#[derive(Clone, Debug)]
 pub struct FilterEntry {
-	pub version: String,
+	pub version: ProtocolVersion,
 	pub timestamp: TimeVal,
 	pub subsystem: SubSystem,
 	pub phase: FilterPhase,
 	pub session_id: String,
 	pub token: String,
+	pub(crate) output: Output,
 }
 
 pub(crate) enum EntryOption {
@@ -51,7 +56,7 @@ fn parse_filter_entry_meta(input: &[u8]) -> IResult<&[u8], EntryOption> {
 }
 
 fn parse_report_entry(input: &[u8]) -> IResult<&[u8], ReportEntry> {
-	let (input, version) = parse_string_parameter(input)?;
+	let (input, version) = parse_data_structure::<ProtocolVersion>(input)?;
 	let (input, _) = parse_delimiter(input)?;
 	let (input, timestamp) = parse_timestamp(input)?;
 	let (input, _) = parse_delimiter(input)?;
@@ -71,7 +76,7 @@ fn parse_report_entry(input: &[u8]) -> IResult<&[u8], ReportEntry> {
 }
 
 fn parse_filter_entry(input: &[u8]) -> IResult<&[u8], FilterEntry> {
-	let (input, version) = parse_string_parameter(input)?;
+	let (input, version) = parse_data_structure::<ProtocolVersion>(input)?;
 	let (input, _) = parse_delimiter(input)?;
 	let (input, timestamp) = parse_timestamp(input)?;
 	let (input, _) = parse_delimiter(input)?;
@@ -79,6 +84,8 @@ fn parse_filter_entry(input: &[u8]) -> IResult<&[u8], FilterEntry> {
 	let (input, _) = parse_delimiter(input)?;
 	let (input, phase) = parse_data_structure::<FilterPhase>(input)?;
 	let (input, _) = parse_delimiter(input)?;
+	// The session id always comes first in filter requests: only the
+	// replies used another order before 0.5.
 	let (input, session_id) = parse_string_parameter(input)?;
 	let (input, _) = parse_delimiter(input)?;
 	let (input, token) = parse_string_parameter(input)?;
@@ -89,6 +96,7 @@ fn parse_filter_entry(input: &[u8]) -> IResult<&[u8], FilterEntry> {
 		phase,
 		session_id,
 		token,
+		output: Output::default(),
 	};
 	Ok((input, entry))
 }
@@ -115,12 +123,9 @@ mod tests {
 		let (_, res) = res.unwrap();
 		let res = match res {
 			EntryOption::Report(r) => r,
-			_ => {
-				assert!(false);
-				return;
-			}
+			_ => panic!("not a report entry"),
 		};
-		assert_eq!(res.version, String::from("0.5"));
+		assert_eq!(res.version, ProtocolVersion::V0_5);
 		assert_eq!(
 			res.timestamp,
 			TimeVal {
@@ -132,4 +137,20 @@ mod tests {
 		assert_eq!(res.event, Event::LinkConnect);
 		assert_eq!(res.session_id, String::from("7641df9771b4ed00"));
 	}
+
+	#[test]
+	fn test_filter_ids_order() {
+		let inputs: [&[u8]; 2] = [
+			b"filter|0.4|1576146008.006099|smtp-in|helo|7641df9771b4ed00|1ef1c203cc576e5d|a",
+			b"filter|0.5|1576146008.006099|smtp-in|helo|7641df9771b4ed00|1ef1c203cc576e5d|a",
+		];
+		for input in inputs.iter() {
+			let f = match parse_entry(input).unwrap().1 {
+				EntryOption::Filter(f) => f,
+				_ => panic!("not a filter entry"),
+			};
+			assert_eq!(f.session_id, "7641df9771b4ed00");
+			assert_eq!(f.token, "1ef1c203cc576e5d");
+		}
+	}
 }
@@ -1,28 +1,69 @@
 use super::{
-	parse_data_structure, parse_delimiter, parse_eol, parse_string_parameter, parse_usize,
+	is_body_char, parse_data_structure, parse_delimiter, parse_eol, parse_string_parameter,
+	parse_usize,
 };
 use crate::SubSystem;
-use nom::bytes::streaming::tag;
+use nom::bytes::streaming::{tag, take_while};
+use nom::combinator::verify;
+use nom::multi::many0;
 use nom::IResult;
+use std::collections::HashMap;
 
-#[derive(Debug)]
-pub(crate) struct Handshake {
-	pub(crate) smtpd_version: String,
-	pub(crate) smtp_session_timeout: usize,
-	pub(crate) subsystem: SubSystem,
+const KNOWN_KEYS: &[&str] = &[
+	"smtpd-version",
+	"smtp-session-timeout",
+	"subsystem",
+	"ready",
+];
+
+/// Configuration sent by OpenSMTPD during the handshake.
+#[derive(Clone, Debug)]
+pub struct FilterConfig {
+	pub smtpd_version: String,
+	pub smtp_session_timeout: usize,
+	pub subsystem: SubSystem,
+	/// Configuration keys unknown to this library, which may be sent
+	/// by newer versions of OpenSMTPD.
+	pub extra: HashMap<String, String>,
 }
 
-pub(crate) fn parse_handshake(input: &[u8]) -> IResult<&[u8], Handshake> {
+pub(crate) fn parse_handshake(input: &[u8]) -> IResult<&[u8], FilterConfig> {
+	let mut extra = HashMap::new();
+	let (input, _) = parse_unknown_configs(input, &mut extra)?;
 	let (input, smtpd_version) = parse_smtpd_version(input)?;
+	let (input, _) = parse_unknown_configs(input, &mut extra)?;
 	let (input, smtp_session_timeout) = parse_smtp_session_timeout(input)?;
+	let (input, _) = parse_unknown_configs(input, &mut extra)?;
 	let (input, subsystem) = parse_subsystem(input)?;
+	let (input, _) = parse_unknown_configs(input, &mut extra)?;
 	let (input, _) = parse_ready(input)?;
-	let handshake = Handshake {
+	let config = FilterConfig {
 		smtpd_version,
 		smtp_session_timeout,
 		subsystem,
+		extra,
 	};
-	Ok((input, handshake))
+	Ok((input, config))
+}
+
+fn parse_unknown_configs<'a>(
+	input: &'a [u8],
+	extra: &mut HashMap<String, String>,
+) -> IResult<&'a [u8], ()> {
+	let (input, configs) = many0(parse_unknown_config)(input)?;
+	extra.extend(configs);
+	Ok((input, ()))
+}
+
+fn parse_unknown_config(input: &[u8]) -> IResult<&[u8], (String, String)> {
+	let (input, _) = parse_config_initial(input)?;
+	let (input, key) = verify(parse_string_parameter, |k: &str| !KNOWN_KEYS.contains(&k))(input)?;
+	let (input, _) = parse_delimiter(input)?;
+	let (input, value) = take_while(is_body_char)(input)?;
+	let (input, _) = parse_eol(input)?;
+	let value = String::from_utf8_lossy(value).into_owned();
+	log::debug!("unknown configuration: {}: {}", key, value);
+	Ok((input, (key, value)))
 }
 
 fn parse_smtpd_version(input: &[u8]) -> IResult<&[u8], String> {
@@ -106,6 +147,31 @@ mod tests {
 		assert_eq!(h.subsystem, SubSystem::SmtpIn);
 	}
 
+	#[test]
+	fn test_valid_handshake_smtp_out() {
+		let input = b"config|smtpd-version|6.6.1\nconfig|smtp-session-timeout|300\nconfig|subsystem|smtp-out\nconfig|ready\n";
+		let r = parse_handshake(input);
+		assert!(r.is_ok());
+		let (r, h) = r.unwrap();
+		assert_eq!(r, b"");
+		assert_eq!(h.subsystem, SubSystem::SmtpOut);
+	}
+
+	#[test]
+	fn test_valid_handshake_unknown_keys() {
+		let input = b"config|smtpd-version|7.0.0\nconfig|smtp-session-timeout|300\nconfig|subsystem|smtp-in\nconfig|admd|mx.example.org\nconfig|some-list|a|b\nconfig|ready\n";
+		let r = parse_handshake(input);
+		assert!(r.is_ok());
+		let (r, h) = r.unwrap();
+		assert_eq!(r, b"");
+		assert_eq!(h.smtpd_version, "7.0.0");
+		assert_eq!(h.smtp_session_timeout, 300);
+		assert_eq!(h.subsystem, SubSystem::SmtpIn);
+		assert_eq!(h.extra.len(), 2);
+		assert_eq!(h.extra.get("admd").unwrap(), "mx.example.org");
+		assert_eq!(h.extra.get("some-list").unwrap(), "a|b");
+	}
+
 	#[test]
 	fn test_invalid_handshakes() {
 		let test_vectors = vec![
@@ -8,17 +8,27 @@ use nom::combinator::map_res;
 use nom::IResult;
 use std::str::FromStr;
 
+// Any byte is accepted, except the line terminators.
 fn is_body_char(c: u8) -> bool {
-	!(c as char).is_control()
+	c != b'\r' && c != b'\n'
 }
 
 fn is_parameter_char(c: u8) -> bool {
-	is_body_char(c) && (c as char) != '|'
+	is_body_char(c) && c != b'|'
 }
 
+// Invalid UTF-8 is a parsing error.
 fn parse_string_parameter(input: &[u8]) -> IResult<&[u8], String> {
+	map_res(take_while1(is_parameter_char), |s: &[u8]| {
+		String::from_utf8(s.to_vec())
+	})(input)
+}
+
+// Invalid UTF-8 is replaced, for the parameters sent as-is by the
+// SMTP clients.
+fn parse_lossy_parameter(input: &[u8]) -> IResult<&[u8], String> {
 	let (input, s) = take_while1(is_parameter_char)(input)?;
-	Ok((input, String::from_utf8(s.to_vec()).unwrap()))
+	Ok((input, String::from_utf8_lossy(s).into_owned()))
 }
 
 fn parse_data_structure<T>(input: &[u8]) -> IResult<&[u8], T>
@@ -40,27 +50,40 @@ fn parse_eol(input: &[u8]) -> IResult<&[u8], &[u8]> {
 
 fn parse_usize(input: &[u8]) -> IResult<&[u8], usize> {
 	map_res(take_while1(|c| (c as char).is_ascii_digit()), |s| {
-		usize::from_str_radix(&String::from_utf8_lossy(s), 10)
+		String::from_utf8_lossy(s).parse::<usize>()
 	})(input)
 }
 
 #[cfg(test)]
 mod tests {
-	use super::is_parameter_char;
+	use super::{is_parameter_char, parse_lossy_parameter, parse_string_parameter};
 
 	#[test]
 	fn test_valid_parameter_char() {
-		let char_lst = "a0.:-_/";
-		for c in char_lst.bytes() {
-			assert!(is_parameter_char(c));
+		let char_lst = b"a0.:-_/\x00\x1b\x85\xe9";
+		for c in char_lst.iter() {
+			assert!(is_parameter_char(*c));
 		}
 	}
 
 	#[test]
 	fn test_invalid_parameter_char() {
-		let char_lst = "|\n";
+		let char_lst = "|\r\n";
 		for c in char_lst.bytes() {
 			assert!(!is_parameter_char(c));
 		}
 	}
+
+	#[test]
+	fn test_string_parameter() {
+		assert_eq!(
+			parse_string_parameter("café|".as_bytes()),
+			Ok((&b"|"[..], String::from("café")))
+		);
+		assert!(parse_string_parameter(b"caf\xe9|").is_err());
+		assert_eq!(
+			parse_lossy_parameter(b"caf\xe9|"),
+			Ok((&b"|"[..], String::from("caf\u{fffd}")))
+		);
+	}
 }
@@ -1,12 +1,13 @@
 use super::{
-	is_body_char, is_parameter_char, parse_data_structure, parse_delimiter, parse_eol,
+	is_parameter_char, parse_data_structure, parse_delimiter, parse_eol, parse_lossy_parameter,
 	parse_string_parameter, parse_usize,
 };
-use crate::{Address, AuthResult, FilterKind, FilterPhase, MailResult, Method};
+use crate::{Address, AuthResult, FilterKind, FilterPhase, MailResult, Method, ProtocolVersion};
 use nom::branch::alt;
 use nom::bytes::streaming::{tag, take_while, take_while1};
 use nom::combinator::{map_res, opt};
-use nom::IResult;
+use nom::error::{Error, ErrorKind};
+use nom::{Err, IResult};
 use std::net::SocketAddr;
 use std::path::PathBuf;
 
@@ -32,37 +33,46 @@ pub(crate) fn parse_filter_connect(
 	Ok((input, (rdns, fcrdns, src, dest)))
 }
 
-pub(crate) fn parse_filter_data_line(input: &[u8]) -> IResult<&[u8], &[u8]> {
-	let (input, _) = parse_delimiter(input)?;
-	let (input, s) = take_while(is_body_char)(input)?;
-	let (input, _) = parse_eol(input)?;
-	Ok((input, s))
+/// Returns the unstuffed data-line, or `None` for the final dot.
+///
+/// The content is byte-transparent: only the final line feed is
+/// removed.
+pub(crate) fn parse_filter_data_line(input: &[u8]) -> IResult<&[u8], Option<&[u8]>> {
+	let (input, _) = parse_delimiter(input)?;
+	let (input, s) = take_while(|c| c != b'\n')(input)?;
+	let (input, _) = tag("\n")(input)?;
+	let data_line = match s {
+		b"." => None,
+		[b'.', rest @ ..] => Some(rest),
+		_ => Some(s),
+	};
+	Ok((input, data_line))
 }
 
 pub(crate) fn parse_filter_ehlo(input: &[u8]) -> IResult<&[u8], String> {
 	let (input, _) = parse_delimiter(input)?;
-	let (input, s) = parse_string_parameter(input)?;
+	let (input, s) = parse_lossy_parameter(input)?;
 	let (input, _) = parse_eol(input)?;
 	Ok((input, s))
 }
 
 pub(crate) fn parse_filter_helo(input: &[u8]) -> IResult<&[u8], String> {
 	let (input, _) = parse_delimiter(input)?;
-	let (input, s) = parse_string_parameter(input)?;
+	let (input, s) = parse_lossy_parameter(input)?;
 	let (input, _) = parse_eol(input)?;
 	Ok((input, s))
 }
 
 pub(crate) fn parse_filter_mail_from(input: &[u8]) -> IResult<&[u8], String> {
 	let (input, _) = parse_delimiter(input)?;
-	let (input, s) = parse_string_parameter(input)?;
+	let (input, s) = parse_lossy_parameter(input)?;
 	let (input, _) = parse_eol(input)?;
 	Ok((input, s))
 }
 
 pub(crate) fn parse_filter_rcpt_to(input: &[u8]) -> IResult<&[u8], String> {
 	let (input, _) = parse_delimiter(input)?;
-	let (input, s) = parse_string_parameter(input)?;
+	let (input, s) = parse_lossy_parameter(input)?;
 	let (input, _) = parse_eol(input)?;
 	Ok((input, s))
 }
@@ -74,13 +84,25 @@ pub(crate) fn parse_filter_starttls(input: &[u8]) -> IResult<&[u8], String> {
 	Ok((input, s))
 }
 
-pub(crate) fn parse_report_link_auth(input: &[u8]) -> IResult<&[u8], (String, AuthResult)> {
-	let (input, _) = parse_delimiter(input)?;
-	let (input, username) = parse_string_parameter(input)?;
-	let (input, _) = parse_delimiter(input)?;
-	let (input, result) = parse_data_structure::<AuthResult>(input)?;
-	let (input, _) = parse_eol(input)?;
-	Ok((input, (username, result)))
+pub(crate) fn parse_report_link_auth(
+	version: ProtocolVersion,
+	input: &[u8],
+) -> IResult<&[u8], (String, AuthResult)> {
+	if version >= ProtocolVersion::V0_6 {
+		let (input, _) = parse_delimiter(input)?;
+		let (input, result) = parse_data_structure::<AuthResult>(input)?;
+		let (input, _) = parse_delimiter(input)?;
+		let (input, username) = parse_string_parameter(input)?;
+		let (input, _) = parse_eol(input)?;
+		Ok((input, (username, result)))
+	} else {
+		let (input, _) = parse_delimiter(input)?;
+		let (input, username) = parse_string_parameter(input)?;
+		let (input, _) = parse_delimiter(input)?;
+		let (input, result) = parse_data_structure::<AuthResult>(input)?;
+		let (input, _) = parse_eol(input)?;
+		Ok((input, (username, result)))
+	}
 }
 
 pub(crate) fn parse_report_link_connect(
@@ -128,15 +150,11 @@ pub(crate) fn parse_report_tx_begin(input: &[u8]) -> IResult<&[u8], String> {
 	Ok((input, id))
 }
 
-pub(crate) fn parse_report_tx_mail(input: &[u8]) -> IResult<&[u8], (String, MailResult, String)> {
-	let (input, _) = parse_delimiter(input)?;
-	let (input, id) = parse_string_parameter(input)?;
-	let (input, _) = parse_delimiter(input)?;
-	let (input, result) = parse_data_structure::<MailResult>(input)?;
-	let (input, _) = parse_delimiter(input)?;
-	let (input, addr) = parse_string_parameter(input)?;
-	let (input, _) = parse_eol(input)?;
-	Ok((input, (id, result, addr)))
+pub(crate) fn parse_report_tx_mail(
+	version: ProtocolVersion,
+	input: &[u8],
+) -> IResult<&[u8], (String, MailResult, String)> {
+	parse_tx_address(version, input)
 }
 
 pub(crate) fn parse_report_tx_reset(input: &[u8]) -> IResult<&[u8], Option<String>> {
@@ -151,15 +169,33 @@ fn parse_tx_reset_opt(input: &[u8]) -> IResult<&[u8], String> {
 	Ok((input, id))
 }
 
-pub(crate) fn parse_report_tx_rcpt(input: &[u8]) -> IResult<&[u8], (String, MailResult, String)> {
+pub(crate) fn parse_report_tx_rcpt(
+	version: ProtocolVersion,
+	input: &[u8],
+) -> IResult<&[u8], (String, MailResult, String)> {
+	parse_tx_address(version, input)
+}
+
+fn parse_tx_address(
+	version: ProtocolVersion,
+	input: &[u8],
+) -> IResult<&[u8], (String, MailResult, String)> {
 	let (input, _) = parse_delimiter(input)?;
 	let (input, id) = parse_string_parameter(input)?;
 	let (input, _) = parse_delimiter(input)?;
-	let (input, result) = parse_data_structure::<MailResult>(input)?;
-	let (input, _) = parse_delimiter(input)?;
-	let (input, addr) = parse_string_parameter(input)?;
-	let (input, _) = parse_eol(input)?;
-	Ok((input, (id, result, addr)))
+	if version >= ProtocolVersion::V0_5 {
+		let (input, result) = parse_data_structure::<MailResult>(input)?;
+		let (input, _) = parse_delimiter(input)?;
+		let (input, addr) = parse_lossy_parameter(input)?;
+		let (input, _) = parse_eol(input)?;
+		Ok((input, (id, result, addr)))
+	} else {
+		let (input, addr) = parse_lossy_parameter(input)?;
+		let (input, _) = parse_delimiter(input)?;
+		let (input, result) = parse_data_structure::<MailResult>(input)?;
+		let (input, _) = parse_eol(input)?;
+		Ok((input, (id, result, addr)))
+	}
 }
 
 pub(crate) fn parse_report_tx_envelope(input: &[u8]) -> IResult<&[u8], (String, String)> {
@@ -228,8 +264,12 @@ fn parse_filter_response_opt(input: &[u8]) -> IResult<&[u8], String> {
 }
 
 pub(crate) fn parse_report_filter_report(
+	version: ProtocolVersion,
 	input: &[u8],
 ) -> IResult<&[u8], (FilterKind, String, String)> {
+	if version < ProtocolVersion::V0_5 {
+		return Err(Err::Error(Error::new(input, ErrorKind::Verify)));
+	}
 	let (input, _) = parse_delimiter(input)?;
 	let (input, kind) = parse_data_structure::<FilterKind>(input)?;
 	let (input, _) = parse_delimiter(input)?;
@@ -273,6 +313,56 @@ mod tests {
 	use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 	use std::path::Path;
 
+	#[test]
+	fn test_data_line() {
+		assert_eq!(
+			parse_filter_data_line(b"|Subject: test\n"),
+			Ok((&b""[..], Some(&b"Subject: test"[..])))
+		);
+		assert_eq!(
+			parse_filter_data_line(b"|..hidden\n"),
+			Ok((&b""[..], Some(&b".hidden"[..])))
+		);
+		assert_eq!(
+			parse_filter_data_line(b"|..\n"),
+			Ok((&b""[..], Some(&b"."[..])))
+		);
+		assert_eq!(parse_filter_data_line(b"|.\n"), Ok((&b""[..], None)));
+		assert_eq!(
+			parse_filter_data_line(b"|caf\xe9 \x00\x1b|\rx\r\n"),
+			Ok((&b""[..], Some(&b"caf\xe9 \x00\x1b|\rx\r"[..])))
+		);
+	}
+
+	#[test]
+	fn test_invalid_utf8() {
+		let expected = Ok((&b""[..], String::from("<caf\u{fffd}@example.org>")));
+		assert_eq!(
+			parse_filter_mail_from(b"|<caf\xe9@example.org>\n"),
+			expected
+		);
+		assert_eq!(parse_filter_rcpt_to(b"|<caf\xe9@example.org>\n"), expected);
+		assert_eq!(
+			parse_filter_helo(b"|caf\xe9\n"),
+			Ok((&b""[..], String::from("caf\u{fffd}")))
+		);
+		assert!(parse_filter_auth(b"|caf\xe9\n").is_err());
+		assert_eq!(
+			parse_report_tx_mail(
+				ProtocolVersion::V0_5,
+				b"|1ef1c203|ok|<caf\xe9@example.org>\n"
+			),
+			Ok((
+				&b""[..],
+				(
+					String::from("1ef1c203"),
+					MailResult::Ok,
+					String::from("<caf\u{fffd}@example.org>")
+				)
+			))
+		);
+	}
+
 	#[test]
 	fn test_ipv4_port() {
 		let res = parse_address(b"199.185.178.25:33174|");
@@ -285,7 +375,7 @@ mod tests {
 				assert_eq!(addr.port(), 33174);
 				assert_eq!(addr.ip(), IpAddr::V4(Ipv4Addr::new(199, 185, 178, 25)));
 			}
-			Address::UnixSocket(_) => assert!(false),
+			Address::UnixSocket(_) => panic!("not an IP address"),
 		};
 	}
 
@@ -304,7 +394,7 @@ mod tests {
 					IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0x42))
 				);
 			}
-			Address::UnixSocket(_) => assert!(false),
+			Address::UnixSocket(_) => panic!("not an IP address"),
 		};
 	}
 
@@ -318,7 +408,7 @@ mod tests {
 			Address::UnixSocket(addr) => {
 				assert_eq!(addr, Path::new("/var/something.sock").to_path_buf());
 			}
-			Address::Ip(_) => assert!(false),
+			Address::Ip(_) => panic!("not a unix socket"),
 		};
 	}
 
@@ -342,7 +432,48 @@ mod tests {
 		let test_vectors = vec!["|\n", "|\r\n", "|derp", "|derp|derpson\n"];
 		for test in test_vectors {
 			let res = parse_filter_auth(test.as_bytes());
-			assert!(!res.is_ok());
+			assert!(res.is_err());
 		}
 	}
+
+	#[test]
+	fn test_parse_report_link_auth_versions() {
+		let (_, (username, result)) =
+			parse_report_link_auth(ProtocolVersion::V0_5, b"|derp|pass\n").unwrap();
+		assert_eq!(username, "derp");
+		assert_eq!(result, AuthResult::Pass);
+		let (_, (username, result)) =
+			parse_report_link_auth(ProtocolVersion::V0_6, b"|fail|derp\n").unwrap();
+		assert_eq!(username, "derp");
+		assert_eq!(result, AuthResult::Fail);
+		assert!(parse_report_link_auth(ProtocolVersion::V0_6, b"|derp|pass\n").is_err());
+	}
+
+	#[test]
+	fn test_parse_report_tx_mail_versions() {
+		let (_, (id, result, addr)) =
+			parse_report_tx_mail(ProtocolVersion::V0_4, b"|1ef1c203|derp@example.com|ok\n")
+				.unwrap();
+		assert_eq!(id, "1ef1c203");
+		assert_eq!(result, MailResult::Ok);
+		assert_eq!(addr, "derp@example.com");
+		for version in &[ProtocolVersion::V0_5, ProtocolVersion::V0_7] {
+			let (_, (id, result, addr)) =
+				parse_report_tx_rcpt(*version, b"|1ef1c203|tempfail|derp@example.com\n").unwrap();
+			assert_eq!(id, "1ef1c203");
+			assert_eq!(result, MailResult::TempFail);
+			assert_eq!(addr, "derp@example.com");
+		}
+	}
+
+	#[test]
+	fn test_parse_report_filter_report_versions() {
+		let input = b"|builtin|derp|some message\n";
+		assert!(parse_report_filter_report(ProtocolVersion::V0_4, input).is_err());
+		let (_, (kind, name, message)) =
+			parse_report_filter_report(ProtocolVersion::V0_5, input).unwrap();
+		assert_eq!(kind, FilterKind::Builtin);
+		assert_eq!(name, "derp");
+		assert_eq!(message, "some message");
+	}
 }
@@ -1,4 +1,6 @@
-use crate::error::nom_err_to_string;
+use crate::error::{line_error, panic_error};
+use crate::io::Output;
+use crate::message::{return_message, MessageBuffers};
 use crate::parsers::entry::{parse_entry, EntryOption};
 use crate::parsers::parameters::{
 	parse_filter_auth, parse_filter_connect, parse_filter_data_line, parse_filter_ehlo,
@@ -9,158 +11,441 @@ use crate::parsers::parameters::{
 	parse_report_tx_begin, parse_report_tx_commit, parse_report_tx_data, parse_report_tx_envelope,
 	parse_report_tx_mail, parse_report_tx_rcpt, parse_report_tx_reset, parse_report_tx_rollback,
 };
-use crate::{Event, Filter, FilterPhase};
+use crate::responder::send_filter_result;
+use crate::runner::ErrorHandler;
+#[cfg(feature = "async")]
+use crate::AsyncFilter;
+use crate::{Error, Event, Filter, FilterEntry, FilterPhase, FilterResponse, ProtocolVersion};
+#[cfg(feature = "async")]
+use std::future::Future;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+#[cfg(feature = "async")]
+use std::sync::Arc;
+#[cfg(feature = "async")]
+use tokio::task::JoinSet;
 
 macro_rules! handle_reports {
-	($obj: ident, $r: ident, $input: ident) => {
+	($obj: ident, $r: ident, $line: ident, $input: ident $(, $aw: tt)?) => {
 		match $r.event {
 			Event::LinkAuth => {
 				let (_, (username, result)) =
-					parse_report_link_auth($input).map_err(|e| e.to_string())?;
-				$obj.on_report_link_auth(&$r, &username, result);
+					parse_report_link_auth($r.version, $input).map_err(|e| line_error($line, e))?;
+				$obj.on_report_link_auth(&$r, &username, result)$(.$aw)?;
 			}
 			Event::LinkConnect => {
 				let (_, (rdns, fcrdns, src, dest)) =
-					parse_report_link_connect($input).map_err(|e| e.to_string())?;
-				$obj.on_report_link_connect(&$r, &rdns, &fcrdns, &src, &dest);
+					parse_report_link_connect($input).map_err(|e| line_error($line, e))?;
+				$obj.on_report_link_connect(&$r, &rdns, &fcrdns, &src, &dest)$(.$aw)?;
 			}
 			Event::LinkDisconnect => {
-				$obj.on_report_link_disconnect(&$r);
+				$obj.on_report_link_disconnect(&$r)$(.$aw)?;
 			}
 			Event::LinkGreeting => {
 				let (_, hostname) =
-					parse_report_link_greeting($input).map_err(|e| e.to_string())?;
-				$obj.on_report_link_greeting(&$r, &hostname);
+					parse_report_link_greeting($input).map_err(|e| line_error($line, e))?;
+				$obj.on_report_link_greeting(&$r, &hostname)$(.$aw)?;
 			}
 			Event::LinkIdentify => {
 				let (_, (method, identity)) =
-					parse_report_link_identify($input).map_err(|e| e.to_string())?;
-				$obj.on_report_link_identify(&$r, method, &identity);
+					parse_report_link_identify($input).map_err(|e| line_error($line, e))?;
+				$obj.on_report_link_identify(&$r, method, &identity)$(.$aw)?;
 			}
 			Event::LinkTls => {
-				let (_, s) = parse_report_link_tls($input).map_err(|e| e.to_string())?;
-				$obj.on_report_link_tls(&$r, &s);
+				let (_, s) = parse_report_link_tls($input).map_err(|e| line_error($line, e))?;
+				$obj.on_report_link_tls(&$r, &s)$(.$aw)?;
 			}
 			Event::TxBegin => {
-				let (_, id) = parse_report_tx_begin($input).map_err(|e| e.to_string())?;
-				$obj.on_report_tx_begin(&$r, &id);
+				let (_, id) = parse_report_tx_begin($input).map_err(|e| line_error($line, e))?;
+				$obj.on_report_tx_begin(&$r, &id)$(.$aw)?;
 			}
 			Event::TxMail => {
 				let (_, (id, result, addr)) =
-					parse_report_tx_mail($input).map_err(|e| e.to_string())?;
-				$obj.on_report_tx_mail(&$r, &id, result, &addr);
+					parse_report_tx_mail($r.version, $input).map_err(|e| line_error($line, e))?;
+				$obj.on_report_tx_mail(&$r, &id, result, &addr)$(.$aw)?;
 			}
 			Event::TxReset => {
-				let (_, id) = parse_report_tx_reset($input).map_err(|e| e.to_string())?;
-				$obj.on_report_tx_reset(&$r, &id);
+				let (_, id) = parse_report_tx_reset($input).map_err(|e| line_error($line, e))?;
+				$obj.on_report_tx_reset(&$r, &id)$(.$aw)?;
 			}
 			Event::TxRcpt => {
 				let (_, (id, result, addr)) =
-					parse_report_tx_rcpt($input).map_err(|e| e.to_string())?;
-				$obj.on_report_tx_rcpt(&$r, &id, result, &addr);
+					parse_report_tx_rcpt($r.version, $input).map_err(|e| line_error($line, e))?;
+				$obj.on_report_tx_rcpt(&$r, &id, result, &addr)$(.$aw)?;
 			}
 			Event::TxEnvelope => {
 				let (_, (msg, env)) =
-					parse_report_tx_envelope($input).map_err(|e| e.to_string())?;
-				$obj.on_report_tx_envelope(&$r, &msg, &env);
+					parse_report_tx_envelope($input).map_err(|e| line_error($line, e))?;
+				$obj.on_report_tx_envelope(&$r, &msg, &env)$(.$aw)?;
 			}
 			Event::TxData => {
-				let (_, (id, result)) = parse_report_tx_data($input).map_err(|e| e.to_string())?;
-				$obj.on_report_tx_data(&$r, &id, result);
+				let (_, (id, result)) = parse_report_tx_data($input).map_err(|e| line_error($line, e))?;
+				$obj.on_report_tx_data(&$r, &id, result)$(.$aw)?;
 			}
 			Event::TxCommit => {
-				let (_, (id, size)) = parse_report_tx_commit($input).map_err(|e| e.to_string())?;
-				$obj.on_report_tx_commit(&$r, &id, size);
+				let (_, (id, size)) = parse_report_tx_commit($input).map_err(|e| line_error($line, e))?;
+				$obj.on_report_tx_commit(&$r, &id, size)$(.$aw)?;
 			}
 			Event::TxRollback => {
-				let (_, id) = parse_report_tx_rollback($input).map_err(|e| e.to_string())?;
-				$obj.on_report_tx_rollback(&$r, &id);
+				let (_, id) = parse_report_tx_rollback($input).map_err(|e| line_error($line, e))?;
+				$obj.on_report_tx_rollback(&$r, &id)$(.$aw)?;
 			}
 			Event::ProtocolClient => {
-				let (_, cmd) = parse_report_protocol_client($input).map_err(|e| e.to_string())?;
-				$obj.on_report_protocol_client(&$r, &cmd);
+				let (_, cmd) = parse_report_protocol_client($input).map_err(|e| line_error($line, e))?;
+				$obj.on_report_protocol_client(&$r, &cmd)$(.$aw)?;
 			}
 			Event::ProtocolServer => {
-				let (_, res) = parse_report_protocol_server($input).map_err(|e| e.to_string())?;
-				$obj.on_report_protocol_server(&$r, &res);
+				let (_, res) = parse_report_protocol_server($input).map_err(|e| line_error($line, e))?;
+				$obj.on_report_protocol_server(&$r, &res)$(.$aw)?;
 			}
 			Event::FilterResponse => {
 				let (_, (phase, res, param)) =
-					parse_report_filter_response($input).map_err(|e| e.to_string())?;
-				$obj.on_report_filter_response(&$r, phase, &res, &param);
+					parse_report_filter_response($input).map_err(|e| line_error($line, e))?;
+				$obj.on_report_filter_response(&$r, phase, &res, &param)$(.$aw)?;
 			}
 			Event::FilterReport => {
 				let (_, (kind, name, message)) =
-					parse_report_filter_report($input).map_err(|e| e.to_string())?;
-				$obj.on_report_filter_report(&$r, kind, &name, &message);
+					parse_report_filter_report($r.version, $input).map_err(|e| line_error($line, e))?;
+				$obj.on_report_filter_report(&$r, kind, &name, &message)$(.$aw)?;
 			}
 			Event::Timeout => {
-				$obj.on_report_timeout(&$r);
+				$obj.on_report_timeout(&$r)$(.$aw)?;
 			}
 		}
 	};
 }
 
+// Returns whether the filter registered the report if it ends the
+// session, `None` otherwise.
+macro_rules! session_end {
+	($obj: ident, $r: ident) => {
+		match $r.event {
+			Event::LinkDisconnect => Some($obj.has_report_link_disconnect(&$r.subsystem)),
+			Event::Timeout => Some($obj.has_report_timeout(&$r.subsystem)),
+			_ => None,
+		}
+	};
+}
+
 macro_rules! handle_filters {
-	($obj: ident, $f: ident, $input: ident) => {
+	($obj: ident, $f: ident, $line: ident, $input: ident $(, $aw: tt)?) => {
 		match $f.phase {
 			FilterPhase::Auth => {
-				let (_, auth) = parse_filter_auth($input).map_err(|e| e.to_string())?;
-				Some($obj.on_filter_auth(&$f, &auth))
+				let (_, auth) = parse_filter_auth($input).map_err(|e| line_error($line, e))?;
+				Some($obj.on_filter_auth(&$f, &auth)$(.$aw)?)
 			}
-			FilterPhase::Commit => Some($obj.on_filter_commit(&$f)),
+			FilterPhase::Commit => Some($obj.on_filter_commit(&$f)$(.$aw)?),
 			FilterPhase::Connect => {
 				let (_, (rdns, fcrdns, src, dest)) =
-					parse_filter_connect($input).map_err(|e| e.to_string())?;
-				Some($obj.on_filter_connect(&$f, &rdns, &fcrdns, &src, &dest))
+					parse_filter_connect($input).map_err(|e| line_error($line, e))?;
+				Some($obj.on_filter_connect(&$f, &rdns, &fcrdns, &src, &dest)$(.$aw)?)
 			}
-			FilterPhase::Data => Some($obj.on_filter_data(&$f)),
+			FilterPhase::Data => Some($obj.on_filter_data(&$f)$(.$aw)?),
 			FilterPhase::DataLine => {
-				let (_, data_line) = parse_filter_data_line($input).map_err(|e| e.to_string())?;
-				$obj.on_filter_data_line(&$f, &data_line);
+				let (_, data_line) = parse_filter_data_line($input).map_err(|e| line_error($line, e))?;
+				match data_line {
+					Some(data_line) => $obj.on_filter_data_line(&$f, data_line)$(.$aw)?,
+					None => $obj.on_filter_data_end(&$f)$(.$aw)?,
+				}
 				None
 			}
 			FilterPhase::Ehlo => {
-				let (_, identity) = parse_filter_ehlo($input).map_err(|e| e.to_string())?;
-				Some($obj.on_filter_ehlo(&$f, &identity))
+				let (_, identity) = parse_filter_ehlo($input).map_err(|e| line_error($line, e))?;
+				Some($obj.on_filter_ehlo(&$f, &identity)$(.$aw)?)
 			}
 			FilterPhase::Helo => {
-				let (_, identity) = parse_filter_helo($input).map_err(|e| e.to_string())?;
-				Some($obj.on_filter_helo(&$f, &identity))
+				let (_, identity) = parse_filter_helo($input).map_err(|e| line_error($line, e))?;
+				Some($obj.on_filter_helo(&$f, &identity)$(.$aw)?)
 			}
 			FilterPhase::MailFrom => {
-				let (_, address) = parse_filter_mail_from($input).map_err(|e| e.to_string())?;
-				Some($obj.on_filter_mail_from(&$f, &address))
+				let (_, address) = parse_filter_mail_from($input).map_err(|e| line_error($line, e))?;
+				Some($obj.on_filter_mail_from(&$f, &address)$(.$aw)?)
 			}
 			FilterPhase::RcptTo => {
-				let (_, address) = parse_filter_rcpt_to($input).map_err(|e| e.to_string())?;
-				Some($obj.on_filter_rcpt_to(&$f, &address))
+				let (_, address) = parse_filter_rcpt_to($input).map_err(|e| line_error($line, e))?;
+				Some($obj.on_filter_rcpt_to(&$f, &address)$(.$aw)?)
 			}
 			FilterPhase::StartTls => {
-				let (_, tls_str) = parse_filter_starttls($input).map_err(|e| e.to_string())?;
-				Some($obj.on_filter_starttls(&$f, &tls_str))
+				let (_, tls_str) = parse_filter_starttls($input).map_err(|e| line_error($line, e))?;
+				Some($obj.on_filter_starttls(&$f, &tls_str)$(.$aw)?)
 			}
 		}
 	};
 }
 
-pub(crate) fn line<T>(user_object: &mut T, input: &[u8]) -> Result<(), String>
+pub(crate) fn line<T>(
+	user_object: &mut T,
+	output: &Output,
+	messages: &mut MessageBuffers,
+	line: &[u8],
+) -> Result<(), Error>
+where
+	T: Filter,
+{
+	let (input, entry) = parse_entry(line).map_err(|e| line_error(line, e))?;
+	let session_id = match &entry {
+		EntryOption::Report(r) => r.session_id.clone(),
+		EntryOption::Filter(f) => f.session_id.clone(),
+	};
+	// The rest of the message is sent back unmodified if a data-line
+	// handler panics.
+	let data_entry = match &entry {
+		EntryOption::Filter(f) if f.phase == FilterPhase::DataLine => {
+			let mut f = f.clone();
+			f.output = output.clone();
+			Some(f)
+		}
+		_ => None,
+	};
+	catch_unwind(AssertUnwindSafe(|| {
+		dispatch(user_object, output, messages, line, input, entry)
+	}))
+	.unwrap_or_else(|payload| {
+		if let Some(f) = &data_entry {
+			let data_line = parse_filter_data_line(input).ok().and_then(|(_, l)| l);
+			messages.pass_through(f, data_line);
+		}
+		Err(panic_error(&session_id, payload))
+	})
+}
+
+fn dispatch<T>(
+	user_object: &mut T,
+	output: &Output,
+	messages: &mut MessageBuffers,
+	line: &[u8],
+	input: &[u8],
+	entry: EntryOption,
+) -> Result<(), Error>
 where
 	T: Filter,
 {
-	let (input, entry) = parse_entry(input).map_err(nom_err_to_string)?;
 	match entry {
-		EntryOption::Report(r) => handle_reports!(user_object, r, input),
-		EntryOption::Filter(f) => {
-			if let Some(answer) = handle_filters!(user_object, f, input) {
-				println!(
-					"filter-result|{}|{}|{}",
-					f.session_id,
-					f.token,
-					answer.to_string()
-				);
-			};
+		EntryOption::Report(r) => {
+			if let Some(registered) = session_end!(user_object, r) {
+				messages.purge(&r.session_id);
+				if !registered {
+					return Ok(());
+				}
+			}
+			handle_reports!(user_object, r, line, input)
+		}
+		EntryOption::Filter(mut f) if f.phase == FilterPhase::DataLine => {
+			f.output = output.clone();
+			let (_, data_line) = parse_filter_data_line(input).map_err(|e| line_error(line, e))?;
+			if messages.skip(&f, data_line) {
+				return Ok(());
+			}
+			if !user_object.has_message(&f.subsystem) {
+				handle_filters!(user_object, f, line, input);
+			} else if let Some(message) = messages.push(&f, data_line) {
+				// The original message is sent back if the handler panics.
+				let original = message.clone();
+				match catch_unwind(AssertUnwindSafe(|| user_object.on_message(&f, message))) {
+					Ok(content) => return_message(&f, &content),
+					Err(payload) => {
+						return_message(&f, original.as_bytes());
+						return Err(panic_error(&f.session_id, payload));
+					}
+				}
+			}
+		}
+		EntryOption::Filter(mut f) => {
+			f.output = output.clone();
+			let answer = handle_filters!(user_object, f, line, input);
+			send_answer(&f, answer);
 		}
 	};
 	Ok(())
 }
+
+/// Reports an error which occurred while processing a line.
+pub(crate) fn report_error<T>(
+	user_object: &mut T,
+	errors: &ErrorHandler,
+	output: &Output,
+	line: &[u8],
+	error: &Error,
+) where
+	T: Filter,
+{
+	if let Error::Panic {
+		session_id,
+		message,
+	} = error
+	{
+		user_object.on_panic(session_id, message);
+	}
+	errors.handle(output, line, error);
+}
+
+#[cfg(feature = "async")]
+pub(crate) async fn async_line<T>(
+	user_object: &Arc<T>,
+	output: &Output,
+	messages: &mut MessageBuffers,
+	errors: &Arc<ErrorHandler>,
+	tasks: &mut JoinSet<()>,
+	line: Vec<u8>,
+) -> Result<(), Error>
+where
+	T: AsyncFilter + 'static,
+{
+	let (rest, entry) = parse_entry(&line).map_err(|e| line_error(&line, e))?;
+	let offset = line.len() - rest.len();
+	match entry {
+		EntryOption::Report(r) => {
+			if let Some(registered) = session_end!(user_object, r) {
+				messages.purge(&r.session_id);
+				if !registered {
+					return Ok(());
+				}
+			}
+			let user_object = Arc::clone(user_object);
+			let session_id = r.session_id.clone();
+			isolate(&session_id, async move {
+				let (raw, input) = (line.as_slice(), &line[offset..]);
+				handle_reports!(user_object, r, raw, input, await);
+				Ok(())
+			})
+			.await?;
+		}
+		EntryOption::Filter(mut f) if f.phase == FilterPhase::DataLine => {
+			f.output = output.clone();
+			let (_, data_line) =
+				parse_filter_data_line(&line[offset..]).map_err(|e| line_error(&line, e))?;
+			if messages.skip(&f, data_line) {
+				return Ok(());
+			}
+			if !user_object.has_message(&f.subsystem) {
+				let data_line = data_line.map(<[u8]>::to_vec);
+				let entry = f.clone();
+				let user_object = Arc::clone(user_object);
+				let res = isolate(&f.session_id.clone(), async move {
+					let (raw, input) = (line.as_slice(), &line[offset..]);
+					handle_filters!(user_object, f, raw, input, await);
+					Ok(())
+				})
+				.await;
+				if let Err(Error::Panic { .. }) = res {
+					messages.pass_through(&entry, data_line.as_deref());
+				}
+				res?;
+			} else if let Some(message) = messages.push(&f, data_line) {
+				let user_object = Arc::clone(user_object);
+				let output = output.clone();
+				let errors = Arc::clone(errors);
+				tasks.spawn(async move {
+					let entry = f.clone();
+					let original = message.clone();
+					let task_object = Arc::clone(&user_object);
+					let res = isolate(&entry.session_id, async move {
+						let content = task_object.on_message(&f, message).await;
+						return_message(&f, &content);
+						Ok(())
+					})
+					.await;
+					if let Err(e) = res {
+						return_message(&entry, original.as_bytes());
+						async_report_error(&user_object, &errors, &output, &line, &e).await;
+					}
+				});
+			}
+		}
+		EntryOption::Filter(mut f) => {
+			f.output = output.clone();
+			let user_object = Arc::clone(user_object);
+			let output = output.clone();
+			let errors = Arc::clone(errors);
+			tasks.spawn(async move {
+				let session_id = f.session_id.clone();
+				let task_object = Arc::clone(&user_object);
+				let task_line = line.clone();
+				let res = isolate(&session_id, async move {
+					async_filter(&task_object, f, &task_line, offset).await
+				})
+				.await;
+				if let Err(e) = res {
+					async_report_error(&user_object, &errors, &output, &line, &e).await;
+				}
+			});
+		}
+	};
+	Ok(())
+}
+
+/// Runs a handler in its own task, so a panic is caught instead of
+/// stopping the runner.
+#[cfg(feature = "async")]
+async fn isolate<F>(session_id: &str, task: F) -> Result<(), Error>
+where
+	F: Future<Output = Result<(), Error>> + Send + 'static,
+{
+	match tokio::spawn(task).await {
+		Ok(res) => res,
+		Err(e) if e.is_panic() => Err(panic_error(session_id, e.into_panic())),
+		Err(_) => Ok(()),
+	}
+}
+
+#[cfg(feature = "async")]
+async fn async_filter<T>(
+	user_object: &Arc<T>,
+	f: FilterEntry,
+	line: &[u8],
+	offset: usize,
+) -> Result<(), Error>
+where
+	T: AsyncFilter,
+{
+	let input = &line[offset..];
+	let answer = handle_filters!(user_object, f, line, input, await);
+	send_answer(&f, answer);
+	Ok(())
+}
+
+/// Asynchronous version of [`report_error`].
+#[cfg(feature = "async")]
+pub(crate) async fn async_report_error<T>(
+	user_object: &Arc<T>,
+	errors: &ErrorHandler,
+	output: &Output,
+	line: &[u8],
+	error: &Error,
+) where
+	T: AsyncFilter,
+{
+	if let Error::Panic {
+		session_id,
+		message,
+	} = error
+	{
+		user_object.on_panic(session_id, message).await;
+	}
+	errors.handle(output, line, error);
+}
+
+/// Returns the protocol version, the session id and the token of a
+/// filter request which expects a filter-result, even if the rest of
+/// the line is invalid.
+pub(crate) fn filter_request_ids(line: &[u8]) -> Option<(ProtocolVersion, String, String)> {
+	let line = line.strip_suffix(b"\n").unwrap_or(line);
+	let fields: Vec<&[u8]> = line.splitn(8, |&c| c == b'|').collect();
+	match fields.as_slice() {
+		[b"filter", version, _, _, phase, session_id, token, ..]
+			if *phase != b"data-line" && !session_id.is_empty() && !token.is_empty() =>
+		{
+			let version = std::str::from_utf8(version).ok()?.parse().ok()?;
+			let session_id = String::from_utf8(session_id.to_vec()).ok()?;
+			let token = String::from_utf8(token.to_vec()).ok()?;
+			Some((version, session_id, token))
+		}
+		_ => None,
+	}
+}
+
+fn send_answer(f: &FilterEntry, answer: Option<FilterResponse>) {
+	match answer {
+		Some(FilterResponse::Pending) | None => {}
+		Some(answer) => send_filter_result(&f.output, f.version, &f.session_id, &f.token, &answer),
+	}
+}
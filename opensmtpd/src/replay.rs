@@ -0,0 +1,274 @@
+use crate::io::Buffer;
+use crate::{Error, Filter, FilterRunner, TimeVal};
+use std::fmt;
+use std::io::{Cursor, Read};
+
+/// Direction of a line exchanged with OpenSMTPD.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Direction {
+	/// Line sent by OpenSMTPD to the filter.
+	Inbound,
+	/// Line sent by the filter to OpenSMTPD.
+	Outbound,
+}
+
+impl fmt::Display for Direction {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Direction::Inbound => write!(f, "<"),
+			Direction::Outbound => write!(f, ">"),
+		}
+	}
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TranscriptEntry {
+	pub timestamp: TimeVal,
+	pub direction: Direction,
+	/// The line, without the line break.
+	pub line: Vec<u8>,
+}
+
+/// Lines exchanged between OpenSMTPD and a filter, as recorded using
+/// [`FilterRunner::record`].
+///
+/// A transcript can be replayed on a filter in order to check its
+/// responses did not change, which turns real traffic into regression
+/// tests.
+///
+/// ``` rust,no_run
+/// use opensmtpd::{Filter, Transcript};
+/// use std::fs::File;
+///
+/// struct MyFilter {}
+/// impl Filter for MyFilter {}
+///
+/// let transcript = Transcript::read(File::open("transcript.log").unwrap()).unwrap();
+/// for mismatch in transcript.replay(&mut MyFilter {}) {
+///		println!("{}", mismatch);
+/// }
+/// ```
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Transcript {
+	entries: Vec<TranscriptEntry>,
+}
+
+impl Transcript {
+	pub fn parse(input: &[u8]) -> Result<Self, Error> {
+		let entries = input
+			.split(|&c| c == b'\n')
+			.filter(|l| !l.is_empty())
+			.map(parse_entry)
+			.collect::<Result<_, _>>()?;
+		Ok(Transcript { entries })
+	}
+
+	pub fn read<R>(mut input: R) -> Result<Self, Error>
+	where
+		R: Read,
+	{
+		let mut buffer = Vec::new();
+		input.read_to_end(&mut buffer)?;
+		Transcript::parse(&buffer)
+	}
+
+	pub fn entries(&self) -> &[TranscriptEntry] {
+		&self.entries
+	}
+
+	/// Sends the inbound lines to the filter and compares its responses
+	/// to the recorded ones.
+	///
+	/// The responses are compared for each filter request, so the order
+	/// in which the requests are answered does not matter. An empty list
+	/// means the filter behaved as recorded.
+	///
+	/// The replay ends with the input: a response deferred using a
+	/// [`FilterResponder`](crate::FilterResponder) and sent afterwards
+	/// is missing from the comparison, hence reported as a mismatch.
+	pub fn replay<F>(&self, filter: &mut F) -> Vec<Mismatch>
+	where
+		F: Filter,
+	{
+		let mut input = Vec::new();
+		for entry in self.lines(Direction::Inbound) {
+			input.extend_from_slice(entry);
+			input.push(b'\n');
+		}
+		let output = Buffer::default();
+		FilterRunner::new()
+			.input(Cursor::new(input))
+			.output(output.clone())
+			.run(filter);
+		let output = output.take();
+		let actual = output.split(|&c| c == b'\n').filter(|l| !l.is_empty());
+		diff(self.lines(Direction::Outbound), actual)
+	}
+
+	fn lines(&self, direction: Direction) -> impl Iterator<Item = &[u8]> {
+		self.entries
+			.iter()
+			.filter(move |e| e.direction == direction)
+			.map(|e| e.line.as_slice())
+	}
+}
+
+/// Difference between the recorded responses to a filter request and
+/// the ones sent during a replay.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Mismatch {
+	/// The lines compared, e.g. `filter-result|<session id>|<token>`
+	/// or `register`.
+	pub key: String,
+	pub expected: Vec<String>,
+	pub actual: Vec<String>,
+}
+
+impl fmt::Display for Mismatch {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		writeln!(f, "{}:", self.key)?;
+		for line in &self.expected {
+			writeln!(f, "- {}", line)?;
+		}
+		for line in &self.actual {
+			writeln!(f, "+ {}", line)?;
+		}
+		Ok(())
+	}
+}
+
+fn parse_entry(line: &[u8]) -> Result<TranscriptEntry, Error> {
+	let err = || Error::Malformed {
+		line: line.to_vec(),
+		message: "invalid transcript entry".to_string(),
+	};
+	let mut fields = line.splitn(3, |&c| c == b' ');
+	let timestamp = fields.next().ok_or_else(err)?;
+	let direction = match fields.next().ok_or_else(err)? {
+		b"<" => Direction::Inbound,
+		b">" => Direction::Outbound,
+		_ => return Err(err()),
+	};
+	let line = fields.next().ok_or_else(err)?.to_vec();
+	let timestamp = String::from_utf8_lossy(timestamp);
+	let (sec, usec) = timestamp.split_once('.').ok_or_else(err)?;
+	let timestamp = TimeVal {
+		sec: sec.parse().map_err(|_| err())?,
+		usec: usec.parse().map_err(|_| err())?,
+	};
+	Ok(TranscriptEntry {
+		timestamp,
+		direction,
+		line,
+	})
+}
+
+/// Key grouping the lines answering the same request.
+fn line_key(line: &str) -> String {
+	let fields: Vec<&str> = line.splitn(4, '|').collect();
+	match fields.as_slice() {
+		["register", ..] => "register".to_string(),
+		[kind, session_id, token, _] => format!("{}|{}|{}", kind, session_id, token),
+		_ => line.to_string(),
+	}
+}
+
+fn diff<'a, E, A>(expected: E, actual: A) -> Vec<Mismatch>
+where
+	E: Iterator<Item = &'a [u8]>,
+	A: Iterator<Item = &'a [u8]>,
+{
+	let mut groups: Vec<Mismatch> = Vec::new();
+	let mut push = |line: &[u8], is_expected: bool| {
+		let line = String::from_utf8_lossy(line).into_owned();
+		let key = line_key(&line);
+		let pos = match groups.iter().position(|g| g.key == key) {
+			Some(pos) => pos,
+			None => {
+				groups.push(Mismatch {
+					key,
+					expected: Vec::new(),
+					actual: Vec::new(),
+				});
+				groups.len() - 1
+			}
+		};
+		match is_expected {
+			true => groups[pos].expected.push(line),
+			false => groups[pos].actual.push(line),
+		}
+	};
+	expected.for_each(|l| push(l, true));
+	actual.for_each(|l| push(l, false));
+	groups.retain(|g| g.expected != g.actual);
+	groups
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{Direction, Transcript};
+	use crate::io::Buffer;
+	use crate::runner::tests::run_with;
+	use crate::{Filter, FilterEntry, FilterResponse, FilterRunner, SubSystem};
+
+	struct Helo {
+		junk: &'static str,
+	}
+
+	impl Filter for Helo {
+		fn on_filter_helo(&mut self, _entry: &FilterEntry, identity: &str) -> FilterResponse {
+			match identity == self.junk {
+				true => FilterResponse::Junk,
+				false => FilterResponse::Proceed,
+			}
+		}
+
+		fn has_filter_helo(&self, subsystem: &SubSystem) -> bool {
+			*subsystem == SubSystem::SmtpIn
+		}
+	}
+
+	#[test]
+	fn test_record_replay() {
+		let input = "filter|0.5|1576146008.006099|smtp-in|helo|s1|t1|spammer\n\
+			filter|0.5|1576146008.006099|smtp-in|helo|s2|t2|example.org\n";
+		let transcript = Buffer::default();
+		let runner = FilterRunner::new().record(transcript.clone());
+		run_with(runner, &mut Helo { junk: "spammer" }, input);
+		let transcript = Transcript::parse(&transcript.take()).unwrap();
+		let lines = |direction| -> Vec<String> {
+			transcript
+				.entries()
+				.iter()
+				.filter(|e| e.direction == direction)
+				.map(|e| String::from_utf8_lossy(&e.line).into_owned())
+				.collect()
+		};
+		assert_eq!(lines(Direction::Inbound).len(), 6);
+		assert_eq!(lines(Direction::Inbound)[0], "config|smtpd-version|6.6.1");
+		assert_eq!(
+			lines(Direction::Outbound),
+			vec![
+				"register|filter|smtp-in|helo",
+				"register|ready",
+				"filter-result|s1|t1|junk",
+				"filter-result|s2|t2|proceed",
+			]
+		);
+
+		assert!(transcript.replay(&mut Helo { junk: "spammer" }).is_empty());
+		let mismatches = transcript.replay(&mut Helo {
+			junk: "example.org",
+		});
+		assert_eq!(mismatches.len(), 2);
+		assert_eq!(mismatches[0].key, "filter-result|s1|t1");
+		assert_eq!(mismatches[0].expected, vec!["filter-result|s1|t1|junk"]);
+		assert_eq!(mismatches[0].actual, vec!["filter-result|s1|t1|proceed"]);
+		assert_eq!(
+			mismatches[1].to_string(),
+			"filter-result|s2|t2:\n- filter-result|s2|t2|proceed\n+ filter-result|s2|t2|junk\n"
+		);
+
+		assert!(Transcript::parse(b"1576146008.006099 ? config|ready\n").is_err());
+	}
+}
@@ -0,0 +1,190 @@
+use crate::io::Output;
+use crate::{FilterEntry, FilterResponse, ProtocolVersion, SmtpStatusCode};
+
+/// Handle used to answer a filter request after the handler returned.
+///
+/// When a filter needs some time to take its decision (database
+/// lookup, content scanning, ...), the handler can create a responder
+/// from the [`FilterEntry`], move it to another thread and return
+/// [`FilterResponse::Pending`]. The filter-result will be sent once
+/// [`respond`](FilterResponder::respond) is called. In the meantime,
+/// [`run_filter`](crate::run_filter) keeps processing the other
+/// sessions.
+///
+/// A responder can only be used once. Dropping it without answering
+/// would leave the SMTP session waiting until OpenSMTPD times out,
+/// hence an error is logged and the request is answered with
+/// [`FilterRunner::fail_response`](crate::FilterRunner::fail_response).
+/// Likewise, [`FilterResponse::Pending`] cannot be deferred again: it
+/// is answered with a temporary failure (`451`).
+///
+/// ``` rust
+/// use opensmtpd::{Filter, FilterEntry, FilterResponder, FilterResponse};
+/// use opensmtpd_derive::register;
+/// use std::thread;
+///
+/// struct SlowFilter {}
+///
+/// impl Filter for SlowFilter {
+///		#[register]
+///		fn on_filter_rcpt_to(&mut self, entry: &FilterEntry, _address: &str) -> FilterResponse {
+///			let responder = FilterResponder::new(entry);
+///			thread::spawn(move || {
+///				// Some slow lookup here.
+///				responder.respond(FilterResponse::Proceed);
+///			});
+///			FilterResponse::Pending
+///		}
+/// }
+/// ```
+#[derive(Debug)]
+pub struct FilterResponder {
+	version: ProtocolVersion,
+	session_id: String,
+	token: String,
+	output: Output,
+	answered: bool,
+}
+
+impl FilterResponder {
+	pub fn new(entry: &FilterEntry) -> Self {
+		FilterResponder {
+			version: entry.version,
+			session_id: entry.session_id.clone(),
+			token: entry.token.clone(),
+			output: entry.output.clone(),
+			answered: false,
+		}
+	}
+
+	pub fn session_id(&self) -> &str {
+		&self.session_id
+	}
+
+	pub fn token(&self) -> &str {
+		&self.token
+	}
+
+	pub fn respond(mut self, response: FilterResponse) {
+		let response = match response {
+			FilterResponse::Pending => {
+				log::error!(
+					"Pending is not a valid deferred response, sending a temporary failure instead (session id: {}, token: {})",
+					self.session_id,
+					self.token
+				);
+				FilterResponse::Reject(SmtpStatusCode::from_number(451))
+			}
+			response => response,
+		};
+		self.answered = true;
+		send_filter_result(
+			&self.output,
+			self.version,
+			&self.session_id,
+			&self.token,
+			&response,
+		);
+	}
+}
+
+impl Drop for FilterResponder {
+	fn drop(&mut self) {
+		if !self.answered {
+			log::error!(
+				"Filter responder dropped without answering, sending the fail response (session id: {}, token: {})",
+				self.session_id,
+				self.token
+			);
+			let response = self.output.fail_response();
+			if let FilterResponse::Pending = response {
+				return;
+			}
+			send_filter_result(
+				&self.output,
+				self.version,
+				&self.session_id,
+				&self.token,
+				&response,
+			);
+		}
+	}
+}
+
+/// Returns the ids of a filter request in the order expected by the
+/// given protocol version: the token came first before 0.5.
+pub(crate) fn ordered_ids<'a>(
+	version: ProtocolVersion,
+	session_id: &'a str,
+	token: &'a str,
+) -> (&'a str, &'a str) {
+	if version < ProtocolVersion::V0_5 {
+		(token, session_id)
+	} else {
+		(session_id, token)
+	}
+}
+
+pub(crate) fn send_filter_result(
+	output: &Output,
+	version: ProtocolVersion,
+	session_id: &str,
+	token: &str,
+	response: &FilterResponse,
+) {
+	let (first_id, second_id) = ordered_ids(version, session_id, token);
+	let line = format!("filter-result|{}|{}|{}", first_id, second_id, response);
+	if let Err(e) = output.write_line(line.as_bytes()) {
+		output.report_error(e);
+		return;
+	}
+	log::trace!(
+		"Sent filter-result (session id: {}, token: {}): {}",
+		session_id,
+		token,
+		response
+	);
+}
+
+#[cfg(test)]
+mod tests {
+	use super::FilterResponder;
+	use crate::io::{Buffer, Output};
+	use crate::{FilterResponse, ProtocolVersion, SmtpStatusCode};
+
+	fn responder(output: &Buffer) -> FilterResponder {
+		FilterResponder {
+			version: ProtocolVersion::V0_5,
+			session_id: "s1".to_string(),
+			token: "t1".to_string(),
+			output: Output::new(output.clone()),
+			answered: false,
+		}
+	}
+
+	#[test]
+	fn test_respond() {
+		let output = Buffer::default();
+		responder(&output).respond(FilterResponse::Reject(SmtpStatusCode::from_number(550)));
+		responder(&output).respond(FilterResponse::Pending);
+		assert_eq!(
+			String::from_utf8(output.take()).unwrap(),
+			"filter-result|s1|t1|reject|550 Requested action not taken: mailbox unavailable\n\
+			filter-result|s1|t1|reject|451 Requested action aborted: local error in processing\n"
+		);
+	}
+
+	#[test]
+	fn test_drop() {
+		let output = Buffer::default();
+		drop(responder(&output));
+		for response in [FilterResponse::Junk, FilterResponse::Pending] {
+			let mut dropped = responder(&output);
+			dropped.output.set_fail_response(response);
+		}
+		assert_eq!(
+			String::from_utf8(output.take()).unwrap(),
+			"filter-result|s1|t1|proceed\nfilter-result|s1|t1|junk\n"
+		);
+	}
+}
@@ -0,0 +1,968 @@
+use crate::error::{handshake_error, ErrorHook};
+use crate::io::{read_input, Input, Output, Recorder};
+use crate::message::{MessageBuffers, DEFAULT_MAX_MESSAGE_SIZE};
+use crate::parsers::handshake::parse_handshake;
+use crate::process::{self, filter_request_ids};
+use crate::responder::send_filter_result;
+#[cfg(feature = "async")]
+use crate::AsyncFilter;
+use crate::{Error, Filter, FilterResponse, ShutdownReason, SmtpStatusCode, SubSystem};
+use std::io::{self, Read, Write};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::Arc;
+use std::thread;
+#[cfg(feature = "async")]
+use tokio::task::JoinSet;
+
+macro_rules! recv {
+	($rx: ident $(, $aw: tt)?) => {
+		match $rx.recv()$(.$aw)? {
+			Some(Input::Line(b)) => b,
+			Some(Input::Closed(reason)) => return reason,
+			None => {
+				return ShutdownReason::Io(io::Error::new(
+					io::ErrorKind::BrokenPipe,
+					"the input reader stopped unexpectedly",
+				))
+			}
+		}
+	};
+}
+
+macro_rules! handshake {
+	($rx: ident $(, $aw: tt)?) => {{
+		let mut handshake_buffer: Vec<u8> = Vec::with_capacity(crate::BUFFER_SIZE);
+		loop {
+			let buffer = recv!($rx $(, $aw)?);
+			handshake_buffer.extend_from_slice(&buffer);
+			match parse_handshake(&handshake_buffer) {
+				Ok((_, config)) => {
+					log::trace!(
+						"handshake received (smtpd version: {}, session timeout: {}, subsystem: {})",
+						config.smtpd_version,
+						config.smtp_session_timeout,
+						config.subsystem
+					);
+					break config;
+				}
+				Err(nom::Err::Incomplete(_)) => {}
+				Err(e) => {
+					return ShutdownReason::Protocol(handshake_error(&handshake_buffer, e))
+				}
+			}
+		}
+	}};
+}
+
+/// Handles the errors which do not stop the filter.
+pub(crate) struct ErrorHandler {
+	fail_response: FilterResponse,
+	panic_response: FilterResponse,
+	on_error: Option<ErrorHook>,
+}
+
+impl Default for ErrorHandler {
+	fn default() -> Self {
+		ErrorHandler {
+			fail_response: FilterResponse::Proceed,
+			panic_response: FilterResponse::Reject(SmtpStatusCode::from_number(451)),
+			on_error: None,
+		}
+	}
+}
+
+impl ErrorHandler {
+	/// Reports an error which occurred while processing a line and, if
+	/// it is a filter request, answers it so the session does not hang.
+	pub(crate) fn handle(&self, output: &Output, line: &[u8], error: &Error) {
+		log::error!("{}", error);
+		if let Some(on_error) = &self.on_error {
+			on_error(error);
+		}
+		let response = match error {
+			Error::Panic { .. } => &self.panic_response,
+			_ => &self.fail_response,
+		};
+		if let FilterResponse::Pending = response {
+			return;
+		}
+		if let Some((version, session_id, token)) = filter_request_ids(line) {
+			log::debug!(
+				"sending the fail response (session id: {}, token: {})",
+				session_id,
+				token
+			);
+			send_filter_result(output, version, &session_id, &token, response);
+		}
+	}
+}
+
+const SUBSYSTEMS: [SubSystem; 2] = [SubSystem::SmtpIn, SubSystem::SmtpOut];
+
+macro_rules! handshake_register {
+	($obj: ident, $out: ident, $($func: ident)||+, $subsystem: expr, $type: expr, $name: expr) => {
+		if $($obj.$func(&$subsystem))||+ {
+			let line = format!("register|{}|{}|{}", $type, $subsystem, $name);
+			$out.write_line(line.as_bytes())?;
+			log::trace!("{} {} for {} registered", $type, $name, $subsystem);
+		}
+	};
+}
+
+// Implemented as a macro so it can be used with both the `Filter`
+// and the `AsyncFilter` traits.
+macro_rules! handshake_reply {
+	($obj: ident, $out: ident) => {{
+		for ss in SUBSYSTEMS.iter() {
+			// Filters
+			handshake_register!($obj, $out, has_filter_auth, ss, "filter", "auth");
+			handshake_register!($obj, $out, has_filter_commit, ss, "filter", "commit");
+			handshake_register!($obj, $out, has_filter_connect, ss, "filter", "connect");
+			handshake_register!($obj, $out, has_filter_data, ss, "filter", "data");
+			handshake_register!(
+				$obj,
+				$out,
+				has_filter_data_line || has_message,
+				ss,
+				"filter",
+				"data-line"
+			);
+			handshake_register!($obj, $out, has_filter_ehlo, ss, "filter", "ehlo");
+			handshake_register!($obj, $out, has_filter_helo, ss, "filter", "helo");
+			handshake_register!($obj, $out, has_filter_mail_from, ss, "filter", "mail-from");
+			handshake_register!($obj, $out, has_filter_rcpt_to, ss, "filter", "rcpt-to");
+			handshake_register!($obj, $out, has_filter_starttls, ss, "filter", "starttls");
+
+			// Reports
+			handshake_register!($obj, $out, has_report_link_auth, ss, "report", "link-auth");
+			handshake_register!(
+				$obj,
+				$out,
+				has_report_link_connect,
+				ss,
+				"report",
+				"link-connect"
+			);
+			// Needed to drop the messages of the sessions which end
+			// during DATA.
+			handshake_register!(
+				$obj,
+				$out,
+				has_report_link_disconnect || has_message,
+				ss,
+				"report",
+				"link-disconnect"
+			);
+			handshake_register!(
+				$obj,
+				$out,
+				has_report_link_greeting,
+				ss,
+				"report",
+				"link-greeting"
+			);
+			handshake_register!(
+				$obj,
+				$out,
+				has_report_link_identify,
+				ss,
+				"report",
+				"link-identify"
+			);
+			handshake_register!($obj, $out, has_report_link_tls, ss, "report", "link-tls");
+			handshake_register!($obj, $out, has_report_tx_begin, ss, "report", "tx-begin");
+			handshake_register!($obj, $out, has_report_tx_mail, ss, "report", "tx-mail");
+			handshake_register!($obj, $out, has_report_tx_reset, ss, "report", "tx-reset");
+			handshake_register!($obj, $out, has_report_tx_rcpt, ss, "report", "tx-rcpt");
+			handshake_register!(
+				$obj,
+				$out,
+				has_report_tx_envelope,
+				ss,
+				"report",
+				"tx-envelope"
+			);
+			handshake_register!($obj, $out, has_report_tx_data, ss, "report", "tx-data");
+			handshake_register!($obj, $out, has_report_tx_commit, ss, "report", "tx-commit");
+			handshake_register!(
+				$obj,
+				$out,
+				has_report_tx_rollback,
+				ss,
+				"report",
+				"tx-rollback"
+			);
+			handshake_register!(
+				$obj,
+				$out,
+				has_report_protocol_client,
+				ss,
+				"report",
+				"protocol-client"
+			);
+			handshake_register!(
+				$obj,
+				$out,
+				has_report_protocol_server,
+				ss,
+				"report",
+				"protocol-server"
+			);
+			handshake_register!(
+				$obj,
+				$out,
+				has_report_filter_response,
+				ss,
+				"report",
+				"filter-response"
+			);
+			handshake_register!(
+				$obj,
+				$out,
+				has_report_filter_report,
+				ss,
+				"report",
+				"filter-report"
+			);
+			handshake_register!(
+				$obj,
+				$out,
+				has_report_timeout || has_message,
+				ss,
+				"report",
+				"timeout"
+			);
+		}
+
+		// Ready
+		$out.write_line(b"register|ready")?;
+		log::trace!("register ready");
+	}};
+}
+
+/// Sends the events registered by a filter, then `register|ready`.
+pub(crate) fn register<T>(user_object: &T, output: &Output) -> io::Result<()>
+where
+	T: Filter,
+{
+	handshake_reply!(user_object, output);
+	Ok(())
+}
+
+#[cfg(feature = "async")]
+fn register_async<T>(user_object: &T, output: &Output) -> io::Result<()>
+where
+	T: AsyncFilter,
+{
+	handshake_reply!(user_object, output);
+	Ok(())
+}
+
+/// Runs a filter on any input and output streams.
+///
+/// By default, the filter reads on the standard input and writes on
+/// the standard output, which is what [`run_filter`](crate::run_filter)
+/// does. Other streams, such as pipes, sockets or in-memory buffers,
+/// can be used instead.
+///
+/// ``` rust,no_run
+/// use opensmtpd::{Filter, FilterRunner};
+/// use std::os::unix::net::UnixStream;
+///
+/// struct MyFilter {}
+///
+/// impl Filter for MyFilter {}
+///
+/// let stream = UnixStream::connect("/var/run/my-filter.sock").unwrap();
+/// FilterRunner::new()
+///		.input(stream.try_clone().unwrap())
+///		.output(stream)
+///		.run(&mut MyFilter {});
+/// ```
+pub struct FilterRunner {
+	input: Box<dyn Read + Send>,
+	output: Output,
+	max_message_size: usize,
+	errors: ErrorHandler,
+	recorder: Option<Recorder>,
+}
+
+impl Default for FilterRunner {
+	fn default() -> Self {
+		FilterRunner {
+			input: Box::new(io::stdin()),
+			output: Output::default(),
+			max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+			errors: ErrorHandler::default(),
+			recorder: None,
+		}
+	}
+}
+
+impl FilterRunner {
+	pub fn new() -> Self {
+		FilterRunner::default()
+	}
+
+	pub fn input<R>(mut self, input: R) -> Self
+	where
+		R: Read + Send + 'static,
+	{
+		self.input = Box::new(input);
+		self
+	}
+
+	pub fn output<W>(mut self, output: W) -> Self
+	where
+		W: Write + Send + 'static,
+	{
+		self.output = Output::new(output);
+		self
+	}
+
+	/// Sets the maximum size of the messages buffered for
+	/// [`on_message`](crate::Filter::on_message).
+	///
+	/// Bigger messages are sent back unmodified without calling the
+	/// handler. Defaults to 35 MiB.
+	pub fn max_message_size(mut self, size: usize) -> Self {
+		self.max_message_size = size;
+		self
+	}
+
+	/// Sets a function called for each line which cannot be processed
+	/// or written, in addition to the error being logged.
+	///
+	/// Such errors do not stop the filter, which goes on with the next
+	/// line.
+	pub fn on_error<F>(mut self, f: F) -> Self
+	where
+		F: Fn(&Error) + Send + Sync + 'static,
+	{
+		self.errors.on_error = Some(Arc::new(f));
+		self
+	}
+
+	/// Sets the response sent to a filter request which cannot be
+	/// handled because the line is invalid, or whose
+	/// [`FilterResponder`](crate::FilterResponder) is dropped without
+	/// answering.
+	///
+	/// Defaults to [`FilterResponse::Proceed`], so the sessions go on as
+	/// if the filter were not there. Rejecting or disconnecting, e.g.
+	/// with a `451` or `421` status code, makes the filter fail closed
+	/// instead. [`FilterResponse::Pending`] disables the response.
+	pub fn fail_response(mut self, response: FilterResponse) -> Self {
+		self.errors.fail_response = response;
+		self
+	}
+
+	/// Sets the response sent to a filter request whose handler
+	/// panicked.
+	///
+	/// Panics are caught so the filter keeps serving the other sessions,
+	/// and [`Filter::on_panic`] is called. Defaults to a temporary
+	/// failure (`451`). [`FilterResponse::Pending`] disables the
+	/// response.
+	pub fn panic_response(mut self, response: FilterResponse) -> Self {
+		self.errors.panic_response = response;
+		self
+	}
+
+	/// Records every line received from and sent to OpenSMTPD, with the
+	/// time it has been processed, so it can be replayed later using a
+	/// [`Transcript`](crate::Transcript).
+	pub fn record<W>(mut self, transcript: W) -> Self
+	where
+		W: Write + Send + 'static,
+	{
+		self.recorder = Some(Recorder::new(transcript));
+		self
+	}
+
+	pub fn run<T>(self, user_object: &mut T) -> ShutdownReason
+	where
+		T: Filter,
+	{
+		let reason = self.process(user_object);
+		log::debug!("shutting down: {}", reason);
+		user_object.on_shutdown(&reason);
+		reason
+	}
+
+	fn process<T>(self, user_object: &mut T) -> ShutdownReason
+	where
+		T: Filter,
+	{
+		// IO init
+		let (tx, rx) = channel::<Input>();
+		let rx = ChannelReceiver(rx);
+		let input = self.input;
+		let recorder = self.recorder;
+		let mut output = self.output;
+		if let Some(recorder) = &recorder {
+			output.record(recorder.clone());
+		}
+		if let Some(on_error) = &self.errors.on_error {
+			output.on_error(Arc::clone(on_error));
+		}
+		output.set_fail_response(self.errors.fail_response.clone());
+		thread::spawn(move || {
+			read_input(input, recorder, |line| tx.send(line).is_ok());
+		});
+		let mut messages = MessageBuffers::new(self.max_message_size);
+
+		// Handshake
+		let config = handshake!(rx);
+		user_object.on_config(&config);
+		if let Err(e) = register(user_object, &output) {
+			return ShutdownReason::Io(e);
+		}
+		user_object.on_ready();
+
+		// Read and process input
+		loop {
+			let buffer = recv!(rx);
+			if let Err(e) = process::line(user_object, &output, &mut messages, &buffer) {
+				process::report_error(user_object, &self.errors, &output, &buffer, &e);
+			}
+		}
+	}
+
+	/// Asynchronous version of [`run`](FilterRunner::run).
+	///
+	/// The filter requests are processed in separate tokio tasks, hence
+	/// this function must be called from within a tokio runtime. The
+	/// tasks still running at the end of the input are awaited before
+	/// [`on_shutdown`](AsyncFilter::on_shutdown) is called.
+	#[cfg(feature = "async")]
+	pub async fn run_async<T>(mut self, user_object: T) -> ShutdownReason
+	where
+		T: AsyncFilter + 'static,
+	{
+		let user_object = Arc::new(user_object);
+		if let Some(recorder) = &self.recorder {
+			self.output.record(recorder.clone());
+		}
+		if let Some(on_error) = &self.errors.on_error {
+			self.output.on_error(Arc::clone(on_error));
+		}
+		self.output
+			.set_fail_response(self.errors.fail_response.clone());
+		self.output = self.output.spawn_writer();
+		let output = self.output.clone();
+		let mut tasks = JoinSet::new();
+		let reason = self.process_async(&user_object, &mut tasks).await;
+		log::debug!("shutting down: {}", reason);
+		while tasks.join_next().await.is_some() {}
+		user_object.on_shutdown(&reason).await;
+		output.flush().await;
+		reason
+	}
+
+	#[cfg(feature = "async")]
+	async fn process_async<T>(self, user_object: &Arc<T>, tasks: &mut JoinSet<()>) -> ShutdownReason
+	where
+		T: AsyncFilter + 'static,
+	{
+		// IO init
+		let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<Input>();
+		let input = self.input;
+		let recorder = self.recorder;
+		let output = self.output;
+		thread::spawn(move || {
+			read_input(input, recorder, |line| tx.send(line).is_ok());
+		});
+		let mut messages = MessageBuffers::new(self.max_message_size);
+		let errors = Arc::new(self.errors);
+
+		// Handshake
+		let config = handshake!(rx, await);
+		user_object.on_config(&config).await;
+		if let Err(e) = register_async(user_object.as_ref(), &output) {
+			return ShutdownReason::Io(e);
+		}
+		user_object.on_ready().await;
+
+		// Read and process input
+		loop {
+			let buffer = recv!(rx, await);
+			let line = buffer.clone();
+			while tasks.try_join_next().is_some() {}
+			if let Err(e) =
+				process::async_line(user_object, &output, &mut messages, &errors, tasks, buffer)
+					.await
+			{
+				process::async_report_error(user_object, &errors, &output, &line, &e).await;
+			}
+		}
+	}
+}
+
+// Gives the standard channel receiver the same interface as the tokio
+// one so both can be used in the `recv` macro.
+struct ChannelReceiver(Receiver<Input>);
+
+impl ChannelReceiver {
+	fn recv(&self) -> Option<Input> {
+		self.0.recv().ok()
+	}
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+	use super::FilterRunner;
+	use crate::io::Buffer;
+	use crate::{
+		return_data_line, Filter, FilterConfig, FilterEntry, FilterResponse, ReportEntry,
+		ShutdownReason, SmtpStatusCode, SubSystem,
+	};
+	use opensmtpd_derive::register;
+	use std::io::Cursor;
+	use std::sync::{Arc, Mutex};
+
+	pub(crate) const HANDSHAKE: &str = "config|smtpd-version|6.6.1\nconfig|smtp-session-timeout|300\nconfig|subsystem|smtp-in\nconfig|ready\n";
+
+	/// Runs a filter on the given input and returns what it wrote.
+	pub(crate) fn run_raw_with<F>(
+		runner: FilterRunner,
+		filter: &mut F,
+		input: &[u8],
+	) -> (ShutdownReason, Vec<u8>)
+	where
+		F: Filter,
+	{
+		let output = Buffer::default();
+		let reason = runner
+			.input(Cursor::new(input.to_vec()))
+			.output(output.clone())
+			.run(filter);
+		(reason, output.take())
+	}
+
+	/// Runs a filter on the given lines, sent after the handshake, and
+	/// returns the lines it wrote.
+	pub(crate) fn run_with<F>(runner: FilterRunner, filter: &mut F, input: &str) -> Vec<String>
+	where
+		F: Filter,
+	{
+		let input = format!("{}{}", HANDSHAKE, input);
+		let (reason, output) = run_raw_with(runner, filter, input.as_bytes());
+		assert!(matches!(reason, ShutdownReason::EndOfInput));
+		lines(&output)
+	}
+
+	pub(crate) fn run_lines<F>(filter: &mut F, input: &str) -> Vec<String>
+	where
+		F: Filter,
+	{
+		run_with(FilterRunner::new(), filter, input)
+	}
+
+	/// Asynchronous version of [`run_lines`], run on a single-threaded
+	/// tokio runtime.
+	#[cfg(feature = "async")]
+	pub(crate) fn run_async_lines<F>(filter: F, input: &str) -> Vec<String>
+	where
+		F: crate::AsyncFilter + 'static,
+	{
+		let output = Buffer::default();
+		let runner = FilterRunner::new()
+			.input(Cursor::new(format!("{}{}", HANDSHAKE, input).into_bytes()))
+			.output(output.clone());
+		let reason = tokio::runtime::Builder::new_current_thread()
+			.build()
+			.unwrap()
+			.block_on(runner.run_async(filter));
+		assert!(matches!(reason, ShutdownReason::EndOfInput));
+		lines(&output.take())
+	}
+
+	fn lines(output: &[u8]) -> Vec<String> {
+		String::from_utf8_lossy(output)
+			.lines()
+			.map(String::from)
+			.collect()
+	}
+
+	#[derive(Default)]
+	struct TestFilter {
+		data_lines: Vec<Vec<u8>>,
+		nb_disconnect: usize,
+		nb_tx_commit_out: usize,
+		session_timeout: usize,
+		is_ready: bool,
+		is_shut_down: bool,
+		panics: Vec<String>,
+	}
+
+	impl Filter for TestFilter {
+		fn on_config(&mut self, config: &FilterConfig) {
+			self.session_timeout = config.smtp_session_timeout;
+		}
+
+		fn on_ready(&mut self) {
+			self.is_ready = true;
+		}
+
+		fn on_shutdown(&mut self, _reason: &ShutdownReason) {
+			self.is_shut_down = true;
+		}
+
+		fn on_panic(&mut self, session_id: &str, _message: &str) {
+			self.panics.push(session_id.to_string());
+		}
+
+		#[register]
+		fn on_filter_helo(&mut self, _entry: &FilterEntry, identity: &str) -> FilterResponse {
+			if identity == "panic" {
+				panic!("invalid identity");
+			}
+			if identity == "spammer" {
+				FilterResponse::Junk
+			} else {
+				FilterResponse::Proceed
+			}
+		}
+
+		#[register]
+		fn on_filter_data_line(&mut self, entry: &FilterEntry, data_line: &[u8]) {
+			self.data_lines.push(data_line.to_vec());
+			return_data_line(entry, data_line);
+		}
+
+		#[register(smtp_in, smtp_out)]
+		fn on_report_link_disconnect(&mut self, entry: &ReportEntry) {
+			if entry.session_id == "panic" {
+				panic!("invalid session");
+			}
+			self.nb_disconnect += 1;
+		}
+
+		#[register(smtp_out)]
+		fn on_report_tx_commit(&mut self, entry: &ReportEntry, _message_id: &str, _size: usize) {
+			if entry.subsystem == SubSystem::SmtpOut {
+				self.nb_tx_commit_out += 1;
+			}
+		}
+	}
+
+	fn run_raw(input: &str) -> (TestFilter, ShutdownReason, Vec<String>) {
+		let mut filter = TestFilter::default();
+		let (reason, output) = run_raw_with(FilterRunner::new(), &mut filter, input.as_bytes());
+		(filter, reason, lines(&output))
+	}
+
+	fn run(input: &str) -> (TestFilter, Vec<String>) {
+		let mut filter = TestFilter::default();
+		let lines = run_lines(&mut filter, input);
+		(filter, lines)
+	}
+
+	#[test]
+	fn test_lifecycle() {
+		let (filter, _) = run("");
+		assert_eq!(filter.session_timeout, 300);
+		assert!(filter.is_ready);
+		assert!(filter.is_shut_down);
+	}
+
+	#[test]
+	fn test_invalid_handshake() {
+		let (filter, reason, lines) = run_raw("config|smtpd-version|6.6.1\nconfig|ready\n");
+		assert!(matches!(
+			reason,
+			ShutdownReason::Protocol(crate::Error::Handshake(_))
+		));
+		assert!(lines.is_empty());
+		assert!(!filter.is_ready);
+		assert!(filter.is_shut_down);
+	}
+
+	#[test]
+	fn test_incomplete_handshake() {
+		let (filter, reason, lines) = run_raw("config|smtpd-version|6.6.1\n");
+		assert!(matches!(reason, ShutdownReason::EndOfInput));
+		assert!(lines.is_empty());
+		assert!(!filter.is_ready);
+	}
+
+	#[test]
+	fn test_registration() {
+		let (_, lines) = run("");
+		assert_eq!(
+			lines,
+			vec![
+				"register|filter|smtp-in|data-line",
+				"register|filter|smtp-in|helo",
+				"register|report|smtp-in|link-disconnect",
+				"register|report|smtp-out|link-disconnect",
+				"register|report|smtp-out|tx-commit",
+				"register|ready",
+			]
+		);
+	}
+
+	#[test]
+	fn test_filter_and_report() {
+		let input =
+			"filter|0.5|1576146008.006099|smtp-in|helo|7641df9771b4ed00|1ef1c203cc576e5d|spammer\n\
+			filter|0.5|1576146008.006099|smtp-in|helo|7641df9771b4ed01|1ef1c203cc576e5e|mail.example.org\n\
+			report|0.5|1576147242.200225|smtp-in|link-disconnect|7641df9771b4ed00\n\
+			report|0.5|1576147242.200225|smtp-out|tx-commit|7641df9771b4ed02|2a7b3c8d|4242\n\
+			report|0.5|1576147242.200225|smtp-out|link-disconnect|7641df9771b4ed02\n";
+		let (filter, lines) = run(input);
+		assert_eq!(filter.nb_disconnect, 2);
+		assert_eq!(filter.nb_tx_commit_out, 1);
+		assert_eq!(
+			&lines[6..],
+			&[
+				"filter-result|7641df9771b4ed00|1ef1c203cc576e5d|junk",
+				"filter-result|7641df9771b4ed01|1ef1c203cc576e5e|proceed",
+			]
+		);
+	}
+
+	#[test]
+	fn test_data_line() {
+		let input = "filter|0.5|1576146008.006099|smtp-in|data-line|7641df9771b4ed00|1ef1c203cc576e5d|Subject: test\n\
+			filter|0.5|1576146008.006099|smtp-in|data-line|7641df9771b4ed00|1ef1c203cc576e5d|..\n\
+			filter|0.5|1576146008.006099|smtp-in|data-line|7641df9771b4ed00|1ef1c203cc576e5d|.\n";
+		let (filter, lines) = run(input);
+		assert_eq!(
+			filter.data_lines,
+			vec![b"Subject: test".to_vec(), b".".to_vec()]
+		);
+		assert_eq!(
+			&lines[6..],
+			&[
+				"filter-dataline|7641df9771b4ed00|1ef1c203cc576e5d|Subject: test",
+				"filter-dataline|7641df9771b4ed00|1ef1c203cc576e5d|..",
+				"filter-dataline|7641df9771b4ed00|1ef1c203cc576e5d|.",
+			]
+		);
+	}
+
+	#[test]
+	fn test_protocol_0_4() {
+		let input =
+			"filter|0.4|1576146008.006099|smtp-in|helo|7641df9771b4ed00|1ef1c203cc576e5d|spammer\n\
+			filter|0.4|1576146008.006099|smtp-in|helo|7641df9771b4ed00|1ef1c203cc576e5e\n\
+			filter|0.4|1576146008.006099|smtp-in|data-line|7641df9771b4ed00|1ef1c203cc576e5f|Subject: test\n\
+			filter|0.4|1576146008.006099|smtp-in|data-line|7641df9771b4ed00|1ef1c203cc576e5f|.\n";
+		let (_, lines) = run(input);
+		assert_eq!(
+			&lines[6..],
+			&[
+				"filter-result|1ef1c203cc576e5d|7641df9771b4ed00|junk",
+				"filter-result|1ef1c203cc576e5e|7641df9771b4ed00|proceed",
+				"filter-dataline|1ef1c203cc576e5f|7641df9771b4ed00|Subject: test",
+				"filter-dataline|1ef1c203cc576e5f|7641df9771b4ed00|.",
+			]
+		);
+	}
+
+	#[test]
+	fn test_binary_input() {
+		let mut input = HANDSHAKE.as_bytes().to_vec();
+		input.extend_from_slice(b"filter|0.5|1576146008.006099|smtp-in|helo|s|t1|caf\xe9\n");
+		input.extend_from_slice(b"filter|0.5|1576146008.006099|smtp-in|helo|s|t2|spammer\n");
+		input.extend_from_slice(
+			b"filter|0.5|1576146008.006099|smtp-in|data-line|s|t3|caf\xe9\x00\x1b\r|\n",
+		);
+		input.extend_from_slice(b"filter|0.5|1576146008.006099|smtp-in|data-line|s|t3|end\r\n");
+		let (reason, output) =
+			run_raw_with(FilterRunner::new(), &mut TestFilter::default(), &input);
+		assert!(matches!(reason, ShutdownReason::EndOfInput));
+		assert!(
+			output.ends_with(b"filter-result|s|t2|junk\nfilter-dataline|s|t3|caf\xe9\x00\x1b\r|\nfilter-dataline|s|t3|end\r\n")
+		);
+	}
+
+	#[test]
+	fn test_on_error() {
+		let errors = Arc::new(Mutex::new(Vec::new()));
+		let errors_hook = Arc::clone(&errors);
+		let input = "report|0.5|1576147242.200225|smtp-in|link-foo|7641df9771b4ed00\n\
+			filter|0.5|1576146008.006099|smtp-in|helo|7641df9771b4ed00|1ef1c203cc576e5d|spammer\n";
+		let runner =
+			FilterRunner::new().on_error(move |e| errors_hook.lock().unwrap().push(e.to_string()));
+		let lines = run_with(runner, &mut TestFilter::default(), input);
+		assert_eq!(*errors.lock().unwrap(), vec!["unknown event: link-foo"]);
+		assert_eq!(
+			lines.last().unwrap(),
+			"filter-result|7641df9771b4ed00|1ef1c203cc576e5d|junk"
+		);
+	}
+
+	/// Writer which fails on the filter results.
+	struct BrokenPipe;
+
+	impl std::io::Write for BrokenPipe {
+		fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+			if buf.starts_with(b"filter-result") {
+				Err(std::io::ErrorKind::BrokenPipe.into())
+			} else {
+				Ok(buf.len())
+			}
+		}
+
+		fn flush(&mut self) -> std::io::Result<()> {
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn test_on_write_error() {
+		let errors = Arc::new(Mutex::new(Vec::new()));
+		let errors_hook = Arc::clone(&errors);
+		let input = format!(
+			"{}filter|0.5|1576146008.006099|smtp-in|helo|7641df9771b4ed00|1ef1c203cc576e5d|spammer\n",
+			HANDSHAKE
+		);
+		let reason = FilterRunner::new()
+			.on_error(move |e| {
+				assert!(matches!(e, crate::Error::Io(_)));
+				errors_hook.lock().unwrap().push(e.to_string());
+			})
+			.input(Cursor::new(input.into_bytes()))
+			.output(BrokenPipe)
+			.run(&mut TestFilter::default());
+		assert!(matches!(reason, ShutdownReason::EndOfInput));
+		assert_eq!(errors.lock().unwrap().len(), 1);
+	}
+
+	#[test]
+	fn test_fail_response() {
+		let input = "filter|0.5|1576146008.006099|smtp-in|helo|s|t1\n\
+			filter|0.5|1576146008.006099|smtp-in|foo|s|t2|bar\n\
+			filter|0.5|1576146008.006099|smtp-in|helo|s|t3|panic\n\
+			filter|0.5|1576146008.006099|smtp-in|helo|s|t4|spammer\n";
+		let runner = FilterRunner::new()
+			.fail_response(FilterResponse::Reject(SmtpStatusCode::from_number(451)));
+		let lines = run_with(runner, &mut TestFilter::default(), input);
+		assert_eq!(
+			&lines[6..],
+			&[
+				"filter-result|s|t1|reject|451 Requested action aborted: local error in processing",
+				"filter-result|s|t2|reject|451 Requested action aborted: local error in processing",
+				"filter-result|s|t3|reject|451 Requested action aborted: local error in processing",
+				"filter-result|s|t4|junk",
+			]
+		);
+	}
+
+	#[test]
+	fn test_panic() {
+		let input = "report|0.5|1576147242.200225|smtp-in|link-disconnect|panic\n\
+			filter|0.5|1576146008.006099|smtp-in|helo|s1|t1|panic\n\
+			report|0.5|1576147242.200225|smtp-in|link-disconnect|s2\n\
+			filter|0.5|1576146008.006099|smtp-in|helo|s3|t2|spammer\n";
+		let (filter, lines) = run(input);
+		assert_eq!(filter.panics, vec!["panic", "s1"]);
+		assert_eq!(filter.nb_disconnect, 1);
+		assert_eq!(
+			&lines[6..],
+			&[
+				"filter-result|s1|t1|reject|451 Requested action aborted: local error in processing",
+				"filter-result|s3|t2|junk",
+			]
+		);
+	}
+
+	#[cfg(feature = "async")]
+	struct AsyncFilter {}
+
+	#[cfg(feature = "async")]
+	#[crate::async_trait]
+	impl crate::AsyncFilter for AsyncFilter {
+		#[register]
+		async fn on_filter_data_line(&self, entry: &FilterEntry, data_line: &[u8]) {
+			tokio::task::yield_now().await;
+			return_data_line(entry, &data_line.to_ascii_uppercase());
+		}
+	}
+
+	#[cfg(feature = "async")]
+	struct SlowHelo {}
+
+	#[cfg(feature = "async")]
+	#[crate::async_trait]
+	impl crate::AsyncFilter for SlowHelo {
+		#[register]
+		async fn on_filter_helo(&self, _entry: &FilterEntry, identity: &str) -> FilterResponse {
+			for _ in 0..identity.len() {
+				tokio::task::yield_now().await;
+			}
+			if identity == "spammer" {
+				FilterResponse::Junk
+			} else {
+				FilterResponse::Proceed
+			}
+		}
+	}
+
+	#[cfg(feature = "async")]
+	#[test]
+	fn test_run_async_awaits_tasks() {
+		let input = "filter|0.5|1576146008.006099|smtp-in|helo|s1|t1|spammer\n\
+			filter|0.5|1576146008.006099|smtp-in|helo|s2|t2|a\n";
+		let mut lines = run_async_lines(SlowHelo {}, input);
+		lines[2..].sort();
+		assert_eq!(
+			lines,
+			vec![
+				"register|filter|smtp-in|helo",
+				"register|ready",
+				"filter-result|s1|t1|junk",
+				"filter-result|s2|t2|proceed",
+			]
+		);
+	}
+
+	#[cfg(feature = "async")]
+	#[test]
+	fn test_run_async() {
+		let input = "filter|0.5|1576146008.006099|smtp-in|data-line|s1|t1|Subject: test\n\
+			filter|0.5|1576146008.006099|smtp-in|data-line|s1|t1|.\n";
+		assert_eq!(
+			run_async_lines(AsyncFilter {}, input),
+			vec![
+				"register|filter|smtp-in|data-line",
+				"register|ready",
+				"filter-dataline|s1|t1|SUBJECT: TEST",
+				"filter-dataline|s1|t1|.",
+			]
+		);
+	}
+
+	struct ImplFilter {}
+
+	#[crate::filter(smtp_in, smtp_out)]
+	impl Filter for ImplFilter {
+		fn on_ready(&mut self) {}
+
+		fn on_filter_helo(&mut self, _entry: &FilterEntry, _identity: &str) -> FilterResponse {
+			FilterResponse::Junk
+		}
+
+		fn on_report_link_disconnect(&mut self, _entry: &ReportEntry) {}
+
+		#[register(smtp_out)]
+		fn on_report_tx_commit(&mut self, _entry: &ReportEntry, _message_id: &str, _size: usize) {}
+	}
+
+	#[test]
+	fn test_filter_attribute() {
+		assert_eq!(
+			run_lines(&mut ImplFilter {}, ""),
+			vec![
+				"register|filter|smtp-in|helo",
+				"register|report|smtp-in|link-disconnect",
+				"register|report|smtp-out|link-disconnect",
+				"register|report|smtp-out|tx-commit",
+				"register|ready",
+			]
+		);
+	}
+}
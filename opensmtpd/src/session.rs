@@ -0,0 +1,624 @@
+use crate::{
+	return_data_end, Address, AuthResult, Event, Filter, FilterConfig, FilterEntry, FilterKind,
+	FilterPhase, FilterResponse, MailResult, Message, Method, ReportEntry, ShutdownReason,
+	SubSystem, Transaction, TransactionState,
+};
+use std::collections::HashMap;
+use std::ops::{Deref, DerefMut};
+
+macro_rules! get_session {
+	($obj: ident, $entry: ident) => {
+		$obj.sessions
+			.entry($entry.session_id.clone())
+			.or_insert_with(|| SessionContext::new(&$entry.session_id))
+	};
+}
+
+macro_rules! session_filter {
+	(
+		filters { $($(#[$f_attr: meta])* $f_name: ident, $f_has: ident, ($($f_param: ident: $f_type: ty),*);)* }
+		reports { $($(#[$r_attr: meta])* $r_name: ident, $r_has: ident, $r_event: ident, ($($r_param: ident: $r_type: ty),*);)* }
+	) => {
+		/// Filter whose handlers receive the state of the current session.
+		///
+		/// This trait is similar to [`Filter`], except that every filter and
+		/// report handler also receives the [`SessionContext`] of the session
+		/// the event belongs to. The filter has to be wrapped in [`Sessions`]
+		/// before being run.
+		///
+		/// There is no asynchronous counterpart: [`Sessions`] implements
+		/// [`Filter`] only, so it cannot be run by `run_async_filter`.
+		pub trait SessionFilter {
+			type Session: Default;
+
+			fn on_config(&mut self, _config: &FilterConfig) {}
+
+			fn on_ready(&mut self) {}
+
+			fn on_shutdown(&mut self, _reason: &ShutdownReason) {}
+
+			fn on_panic(&mut self, _session_id: &str, _message: &str) {}
+
+			$(
+				$(#[$f_attr])*
+				#[allow(unused_variables)]
+				fn $f_name(
+					&mut self,
+					entry: &FilterEntry,
+					session: &mut SessionContext<Self::Session>,
+					$($f_param: $f_type),*
+				) -> FilterResponse {
+					FilterResponse::Proceed
+				}
+				#[doc(hidden)]
+				fn $f_has(&self, _subsystem: &SubSystem) -> bool {
+					false
+				}
+			)*
+
+			fn on_filter_data_line(
+				&mut self,
+				_entry: &FilterEntry,
+				_session: &mut SessionContext<Self::Session>,
+				_data_line: &[u8],
+			) {
+			}
+			#[doc(hidden)]
+			fn has_filter_data_line(&self, _subsystem: &SubSystem) -> bool {
+				false
+			}
+
+			fn on_filter_data_end(
+				&mut self,
+				entry: &FilterEntry,
+				_session: &mut SessionContext<Self::Session>,
+			) {
+				return_data_end(entry);
+			}
+
+			fn on_message(
+				&mut self,
+				_entry: &FilterEntry,
+				_session: &mut SessionContext<Self::Session>,
+				message: Message,
+			) -> Vec<u8> {
+				message.into_bytes()
+			}
+			#[doc(hidden)]
+			fn has_message(&self, _subsystem: &SubSystem) -> bool {
+				false
+			}
+
+			$(
+				$(#[$r_attr])*
+				#[allow(unused_variables)]
+				fn $r_name(
+					&mut self,
+					entry: &ReportEntry,
+					session: &mut SessionContext<Self::Session>,
+					$($r_param: $r_type),*
+				) {
+				}
+				#[doc(hidden)]
+				fn $r_has(&self, _subsystem: &SubSystem) -> bool {
+					false
+				}
+			)*
+		}
+
+		/// Report handlers of [`Filter`], used internally to keep track of
+		/// the state of the sessions.
+		trait ReportTracker {
+			$(
+				fn $r_name(&mut self, _entry: &ReportEntry, $(_: $r_type),*) {}
+			)*
+		}
+
+		impl<F: SessionFilter> Sessions<F> {
+			fn uses_subsystem(&self, subsystem: &SubSystem) -> bool {
+				$(self.filter.$f_has(subsystem) ||)*
+				$(self.filter.$r_has(subsystem) ||)*
+				self.filter.has_filter_data_line(subsystem) || self.filter.has_message(subsystem)
+			}
+		}
+
+		impl<F: SessionFilter> Filter for Sessions<F> {
+			fn on_config(&mut self, config: &FilterConfig) {
+				self.filter.on_config(config);
+			}
+
+			fn on_ready(&mut self) {
+				self.filter.on_ready();
+			}
+
+			fn on_shutdown(&mut self, reason: &ShutdownReason) {
+				self.filter.on_shutdown(reason);
+			}
+
+			fn on_panic(&mut self, session_id: &str, message: &str) {
+				self.filter.on_panic(session_id, message);
+				if self.drop_on_panic && self.sessions.remove(session_id).is_some() {
+					log::debug!("session {} dropped after a panic", session_id);
+				}
+			}
+
+			$(
+				fn $f_name(&mut self, entry: &FilterEntry, $($f_param: $f_type),*) -> FilterResponse {
+					let session = get_session!(self, entry);
+					self.filter.$f_name(entry, session, $($f_param),*)
+				}
+
+				fn $f_has(&self, subsystem: &SubSystem) -> bool {
+					self.filter.$f_has(subsystem)
+				}
+			)*
+
+			fn on_filter_data_line(&mut self, entry: &FilterEntry, data_line: &[u8]) {
+				let session = get_session!(self, entry);
+				self.filter.on_filter_data_line(entry, session, data_line)
+			}
+
+			fn has_filter_data_line(&self, subsystem: &SubSystem) -> bool {
+				self.filter.has_filter_data_line(subsystem)
+			}
+
+			fn on_filter_data_end(&mut self, entry: &FilterEntry) {
+				let session = get_session!(self, entry);
+				self.filter.on_filter_data_end(entry, session)
+			}
+
+			fn on_message(&mut self, entry: &FilterEntry, message: Message) -> Vec<u8> {
+				let session = get_session!(self, entry);
+				self.filter.on_message(entry, session, message)
+			}
+
+			fn has_message(&self, subsystem: &SubSystem) -> bool {
+				self.filter.has_message(subsystem)
+			}
+
+			$(
+				fn $r_name(&mut self, entry: &ReportEntry, $($r_param: $r_type),*) {
+					let event = Event::$r_event;
+					if matches!(event, Event::LinkConnect) {
+						self.sessions.insert(
+							entry.session_id.clone(),
+							SessionContext::new(&entry.session_id),
+						);
+					}
+					let session = get_session!(self, entry);
+					TransactionTracker(&mut session.transaction)
+						.$r_name(entry, $(Clone::clone(&$r_param)),*);
+					if self.filter.$r_has(&entry.subsystem) {
+						self.filter.$r_name(entry, session, $($r_param),*);
+					}
+					if matches!(event, Event::TxReset) {
+						session.transaction = None;
+					}
+					if event.ends_session() {
+						self.sessions.remove(&entry.session_id);
+					}
+				}
+
+				fn $r_has(&self, subsystem: &SubSystem) -> bool {
+					self.filter.$r_has(subsystem)
+						|| (is_tracked(&Event::$r_event) && self.uses_subsystem(subsystem))
+				}
+			)*
+		}
+	};
+}
+
+/// State attached to an SMTP session.
+///
+/// The user-defined session data is accessible through `Deref` and
+/// `DerefMut`.
+#[derive(Debug)]
+pub struct SessionContext<S> {
+	session_id: String,
+	transaction: Option<Transaction>,
+	data: S,
+}
+
+impl<S: Default> SessionContext<S> {
+	fn new(session_id: &str) -> Self {
+		SessionContext {
+			session_id: session_id.to_string(),
+			transaction: None,
+			data: S::default(),
+		}
+	}
+}
+
+impl<S> SessionContext<S> {
+	pub fn session_id(&self) -> &str {
+		&self.session_id
+	}
+
+	/// Returns the current transaction, if any.
+	pub fn transaction(&self) -> Option<&Transaction> {
+		self.transaction.as_ref()
+	}
+
+	pub fn into_inner(self) -> S {
+		self.data
+	}
+}
+
+impl<S> Deref for SessionContext<S> {
+	type Target = S;
+
+	fn deref(&self) -> &S {
+		&self.data
+	}
+}
+
+impl<S> DerefMut for SessionContext<S> {
+	fn deref_mut(&mut self) -> &mut S {
+		&mut self.data
+	}
+}
+
+/// Adapter running a [`SessionFilter`] as a [`Filter`].
+///
+/// A new session is created using `Default` when a client connects
+/// and dropped when it disconnects or times out. In order to do so,
+/// the `link-connect`, `link-disconnect` and `timeout` reports are
+/// registered for every subsystem the filter uses. A session is also
+/// created on the fly if an event is received for an unknown session,
+/// which happens when the filter is started while clients are already
+/// connected.
+///
+/// The `tx-*` reports are also registered in order to keep track of the
+/// session's [`Transaction`], which is accessible from every handler
+/// using [`SessionContext::transaction`].
+///
+/// ``` rust
+/// use opensmtpd::{run_filter, FilterEntry, FilterResponse, SessionContext, SessionFilter, Sessions};
+/// use opensmtpd_derive::register;
+///
+/// #[derive(Default)]
+/// struct Rcpt {
+///		nb: usize,
+/// }
+///
+/// struct MaxRcpt {}
+///
+/// impl SessionFilter for MaxRcpt {
+///		type Session = Rcpt;
+///
+///		#[register]
+///		fn on_filter_rcpt_to(
+///			&mut self,
+///			_entry: &FilterEntry,
+///			session: &mut SessionContext<Rcpt>,
+///			_address: &str,
+///		) -> FilterResponse {
+///			session.nb += 1;
+///			FilterResponse::Proceed
+///		}
+/// }
+///
+/// fn main() {
+///		run_filter(&mut Sessions::new(MaxRcpt {}));
+/// }
+/// ```
+#[derive(Debug)]
+pub struct Sessions<F: SessionFilter> {
+	filter: F,
+	sessions: HashMap<String, SessionContext<F::Session>>,
+	drop_on_panic: bool,
+}
+
+impl<F: SessionFilter> Sessions<F> {
+	pub fn new(filter: F) -> Self {
+		Sessions {
+			filter,
+			sessions: HashMap::new(),
+			drop_on_panic: false,
+		}
+	}
+
+	/// Drops the state of a session when one of its handlers panics, so
+	/// the following events of this session start from a fresh state
+	/// instead of a possibly inconsistent one. Disabled by default.
+	pub fn drop_session_on_panic(mut self, drop: bool) -> Self {
+		self.drop_on_panic = drop;
+		self
+	}
+
+	pub fn filter(&self) -> &F {
+		&self.filter
+	}
+
+	pub fn filter_mut(&mut self) -> &mut F {
+		&mut self.filter
+	}
+
+	pub fn into_inner(self) -> F {
+		self.filter
+	}
+
+	/// Returns the session with the given id, if it exists.
+	pub fn get(&self, session_id: &str) -> Option<&SessionContext<F::Session>> {
+		self.sessions.get(session_id)
+	}
+
+	/// Returns the number of sessions currently tracked.
+	pub fn len(&self) -> usize {
+		self.sessions.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.sessions.is_empty()
+	}
+}
+
+for_each_handler!(session_filter);
+
+/// Returns whether the report is needed to keep track of the sessions
+/// and of their transaction.
+fn is_tracked(event: &Event) -> bool {
+	matches!(
+		event,
+		Event::LinkConnect
+			| Event::LinkDisconnect
+			| Event::Timeout
+			| Event::TxBegin
+			| Event::TxMail
+			| Event::TxReset
+			| Event::TxRcpt
+			| Event::TxEnvelope
+			| Event::TxCommit
+			| Event::TxRollback
+	)
+}
+
+/// Updates the transaction of a session from the `tx-*` reports.
+struct TransactionTracker<'a>(&'a mut Option<Transaction>);
+
+impl TransactionTracker<'_> {
+	fn transaction_for(&mut self, message_id: &str) -> Option<&mut Transaction> {
+		self.0.as_mut().filter(|tx| tx.message_id == message_id)
+	}
+}
+
+impl ReportTracker for TransactionTracker<'_> {
+	fn on_report_tx_begin(&mut self, _entry: &ReportEntry, message_id: &str) {
+		*self.0 = Some(Transaction::new(message_id));
+	}
+
+	fn on_report_tx_mail(
+		&mut self,
+		_entry: &ReportEntry,
+		message_id: &str,
+		result: MailResult,
+		address: &str,
+	) {
+		if let Some(tx) = self.transaction_for(message_id) {
+			tx.set_mail_from(result, address);
+		}
+	}
+
+	fn on_report_tx_rcpt(
+		&mut self,
+		_entry: &ReportEntry,
+		message_id: &str,
+		result: MailResult,
+		address: &str,
+	) {
+		if let Some(tx) = self.transaction_for(message_id) {
+			tx.add_rcpt_to(result, address);
+		}
+	}
+
+	fn on_report_tx_envelope(&mut self, _entry: &ReportEntry, message_id: &str, envelope_id: &str) {
+		if let Some(tx) = self.transaction_for(message_id) {
+			tx.envelope_ids.push(envelope_id.to_string());
+		}
+	}
+
+	fn on_report_tx_commit(&mut self, _entry: &ReportEntry, message_id: &str, message_size: usize) {
+		if let Some(tx) = self.transaction_for(message_id) {
+			tx.state = TransactionState::Committed;
+			tx.size = Some(message_size);
+		}
+	}
+
+	fn on_report_tx_rollback(&mut self, _entry: &ReportEntry, message_id: &str) {
+		if let Some(tx) = self.transaction_for(message_id) {
+			tx.state = TransactionState::RolledBack;
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{SessionContext, SessionFilter, Sessions};
+	use crate::runner::tests::run_lines;
+	use crate::{
+		FilterEntry, FilterResponse, MailResult, ReportEntry, SmtpStatusCode, Transaction,
+		TransactionState,
+	};
+	use opensmtpd_derive::register;
+
+	#[derive(Default)]
+	struct Rcpt {
+		nb: usize,
+	}
+
+	#[derive(Default)]
+	struct MaxRcpt {
+		closed: Vec<(String, usize)>,
+		committed: Option<Transaction>,
+		panics: Vec<String>,
+	}
+
+	impl SessionFilter for MaxRcpt {
+		type Session = Rcpt;
+
+		fn on_panic(&mut self, session_id: &str, _message: &str) {
+			self.panics.push(session_id.to_string());
+		}
+
+		#[register]
+		fn on_filter_rcpt_to(
+			&mut self,
+			_entry: &FilterEntry,
+			session: &mut SessionContext<Rcpt>,
+			address: &str,
+		) -> FilterResponse {
+			session.nb += 1;
+			if address == "panic@example.org" {
+				panic!("invalid address");
+			}
+			if session.nb > 2 {
+				FilterResponse::Reject(SmtpStatusCode::from_number(452))
+			} else {
+				FilterResponse::Proceed
+			}
+		}
+
+		#[register]
+		fn on_filter_commit(
+			&mut self,
+			_entry: &FilterEntry,
+			session: &mut SessionContext<Rcpt>,
+		) -> FilterResponse {
+			self.committed = session.transaction().cloned();
+			FilterResponse::Proceed
+		}
+
+		#[register]
+		fn on_report_link_disconnect(
+			&mut self,
+			_entry: &ReportEntry,
+			session: &mut SessionContext<Rcpt>,
+		) {
+			self.closed
+				.push((session.session_id().to_string(), session.nb));
+		}
+	}
+
+	fn run(input: &str) -> (Sessions<MaxRcpt>, Vec<String>) {
+		run_filter(Sessions::new(MaxRcpt::default()), input)
+	}
+
+	fn run_filter(mut filter: Sessions<MaxRcpt>, input: &str) -> (Sessions<MaxRcpt>, Vec<String>) {
+		let lines = run_lines(&mut filter, input);
+		(filter, lines)
+	}
+
+	#[test]
+	fn test_registration() {
+		let (_, lines) = run("");
+		assert_eq!(
+			lines,
+			vec![
+				"register|filter|smtp-in|commit",
+				"register|filter|smtp-in|rcpt-to",
+				"register|report|smtp-in|link-connect",
+				"register|report|smtp-in|link-disconnect",
+				"register|report|smtp-in|tx-begin",
+				"register|report|smtp-in|tx-mail",
+				"register|report|smtp-in|tx-reset",
+				"register|report|smtp-in|tx-rcpt",
+				"register|report|smtp-in|tx-envelope",
+				"register|report|smtp-in|tx-commit",
+				"register|report|smtp-in|tx-rollback",
+				"register|report|smtp-in|timeout",
+				"register|ready",
+			]
+		);
+	}
+
+	#[test]
+	fn test_sessions() {
+		let input = "report|0.5|1576146008.006099|smtp-in|link-connect|s1|localhost|pass|127.0.0.1:4242|127.0.0.1:25\n\
+			report|0.5|1576146008.006099|smtp-in|link-connect|s2|localhost|pass|127.0.0.1:4243|127.0.0.1:25\n\
+			filter|0.5|1576146008.006099|smtp-in|rcpt-to|s1|t1|a@example.org\n\
+			filter|0.5|1576146008.006099|smtp-in|rcpt-to|s2|t2|b@example.org\n\
+			filter|0.5|1576146008.006099|smtp-in|rcpt-to|s1|t3|c@example.org\n\
+			filter|0.5|1576146008.006099|smtp-in|rcpt-to|s1|t4|d@example.org\n\
+			report|0.5|1576146008.006099|smtp-in|link-disconnect|s1\n\
+			report|0.5|1576146008.006099|smtp-in|link-connect|s1|localhost|pass|127.0.0.1:4244|127.0.0.1:25\n\
+			filter|0.5|1576146008.006099|smtp-in|rcpt-to|s1|t5|e@example.org\n\
+			report|0.5|1576146008.006099|smtp-in|timeout|s2\n";
+		let (filter, lines) = run(input);
+		assert_eq!(
+			&lines[13..],
+			&[
+				"filter-result|s1|t1|proceed",
+				"filter-result|s2|t2|proceed",
+				"filter-result|s1|t3|proceed",
+				"filter-result|s1|t4|reject|452 Requested action not taken: insufficient system storage",
+				"filter-result|s1|t5|proceed",
+			]
+		);
+		assert_eq!(filter.filter().closed, vec![("s1".to_string(), 3)]);
+		assert_eq!(filter.len(), 1);
+		assert_eq!(filter.get("s1").map(|s| s.nb), Some(1));
+		assert!(filter.get("s2").is_none());
+	}
+
+	#[test]
+	fn test_transaction() {
+		let input = "report|0.6|1576146008.006099|smtp-in|link-connect|s1|localhost|pass|127.0.0.1:4242|127.0.0.1:25\n\
+			report|0.6|1576146008.006099|smtp-in|tx-begin|s1|m1\n\
+			report|0.6|1576146008.006099|smtp-in|tx-mail|s1|m1|ok|<a@example.org>\n\
+			report|0.6|1576146008.006099|smtp-in|tx-rcpt|s1|m1|ok|<b@example.org>\n\
+			report|0.6|1576146008.006099|smtp-in|tx-rcpt|s1|m1|permfail|<c@example.org>\n\
+			report|0.6|1576146008.006099|smtp-in|tx-envelope|s1|m1|e1\n\
+			filter|0.6|1576146008.006099|smtp-in|commit|s1|t1\n\
+			report|0.6|1576146008.006099|smtp-in|tx-commit|s1|m1|4242\n";
+		let (filter, _) = run(input);
+		let tx = filter.filter().committed.as_ref().unwrap();
+		assert_eq!(tx.message_id, "m1");
+		assert_eq!(tx.state, TransactionState::InProgress);
+		assert_eq!(tx.mail_from.as_deref(), Some("<a@example.org>"));
+		assert_eq!(tx.rcpt_to, vec!["<b@example.org>"]);
+		assert_eq!(
+			tx.rejected_rcpt_to,
+			vec![("<c@example.org>".to_string(), MailResult::PermFail)]
+		);
+		assert_eq!(tx.envelope_ids, vec!["e1"]);
+		let tx = filter.get("s1").unwrap().transaction().unwrap();
+		assert_eq!(tx.state, TransactionState::Committed);
+		assert_eq!(tx.size, Some(4242));
+
+		let input = format!(
+			"{}report|0.6|1576146008.006099|smtp-in|tx-reset|s1|m1\n",
+			input
+		);
+		let (filter, _) = run(&input);
+		assert!(filter.get("s1").unwrap().transaction().is_none());
+	}
+
+	#[test]
+	fn test_drop_session_on_panic() {
+		let input = "filter|0.5|1576146008.006099|smtp-in|rcpt-to|s1|t1|a@example.org\n\
+			filter|0.5|1576146008.006099|smtp-in|rcpt-to|s1|t2|panic@example.org\n\
+			filter|0.5|1576146008.006099|smtp-in|rcpt-to|s1|t3|b@example.org\n\
+			filter|0.5|1576146008.006099|smtp-in|rcpt-to|s1|t4|c@example.org\n";
+		let (filter, lines) = run(input);
+		assert_eq!(filter.filter().panics, vec!["s1"]);
+		assert_eq!(filter.get("s1").map(|s| s.nb), Some(4));
+		assert_eq!(
+			lines[16],
+			"filter-result|s1|t4|reject|452 Requested action not taken: insufficient system storage"
+		);
+
+		let filter = Sessions::new(MaxRcpt::default()).drop_session_on_panic(true);
+		let (filter, lines) = run_filter(filter, input);
+		assert_eq!(filter.filter().panics, vec!["s1"]);
+		assert_eq!(filter.get("s1").map(|s| s.nb), Some(2));
+		assert_eq!(
+			&lines[13..],
+			&[
+				"filter-result|s1|t1|proceed",
+				"filter-result|s1|t2|reject|451 Requested action aborted: local error in processing",
+				"filter-result|s1|t3|proceed",
+				"filter-result|s1|t4|proceed",
+			]
+		);
+	}
+}
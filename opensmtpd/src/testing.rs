@@ -0,0 +1,973 @@
+//! Simulated OpenSMTPD, to test filters without running it.
+//!
+//! The [`Smtpd`] performs the handshake with the filter, checks the
+//! events it registers and then sends the reports and filter requests
+//! OpenSMTPD would send for the SMTP sessions driven by the test. Only
+//! the events registered by the filter are sent, the other filter
+//! phases being accepted as OpenSMTPD does. The lines written by the
+//! filter are parsed into [`FilterOutput`] values.
+//!
+//! ``` rust
+//! use opensmtpd::testing::{Decision, Smtpd};
+//! use opensmtpd::{Filter, FilterEntry, FilterResponse, SmtpStatusCode};
+//!
+//! struct NoSpammer {}
+//!
+//! #[opensmtpd::filter]
+//! impl Filter for NoSpammer {
+//!		fn on_filter_mail_from(&mut self, _entry: &FilterEntry, address: &str) -> FilterResponse {
+//!			match address {
+//!				"<spammer@example.org>" => FilterResponse::Reject(SmtpStatusCode::from_number(550)),
+//!				_ => FilterResponse::Proceed,
+//!			}
+//!		}
+//! }
+//!
+//! let mut smtpd = Smtpd::new(NoSpammer {});
+//! smtpd.assert_registrations(&["filter|smtp-in|mail-from"]);
+//! let mut session = smtpd.session();
+//! session.connect("mail.example.org", "192.0.2.1:4242");
+//! session.ehlo("mail.example.org");
+//! let decision = session.mail_from("<spammer@example.org>");
+//! assert!(matches!(decision, Some(Decision::Reject(_))));
+//! session.disconnect();
+//! ```
+
+use crate::io::{Buffer, Output};
+use crate::message::{MessageBuffers, DEFAULT_MAX_MESSAGE_SIZE};
+use crate::parsers::handshake::parse_handshake;
+use crate::process;
+use crate::runner::{register, ErrorHandler};
+use crate::{
+	Error, Event, Filter, FilterConfig, FilterPhase, ProtocolVersion, ShutdownReason, SubSystem,
+};
+use std::convert::Infallible;
+use std::fmt;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const SMTPD_VERSION: &str = "7.0.0";
+const PROTOCOL_VERSION: ProtocolVersion = ProtocolVersion::V0_7;
+const SESSION_TIMEOUT: usize = 300;
+const LOCAL_ADDRESS: &str = "192.0.2.25:25";
+
+/// Response of the filter to a filter request.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Decision {
+	Proceed,
+	Junk,
+	Reject(String),
+	Disconnect(String),
+	Rewrite(String),
+	Report(String),
+}
+
+impl Decision {
+	/// Returns whether the SMTP session goes on after this decision.
+	pub fn proceeds(&self) -> bool {
+		!matches!(self, Decision::Reject(_) | Decision::Disconnect(_))
+	}
+}
+
+impl fmt::Display for Decision {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Decision::Proceed => write!(f, "proceed"),
+			Decision::Junk => write!(f, "junk"),
+			Decision::Reject(p) => write!(f, "reject|{}", p),
+			Decision::Disconnect(p) => write!(f, "disconnect|{}", p),
+			Decision::Rewrite(p) => write!(f, "rewrite|{}", p),
+			Decision::Report(p) => write!(f, "report|{}", p),
+		}
+	}
+}
+
+/// Parses the end of a filter-result line, e.g. `reject|550 go away`.
+impl FromStr for Decision {
+	type Err = Error;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let decision = match s.split_once('|') {
+			None if s == "proceed" => Decision::Proceed,
+			None if s == "junk" => Decision::Junk,
+			Some(("reject", p)) if is_smtp_response(p) => Decision::Reject(p.to_string()),
+			Some(("disconnect", p)) if is_smtp_response(p) => Decision::Disconnect(p.to_string()),
+			Some(("rewrite", p)) if !p.is_empty() => Decision::Rewrite(p.to_string()),
+			Some(("report", p)) if !p.is_empty() => Decision::Report(p.to_string()),
+			_ => return Err(Error::invalid_value("filter result", s)),
+		};
+		Ok(decision)
+	}
+}
+
+/// Line written by the filter after the handshake.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum FilterOutput {
+	Result {
+		session_id: String,
+		token: String,
+		decision: Decision,
+	},
+	/// A data-line, unstuffed.
+	DataLine {
+		session_id: String,
+		token: String,
+		line: String,
+	},
+	DataEnd {
+		session_id: String,
+		token: String,
+	},
+}
+
+impl FilterOutput {
+	fn parse(line: &str) -> Self {
+		let fields: Vec<&str> = line.splitn(4, '|').collect();
+		let (kind, session_id, token, value) = match fields.as_slice() {
+			[kind, session_id, token, value] => {
+				(*kind, session_id.to_string(), token.to_string(), *value)
+			}
+			_ => panic!("invalid line written by the filter: {}", line),
+		};
+		match kind {
+			"filter-result" => FilterOutput::Result {
+				session_id,
+				token,
+				decision: value.parse().unwrap_or_else(|_| {
+					panic!("invalid filter result written by the filter: {}", line)
+				}),
+			},
+			"filter-dataline" if value == "." => FilterOutput::DataEnd { session_id, token },
+			"filter-dataline" => FilterOutput::DataLine {
+				session_id,
+				token,
+				line: value.strip_prefix('.').unwrap_or(value).to_string(),
+			},
+			_ => panic!("invalid line written by the filter: {}", line),
+		}
+	}
+}
+
+/// Lines returned by the filter for a message.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Data {
+	/// Response to the `data` filter request, `None` if the filter did
+	/// not answer.
+	pub decision: Option<Decision>,
+	/// The message returned by the filter.
+	pub lines: Vec<String>,
+	/// Whether the filter ended the message.
+	pub complete: bool,
+}
+
+/// Lines OpenSMTPD sends to a filter on the `smtp-in` subsystem.
+///
+/// The generator knows the events registered by the filter: reports
+/// and filter requests are only generated for them. It is used by
+/// [`Smtpd`] and can drive a filter over any other [`Transport`].
+#[derive(Clone, Debug, Default)]
+pub struct LineGenerator {
+	registrations: Vec<String>,
+	ready: bool,
+	last_id: u64,
+}
+
+impl LineGenerator {
+	pub fn new() -> Self {
+		LineGenerator::default()
+	}
+
+	/// Returns the configuration lines starting the handshake.
+	pub fn handshake(&self) -> Vec<String> {
+		vec![
+			format!("config|smtpd-version|{}", SMTPD_VERSION),
+			format!("config|smtp-session-timeout|{}", SESSION_TIMEOUT),
+			format!("config|subsystem|{}", SubSystem::SmtpIn),
+			"config|ready".to_string(),
+		]
+	}
+
+	/// Checks a registration line written by the filter. Returns `true`
+	/// once the filter sent `register|ready`.
+	pub fn register(&mut self, line: &str) -> Result<bool, String> {
+		if self.ready {
+			return Err(format!("registration after `register|ready`: {}", line));
+		}
+		let registration = match line.strip_prefix("register|") {
+			Some(r) => r,
+			None => return Err(format!("invalid registration: {}", line)),
+		};
+		if registration == "ready" {
+			self.ready = true;
+			return Ok(true);
+		}
+		let valid = match registration.split('|').collect::<Vec<_>>().as_slice() {
+			["filter", subsystem, phase] => {
+				subsystem.parse::<SubSystem>().is_ok() && phase.parse::<FilterPhase>().is_ok()
+			}
+			["report", subsystem, event] => {
+				subsystem.parse::<SubSystem>().is_ok() && event.parse::<Event>().is_ok()
+			}
+			_ => false,
+		};
+		if !valid {
+			return Err(format!("invalid registration: {}", line));
+		}
+		if self.is_registered(registration) {
+			return Err(format!("duplicate registration: {}", line));
+		}
+		self.registrations.push(registration.to_string());
+		Ok(false)
+	}
+
+	pub fn is_ready(&self) -> bool {
+		self.ready
+	}
+
+	/// Returns the events registered by the filter, e.g.
+	/// `filter|smtp-in|helo`.
+	pub fn registrations(&self) -> &[String] {
+		&self.registrations
+	}
+
+	pub fn is_registered(&self, registration: &str) -> bool {
+		self.registrations.iter().any(|r| r == registration)
+	}
+
+	/// Returns a new session id or token.
+	pub fn next_id(&mut self) -> String {
+		self.last_id += 1;
+		format!("{:016x}", self.last_id)
+	}
+
+	/// Returns a new message id.
+	pub fn next_message_id(&mut self) -> String {
+		self.last_id += 1;
+		format!("{:08x}", self.last_id)
+	}
+
+	/// Returns the report line, or `None` if the filter did not register
+	/// the event. The parameters start with a `|`.
+	pub fn report(&self, session_id: &str, event: Event, params: &str) -> Option<String> {
+		if !self.is_registered(&format!("report|{}|{}", SubSystem::SmtpIn, event)) {
+			return None;
+		}
+		Some(format!(
+			"report|{}|{}|{}|{}|{}{}",
+			PROTOCOL_VERSION,
+			timestamp(),
+			SubSystem::SmtpIn,
+			event,
+			session_id,
+			params
+		))
+	}
+
+	/// Returns the token and the line of the filter request, or `None`
+	/// if the filter did not register the phase. The parameters start
+	/// with a `|`.
+	pub fn filter_request(
+		&mut self,
+		session_id: &str,
+		phase: FilterPhase,
+		params: &str,
+	) -> Option<(String, String)> {
+		if !self.is_registered(&format!("filter|{}|{}", SubSystem::SmtpIn, phase)) {
+			return None;
+		}
+		let token = self.next_id();
+		let line = format!(
+			"filter|{}|{}|{}|{}|{}|{}{}",
+			PROTOCOL_VERSION,
+			timestamp(),
+			SubSystem::SmtpIn,
+			phase,
+			session_id,
+			token,
+			params
+		);
+		Some((token, line))
+	}
+
+	/// Returns the token and the data-lines of a message, dot-stuffed
+	/// and ended, or `None` if the filter did not register them.
+	pub fn data_lines(
+		&mut self,
+		session_id: &str,
+		lines: &[&str],
+	) -> Option<(String, Vec<String>)> {
+		let (token, _) = self.filter_request(session_id, FilterPhase::DataLine, "")?;
+		let stuffed = lines.iter().map(|l| match l.starts_with('.') {
+			true => format!(".{}", l),
+			false => l.to_string(),
+		});
+		let lines = stuffed
+			.chain(std::iter::once(".".to_string()))
+			.map(|line| {
+				format!(
+					"filter|{}|{}|{}|{}|{}|{}|{}",
+					PROTOCOL_VERSION,
+					timestamp(),
+					SubSystem::SmtpIn,
+					FilterPhase::DataLine,
+					session_id,
+					token,
+					line
+				)
+			})
+			.collect();
+		Some((token, lines))
+	}
+}
+
+/// Sends the lines of a [`LineGenerator`] to a filter.
+pub trait Transport {
+	type Error;
+
+	fn generator(&mut self) -> &mut LineGenerator;
+
+	fn send_report(&mut self, line: String) -> Result<(), Self::Error>;
+
+	/// Sends a filter request and returns the decision of the filter,
+	/// `None` if it did not answer.
+	fn send_filter_request(
+		&mut self,
+		session_id: &str,
+		token: &str,
+		line: String,
+	) -> Result<Option<Decision>, Self::Error>;
+
+	/// Sends the data-lines of a message and returns the lines sent back
+	/// by the filter, and whether it ended the message.
+	fn send_message(
+		&mut self,
+		session_id: &str,
+		token: &str,
+		lines: Vec<String>,
+	) -> Result<(Vec<String>, bool), Self::Error>;
+}
+
+/// Sequence of the reports and filter requests of an SMTP session.
+///
+/// Each method returns the response of the filter to the corresponding
+/// filter request, [`Decision::Proceed`] if the filter did not register
+/// it. The reports are sent once the filter accepted the command.
+#[derive(Clone, Debug, Default)]
+pub struct SessionLines {
+	id: String,
+	message_id: Option<String>,
+	message_size: usize,
+}
+
+impl SessionLines {
+	pub fn new(generator: &mut LineGenerator) -> Self {
+		SessionLines {
+			id: generator.next_id(),
+			message_id: None,
+			message_size: 0,
+		}
+	}
+
+	pub fn id(&self) -> &str {
+		&self.id
+	}
+
+	/// Returns the identifier of the current transaction, if any.
+	pub fn message_id(&self) -> Option<&str> {
+		self.message_id.as_deref()
+	}
+
+	/// Connects a client from the given address, e.g. `192.0.2.1:4242`.
+	pub fn connect<T>(
+		&mut self,
+		t: &mut T,
+		rdns: &str,
+		src: &str,
+	) -> Result<Option<Decision>, T::Error>
+	where
+		T: Transport,
+	{
+		let params = format!("|{}|pass|{}|{}", rdns, src, LOCAL_ADDRESS);
+		let decision = self.filter(t, FilterPhase::Connect, &params)?;
+		if accepted(&decision) {
+			self.report(t, Event::LinkConnect, &params)?;
+		}
+		Ok(decision)
+	}
+
+	pub fn helo<T>(&mut self, t: &mut T, identity: &str) -> Result<Option<Decision>, T::Error>
+	where
+		T: Transport,
+	{
+		let decision = self.filter(t, FilterPhase::Helo, &format!("|{}", identity))?;
+		if accepted(&decision) {
+			self.report(t, Event::LinkIdentify, &format!("|HELO|{}", identity))?;
+		}
+		Ok(decision)
+	}
+
+	pub fn ehlo<T>(&mut self, t: &mut T, identity: &str) -> Result<Option<Decision>, T::Error>
+	where
+		T: Transport,
+	{
+		let decision = self.filter(t, FilterPhase::Ehlo, &format!("|{}", identity))?;
+		if accepted(&decision) {
+			self.report(t, Event::LinkIdentify, &format!("|EHLO|{}", identity))?;
+		}
+		Ok(decision)
+	}
+
+	/// Starts a new transaction, unless the filter does not proceed.
+	pub fn mail_from<T>(&mut self, t: &mut T, address: &str) -> Result<Option<Decision>, T::Error>
+	where
+		T: Transport,
+	{
+		let decision = self.filter(t, FilterPhase::MailFrom, &format!("|{}", address))?;
+		if accepted(&decision) {
+			let message_id = t.generator().next_message_id();
+			self.report(t, Event::TxBegin, &format!("|{}", message_id))?;
+			let params = format!("|{}|{}|{}", message_id, mail_result(&decision), address);
+			self.report(t, Event::TxMail, &params)?;
+			self.message_id = Some(message_id);
+			self.message_size = 0;
+		}
+		Ok(decision)
+	}
+
+	pub fn rcpt_to<T>(&mut self, t: &mut T, address: &str) -> Result<Option<Decision>, T::Error>
+	where
+		T: Transport,
+	{
+		let message_id = self.transaction();
+		let decision = self.filter(t, FilterPhase::RcptTo, &format!("|{}", address))?;
+		let params = format!("|{}|{}|{}", message_id, mail_result(&decision), address);
+		self.report(t, Event::TxRcpt, &params)?;
+		Ok(decision)
+	}
+
+	/// Sends a message, given without the final dot.
+	pub fn data<T>(&mut self, t: &mut T, lines: &[&str]) -> Result<Data, T::Error>
+	where
+		T: Transport,
+	{
+		let message_id = self.transaction();
+		let mut data = Data {
+			decision: self.filter(t, FilterPhase::Data, "")?,
+			..Data::default()
+		};
+		if !accepted(&data.decision) {
+			return Ok(data);
+		}
+		self.report(t, Event::TxData, &format!("|{}|ok", message_id))?;
+		match t.generator().data_lines(&self.id, lines) {
+			Some((token, data_lines)) => {
+				let (lines, complete) = t.send_message(&self.id, &token, data_lines)?;
+				data.lines = lines;
+				data.complete = complete;
+			}
+			None => {
+				data.lines = lines.iter().map(|l| l.to_string()).collect();
+				data.complete = true;
+			}
+		}
+		self.message_size = data.lines.iter().map(|l| l.len() + 2).sum();
+		Ok(data)
+	}
+
+	/// Ends the transaction.
+	pub fn commit<T>(&mut self, t: &mut T) -> Result<Option<Decision>, T::Error>
+	where
+		T: Transport,
+	{
+		let message_id = self.transaction();
+		let decision = self.filter(t, FilterPhase::Commit, "")?;
+		if accepted(&decision) {
+			let params = format!("|{}|{}", message_id, self.message_size);
+			self.report(t, Event::TxCommit, &params)?;
+		} else {
+			self.report(t, Event::TxRollback, &format!("|{}", message_id))?;
+		}
+		self.report(t, Event::TxReset, &format!("|{}", message_id))?;
+		self.message_id = None;
+		Ok(decision)
+	}
+
+	pub fn disconnect<T>(&mut self, t: &mut T) -> Result<(), T::Error>
+	where
+		T: Transport,
+	{
+		self.report(t, Event::LinkDisconnect, "")
+	}
+
+	fn transaction(&self) -> String {
+		self.message_id
+			.clone()
+			.expect("no transaction, `mail_from` must be called first")
+	}
+
+	fn filter<T>(
+		&self,
+		t: &mut T,
+		phase: FilterPhase,
+		params: &str,
+	) -> Result<Option<Decision>, T::Error>
+	where
+		T: Transport,
+	{
+		match t.generator().filter_request(&self.id, phase, params) {
+			Some((token, line)) => t.send_filter_request(&self.id, &token, line),
+			None => Ok(Some(Decision::Proceed)),
+		}
+	}
+
+	fn report<T>(&self, t: &mut T, event: Event, params: &str) -> Result<(), T::Error>
+	where
+		T: Transport,
+	{
+		match t.generator().report(&self.id, event, params) {
+			Some(line) => t.send_report(line),
+			None => Ok(()),
+		}
+	}
+}
+
+/// Simulated OpenSMTPD running a filter in the current thread.
+///
+/// Filter requests answered later, e.g. using a
+/// [`FilterResponder`](crate::FilterResponder), are available using
+/// [`outputs`](Smtpd::outputs).
+pub struct Smtpd<F> {
+	filter: F,
+	config: FilterConfig,
+	buffer: Buffer,
+	output: Output,
+	messages: MessageBuffers,
+	errors: ErrorHandler,
+	generator: LineGenerator,
+	outputs: Vec<FilterOutput>,
+}
+
+impl<F> Smtpd<F>
+where
+	F: Filter,
+{
+	/// Performs the handshake with the filter.
+	///
+	/// Panics if the filter does not register its events correctly.
+	pub fn new(mut filter: F) -> Self {
+		let mut generator = LineGenerator::new();
+		let mut handshake = generator.handshake().join("\n");
+		handshake.push('\n');
+		let (_, config) = parse_handshake(handshake.as_bytes()).expect("invalid handshake");
+		let buffer = Buffer::default();
+		let output = Output::new(buffer.clone());
+		filter.on_config(&config);
+		register(&filter, &output).expect("unable to write the registrations");
+		for line in take_lines(&buffer) {
+			if let Err(e) = generator.register(&line) {
+				panic!("{}", e);
+			}
+		}
+		assert!(
+			generator.is_ready(),
+			"the filter did not send `register|ready`"
+		);
+		filter.on_ready();
+		Smtpd {
+			filter,
+			config,
+			buffer,
+			output,
+			messages: MessageBuffers::new(DEFAULT_MAX_MESSAGE_SIZE),
+			errors: ErrorHandler::default(),
+			generator,
+			outputs: Vec::new(),
+		}
+	}
+
+	pub fn config(&self) -> &FilterConfig {
+		&self.config
+	}
+
+	pub fn filter(&self) -> &F {
+		&self.filter
+	}
+
+	pub fn filter_mut(&mut self) -> &mut F {
+		&mut self.filter
+	}
+
+	/// Stops the filter and returns it.
+	pub fn shutdown(mut self) -> F {
+		self.filter.on_shutdown(&ShutdownReason::EndOfInput);
+		self.filter
+	}
+
+	/// Returns the events registered by the filter, e.g.
+	/// `filter|smtp-in|helo`.
+	pub fn registrations(&self) -> &[String] {
+		self.generator.registrations()
+	}
+
+	pub fn is_registered(&self, registration: &str) -> bool {
+		self.generator.is_registered(registration)
+	}
+
+	/// Panics if the filter did not register exactly the given events,
+	/// in any order.
+	pub fn assert_registrations(&self, expected: &[&str]) {
+		let mut registrations: Vec<&str> =
+			self.registrations().iter().map(String::as_str).collect();
+		let mut expected = expected.to_vec();
+		registrations.sort_unstable();
+		expected.sort_unstable();
+		assert_eq!(registrations, expected, "unexpected registrations");
+	}
+
+	/// Returns every line written by the filter since the handshake.
+	pub fn outputs(&mut self) -> &[FilterOutput] {
+		self.collect();
+		&self.outputs
+	}
+
+	/// Starts a new SMTP session.
+	pub fn session(&mut self) -> Session<'_, F> {
+		Session {
+			lines: SessionLines::new(&mut self.generator),
+			smtpd: self,
+		}
+	}
+
+	fn send(&mut self, line: String) -> Vec<FilterOutput> {
+		let line = format!("{}\n", line).into_bytes();
+		if let Err(e) = process::line(&mut self.filter, &self.output, &mut self.messages, &line) {
+			process::report_error(&mut self.filter, &self.errors, &self.output, &line, &e);
+		}
+		self.collect()
+	}
+
+	fn collect(&mut self) -> Vec<FilterOutput> {
+		let outputs: Vec<FilterOutput> = take_lines(&self.buffer)
+			.iter()
+			.map(|l| FilterOutput::parse(l))
+			.collect();
+		self.outputs.extend_from_slice(&outputs);
+		outputs
+	}
+}
+
+impl<F> Transport for Smtpd<F>
+where
+	F: Filter,
+{
+	type Error = Infallible;
+
+	fn generator(&mut self) -> &mut LineGenerator {
+		&mut self.generator
+	}
+
+	fn send_report(&mut self, line: String) -> Result<(), Infallible> {
+		self.send(line);
+		Ok(())
+	}
+
+	fn send_filter_request(
+		&mut self,
+		session_id: &str,
+		token: &str,
+		line: String,
+	) -> Result<Option<Decision>, Infallible> {
+		let decision = self.send(line).into_iter().find_map(|o| match o {
+			FilterOutput::Result {
+				session_id: s,
+				token: t,
+				decision,
+			} if s == session_id && t == token => Some(decision),
+			_ => None,
+		});
+		Ok(decision)
+	}
+
+	fn send_message(
+		&mut self,
+		session_id: &str,
+		token: &str,
+		lines: Vec<String>,
+	) -> Result<(Vec<String>, bool), Infallible> {
+		let mut outputs = Vec::new();
+		for line in lines {
+			outputs.extend(self.send(line));
+		}
+		let mut message = Vec::new();
+		let mut complete = false;
+		for output in outputs {
+			match output {
+				FilterOutput::DataLine {
+					session_id: s,
+					token: t,
+					line,
+				} if s == session_id && t == token => message.push(line),
+				FilterOutput::DataEnd {
+					session_id: s,
+					token: t,
+				} if s == session_id && t == token => complete = true,
+				_ => {}
+			}
+		}
+		Ok((message, complete))
+	}
+}
+
+/// SMTP session of a simulated OpenSMTPD.
+///
+/// Each method returns the response of the filter to the corresponding
+/// filter request, or `None` if the filter did not answer. The reports
+/// are sent once the filter accepted the command.
+pub struct Session<'a, F> {
+	smtpd: &'a mut Smtpd<F>,
+	lines: SessionLines,
+}
+
+impl<'a, F> Session<'a, F>
+where
+	F: Filter,
+{
+	pub fn id(&self) -> &str {
+		self.lines.id()
+	}
+
+	/// Returns the identifier of the current transaction, if any.
+	pub fn message_id(&self) -> Option<&str> {
+		self.lines.message_id()
+	}
+
+	/// Connects a client from the given address, e.g. `192.0.2.1:4242`.
+	pub fn connect(&mut self, rdns: &str, src: &str) -> Option<Decision> {
+		infallible(self.lines.connect(self.smtpd, rdns, src))
+	}
+
+	pub fn helo(&mut self, identity: &str) -> Option<Decision> {
+		infallible(self.lines.helo(self.smtpd, identity))
+	}
+
+	pub fn ehlo(&mut self, identity: &str) -> Option<Decision> {
+		infallible(self.lines.ehlo(self.smtpd, identity))
+	}
+
+	/// Starts a new transaction.
+	pub fn mail_from(&mut self, address: &str) -> Option<Decision> {
+		infallible(self.lines.mail_from(self.smtpd, address))
+	}
+
+	pub fn rcpt_to(&mut self, address: &str) -> Option<Decision> {
+		infallible(self.lines.rcpt_to(self.smtpd, address))
+	}
+
+	/// Sends a message, given without the final dot.
+	pub fn data(&mut self, lines: &[&str]) -> Data {
+		infallible(self.lines.data(self.smtpd, lines))
+	}
+
+	/// Ends the transaction.
+	pub fn commit(&mut self) -> Option<Decision> {
+		infallible(self.lines.commit(self.smtpd))
+	}
+
+	pub fn disconnect(mut self) {
+		infallible(self.lines.disconnect(self.smtpd))
+	}
+}
+
+fn infallible<T>(res: Result<T, Infallible>) -> T {
+	match res {
+		Ok(value) => value,
+		Err(e) => match e {},
+	}
+}
+
+fn take_lines(buffer: &Buffer) -> Vec<String> {
+	String::from_utf8_lossy(&buffer.take())
+		.lines()
+		.map(str::to_string)
+		.collect()
+}
+
+fn accepted(decision: &Option<Decision>) -> bool {
+	decision.as_ref().map(Decision::proceeds).unwrap_or(false)
+}
+
+fn mail_result(decision: &Option<Decision>) -> &'static str {
+	match accepted(decision) {
+		true => "ok",
+		false => "permfail",
+	}
+}
+
+fn is_smtp_response(response: &str) -> bool {
+	let code = response.split(' ').next().unwrap_or_default();
+	code.len() == 3 && code.parse::<u16>().is_ok()
+}
+
+fn timestamp() -> String {
+	let now = SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.unwrap_or_default();
+	format!("{}.{:06}", now.as_secs(), now.subsec_micros())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{Decision, FilterOutput, LineGenerator, Smtpd};
+	use crate::{
+		return_data_line, Filter, FilterEntry, FilterResponse, ReportEntry, SmtpStatusCode,
+		SubSystem,
+	};
+
+	#[derive(Default)]
+	struct TestFilter {
+		transactions: Vec<String>,
+		commits: Vec<(String, usize)>,
+		disconnects: usize,
+	}
+
+	#[crate::filter]
+	impl Filter for TestFilter {
+		fn on_filter_mail_from(&mut self, _entry: &FilterEntry, address: &str) -> FilterResponse {
+			match address {
+				"<spammer@example.org>" => FilterResponse::Reject(SmtpStatusCode::from_number(550)),
+				_ => FilterResponse::Proceed,
+			}
+		}
+
+		fn on_filter_rcpt_to(&mut self, _entry: &FilterEntry, address: &str) -> FilterResponse {
+			match address {
+				"<nobody@example.org>" => FilterResponse::Reject(SmtpStatusCode::from_number(550)),
+				_ => FilterResponse::Proceed,
+			}
+		}
+
+		fn on_filter_helo(&mut self, _entry: &FilterEntry, _identity: &str) -> FilterResponse {
+			panic!("HELO is not supported");
+		}
+
+		fn on_filter_data_line(&mut self, entry: &FilterEntry, data_line: &[u8]) {
+			return_data_line(entry, &data_line.to_ascii_uppercase());
+		}
+
+		fn on_report_tx_begin(&mut self, _entry: &ReportEntry, message_id: &str) {
+			self.transactions.push(message_id.to_string());
+		}
+
+		fn on_report_tx_commit(&mut self, _entry: &ReportEntry, message_id: &str, size: usize) {
+			self.commits.push((message_id.to_string(), size));
+		}
+
+		fn on_report_link_disconnect(&mut self, _entry: &ReportEntry) {
+			self.disconnects += 1;
+		}
+	}
+
+	#[test]
+	fn test_session() {
+		let mut smtpd = Smtpd::new(TestFilter::default());
+		smtpd.assert_registrations(&[
+			"filter|smtp-in|data-line",
+			"filter|smtp-in|helo",
+			"filter|smtp-in|mail-from",
+			"filter|smtp-in|rcpt-to",
+			"report|smtp-in|link-disconnect",
+			"report|smtp-in|tx-begin",
+			"report|smtp-in|tx-commit",
+		]);
+		assert_eq!(smtpd.config().subsystem, SubSystem::SmtpIn);
+		let mut session = smtpd.session();
+		let session_id = session.id().to_string();
+		assert_eq!(
+			session.connect("mail.example.org", "192.0.2.1:4242"),
+			Some(Decision::Proceed)
+		);
+		assert_eq!(session.ehlo("mail.example.org"), Some(Decision::Proceed));
+		assert_eq!(
+			session.mail_from("<spammer@example.org>"),
+			Some(Decision::Reject(
+				"550 Requested action not taken: mailbox unavailable".to_string()
+			))
+		);
+		assert_eq!(session.message_id(), None);
+		assert_eq!(
+			session.mail_from("<alice@example.org>"),
+			Some(Decision::Proceed)
+		);
+		let message_id = session.message_id().unwrap().to_string();
+		assert_eq!(
+			session.rcpt_to("<nobody@example.org>"),
+			Some(Decision::Reject(
+				"550 Requested action not taken: mailbox unavailable".to_string()
+			))
+		);
+		assert_eq!(
+			session.rcpt_to("<bob@example.org>"),
+			Some(Decision::Proceed)
+		);
+		let data = session.data(&["Subject: test", "", ".hello"]);
+		assert_eq!(data.decision, Some(Decision::Proceed));
+		assert_eq!(data.lines, vec!["SUBJECT: TEST", "", ".HELLO"]);
+		assert!(data.complete);
+		assert_eq!(session.commit(), Some(Decision::Proceed));
+		session.disconnect();
+
+		let mut session = smtpd.session();
+		assert_eq!(
+			session.helo("mail.example.org"),
+			Some(Decision::Reject(
+				"451 Requested action aborted: local error in processing".to_string()
+			))
+		);
+		session.disconnect();
+
+		let outputs = smtpd.outputs();
+		assert_eq!(outputs.len(), 9);
+		assert!(matches!(
+			&outputs[7],
+			FilterOutput::DataEnd { session_id: s, .. } if *s == session_id
+		));
+		let filter = smtpd.shutdown();
+		assert_eq!(filter.transactions, vec![message_id.clone()]);
+		assert_eq!(filter.commits, vec![(message_id, 25)]);
+		assert_eq!(filter.disconnects, 2);
+	}
+
+	#[test]
+	fn test_registrations() {
+		let mut generator = LineGenerator::new();
+		assert_eq!(
+			generator.register("register|filter|smtp-in|helo"),
+			Ok(false)
+		);
+		assert_eq!(
+			generator.register("register|filter|smtp-in|helo"),
+			Err("duplicate registration: register|filter|smtp-in|helo".to_string())
+		);
+		assert_eq!(generator.register("register|ready"), Ok(true));
+		assert_eq!(
+			generator.register("register|report|smtp-in|tx-begin"),
+			Err(
+				"registration after `register|ready`: register|report|smtp-in|tx-begin".to_string()
+			)
+		);
+		assert_eq!(generator.registrations(), ["filter|smtp-in|helo"]);
+		assert!(generator
+			.report("0000000000000001", crate::Event::TxBegin, "|1")
+			.is_none());
+	}
+
+	#[test]
+	fn test_decision() {
+		for line in ["proceed", "junk", "reject|550 go away", "rewrite|x"].iter() {
+			assert_eq!(line.parse::<Decision>().unwrap().to_string(), *line);
+		}
+		for line in ["maybe", "reject|go away", "report|", "proceed|x"].iter() {
+			assert!(line.parse::<Decision>().is_err(), "{}", line);
+		}
+	}
+}
@@ -0,0 +1,76 @@
+//! Drives an OpenSMTPD filter executable through scripted SMTP sessions.
+//!
+//! ``` text
+//! smtpd-filter-sim [--timeout <seconds>] <script> <filter> [<argument>...]
+//! ```
+//!
+//! The filter is spawned and receives, on its standard input, the lines
+//! OpenSMTPD would send for the sessions described in the script. The
+//! decisions of the filter are printed. The exit status is 1 if the
+//! filter does not follow the filter protocol and 2 on any other error.
+
+mod script;
+mod simulator;
+
+use crate::simulator::{SimError, Simulator};
+use std::env;
+use std::fs;
+use std::io;
+use std::process::{self, Command};
+use std::time::Duration;
+
+const DEFAULT_TIMEOUT: u64 = 10;
+
+fn usage() -> ! {
+	eprintln!(
+		"usage: {} [--timeout <seconds>] <script> <filter> [<argument>...]",
+		env!("CARGO_PKG_NAME")
+	);
+	process::exit(2);
+}
+
+fn fail(message: &dyn std::fmt::Display, code: i32) -> ! {
+	eprintln!("{}", message);
+	process::exit(code);
+}
+
+fn main() {
+	let mut args = env::args().skip(1);
+	let mut timeout = DEFAULT_TIMEOUT;
+	let script_path = loop {
+		match args.next() {
+			Some(arg) if arg == "--timeout" => {
+				timeout = match args.next().and_then(|t| t.parse().ok()) {
+					Some(t) => t,
+					None => usage(),
+				}
+			}
+			Some(arg) if arg == "-h" || arg == "--help" => usage(),
+			Some(arg) => break arg,
+			None => usage(),
+		}
+	};
+	let mut filter = match args.next() {
+		Some(filter) => Command::new(filter),
+		None => usage(),
+	};
+	filter.args(args);
+
+	let script = fs::read_to_string(&script_path)
+		.unwrap_or_else(|e| fail(&format!("{}: {}", script_path, e), 2));
+	let commands =
+		script::parse(&script).unwrap_or_else(|e| fail(&format!("{}: {}", script_path, e), 2));
+
+	let res =
+		Simulator::spawn(filter, Duration::from_secs(timeout), io::stdout()).and_then(|mut sim| {
+			for command in commands.iter() {
+				sim.run(command)?;
+			}
+			sim.stop()
+		});
+	match res {
+		Ok(()) => {}
+		Err(e @ SimError::Protocol(_)) => fail(&e, 1),
+		Err(e) => fail(&e, 2),
+	}
+}
@@ -0,0 +1,216 @@
+use std::fmt;
+
+/// Command of an SMTP session.
+///
+/// A script is made of one command per line. Each `connect` starts a
+/// new session, the other commands apply to the current one. The lines
+/// following `data` are the message, which ends with a line holding a
+/// single dot. Empty lines and lines starting with `#` are ignored
+/// outside of the messages.
+///
+/// ``` text
+/// connect mail.example.org 192.0.2.1:4242
+/// ehlo mail.example.org
+/// mail-from <alice@example.org>
+/// rcpt-to <bob@example.org>
+/// data
+/// Subject: Hello
+///
+/// Hello Bob!
+/// .
+/// commit
+/// disconnect
+/// ```
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) enum Command {
+	Connect {
+		rdns: String,
+		src: String,
+	},
+	Helo(String),
+	Ehlo(String),
+	MailFrom(String),
+	RcptTo(String),
+	/// The message, dot-unstuffed.
+	Data(Vec<String>),
+	Commit,
+	Disconnect,
+}
+
+impl fmt::Display for Command {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Command::Connect { rdns, src } => write!(f, "connect {} {}", rdns, src),
+			Command::Helo(identity) => write!(f, "helo {}", identity),
+			Command::Ehlo(identity) => write!(f, "ehlo {}", identity),
+			Command::MailFrom(address) => write!(f, "mail-from {}", address),
+			Command::RcptTo(address) => write!(f, "rcpt-to {}", address),
+			Command::Data(lines) => write!(f, "data ({} lines)", lines.len()),
+			Command::Commit => write!(f, "commit"),
+			Command::Disconnect => write!(f, "disconnect"),
+		}
+	}
+}
+
+#[derive(Debug)]
+pub(crate) struct ScriptError {
+	line: usize,
+	message: String,
+}
+
+impl fmt::Display for ScriptError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "line {}: {}", self.line, self.message)
+	}
+}
+
+pub(crate) fn parse(input: &str) -> Result<Vec<Command>, ScriptError> {
+	let mut commands = Vec::new();
+	let mut lines = input.lines().enumerate().map(|(n, l)| (n + 1, l));
+	let mut connected = false;
+	let mut in_transaction = false;
+	while let Some((nb, line)) = lines.next() {
+		let err = |message: &str| ScriptError {
+			line: nb,
+			message: message.to_string(),
+		};
+		let line = line.trim();
+		if line.is_empty() || line.starts_with('#') {
+			continue;
+		}
+		let (name, param) = match line.split_once(char::is_whitespace) {
+			Some((name, param)) => (name, param.trim()),
+			None => (line, ""),
+		};
+		let command = match (name, param) {
+			("connect", p) => match p.split_whitespace().collect::<Vec<_>>().as_slice() {
+				[rdns, src] => Command::Connect {
+					rdns: rdns.to_string(),
+					src: src.to_string(),
+				},
+				_ => return Err(err("expected `connect <rdns> <address:port>`")),
+			},
+			("helo", p) if !p.is_empty() => Command::Helo(p.to_string()),
+			("ehlo", p) if !p.is_empty() => Command::Ehlo(p.to_string()),
+			("mail-from", p) if !p.is_empty() => Command::MailFrom(p.to_string()),
+			("rcpt-to", p) if !p.is_empty() => Command::RcptTo(p.to_string()),
+			("data", "") => Command::Data(
+				parse_message(&mut lines)
+					.ok_or_else(|| err("the message must end with a line holding a single dot"))?,
+			),
+			("commit", "") => Command::Commit,
+			("disconnect", "") => Command::Disconnect,
+			("helo", _) | ("ehlo", _) | ("mail-from", _) | ("rcpt-to", _) => {
+				return Err(err(&format!("missing parameter for `{}`", name)))
+			}
+			("data", _) | ("commit", _) | ("disconnect", _) => {
+				return Err(err(&format!("unexpected parameter for `{}`", name)))
+			}
+			_ => return Err(err(&format!("unknown command `{}`", name))),
+		};
+		match command {
+			Command::Connect { .. } => {
+				connected = true;
+				in_transaction = false;
+			}
+			_ if !connected => return Err(err("no session, `connect` must be used first")),
+			Command::MailFrom(_) => in_transaction = true,
+			Command::RcptTo(_) | Command::Data(_) | Command::Commit if !in_transaction => {
+				return Err(err("no transaction, `mail-from` must be used first"))
+			}
+			Command::Commit => in_transaction = false,
+			Command::Disconnect => connected = false,
+			_ => {}
+		}
+		commands.push(command);
+	}
+	Ok(commands)
+}
+
+fn parse_message<'a, I>(lines: &mut I) -> Option<Vec<String>>
+where
+	I: Iterator<Item = (usize, &'a str)>,
+{
+	let mut message = Vec::new();
+	for (_, line) in lines {
+		let line = line.strip_suffix('\r').unwrap_or(line);
+		match line {
+			"." => return Some(message),
+			l => message.push(l.strip_prefix('.').unwrap_or(l).to_string()),
+		}
+	}
+	None
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{parse, Command};
+
+	#[test]
+	fn test_parse() {
+		let script = "# first session\n\
+			connect mail.example.org 192.0.2.1:4242\n\
+			ehlo  mail.example.org\n\
+			mail-from <alice@example.org>\n\
+			rcpt-to <bob@example.org>\n\
+			data\n\
+			Subject: Hello\n\
+			\n\
+			..dot\n\
+			.\n\
+			commit\n\
+			disconnect\n";
+		assert_eq!(
+			parse(script).unwrap(),
+			vec![
+				Command::Connect {
+					rdns: "mail.example.org".to_string(),
+					src: "192.0.2.1:4242".to_string(),
+				},
+				Command::Ehlo("mail.example.org".to_string()),
+				Command::MailFrom("<alice@example.org>".to_string()),
+				Command::RcptTo("<bob@example.org>".to_string()),
+				Command::Data(vec![
+					"Subject: Hello".to_string(),
+					"".to_string(),
+					".dot".to_string(),
+				]),
+				Command::Commit,
+				Command::Disconnect,
+			]
+		);
+	}
+
+	#[test]
+	fn test_parse_errors() {
+		let errors = [
+			(
+				"helo example.org\n",
+				"line 1: no session, `connect` must be used first",
+			),
+			(
+				"connect example.org\n",
+				"line 1: expected `connect <rdns> <address:port>`",
+			),
+			(
+				"connect a 192.0.2.1:25\nehlo\n",
+				"line 2: missing parameter for `ehlo`",
+			),
+			(
+				"connect a 192.0.2.1:25\n\nquit\n",
+				"line 3: unknown command `quit`",
+			),
+			(
+				"connect a 192.0.2.1:25\nrcpt-to <bob@example.org>\n",
+				"line 2: no transaction, `mail-from` must be used first",
+			),
+			(
+				"connect a 192.0.2.1:25\nmail-from <>\ndata\nSubject: x\n",
+				"line 3: the message must end with a line holding a single dot",
+			),
+		];
+		for (script, error) in errors.iter() {
+			assert_eq!(parse(script).unwrap_err().to_string(), *error);
+		}
+	}
+}
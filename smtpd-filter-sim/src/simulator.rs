@@ -0,0 +1,447 @@
+use crate::script::Command;
+use opensmtpd::testing::{Decision, LineGenerator, SessionLines, Transport};
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Write};
+use std::mem;
+use std::process::{Child, ChildStdin, Stdio};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Interval between two checks of the filter exit status.
+const EXIT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Debug)]
+pub(crate) enum SimError {
+	Io(io::Error),
+	/// The filter does not follow the filter protocol.
+	Protocol(String),
+}
+
+impl fmt::Display for SimError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			SimError::Io(e) => write!(f, "I/O error: {}", e),
+			SimError::Protocol(msg) => write!(f, "protocol violation: {}", msg),
+		}
+	}
+}
+
+impl From<io::Error> for SimError {
+	fn from(error: io::Error) -> Self {
+		SimError::Io(error)
+	}
+}
+
+fn violation<T>(message: String) -> Result<T, SimError> {
+	Err(SimError::Protocol(message))
+}
+
+/// OpenSMTPD simulated on the standard input and output of a filter
+/// executable.
+pub(crate) struct Simulator<W> {
+	child: Child,
+	stdin: Option<ChildStdin>,
+	lines: Receiver<io::Result<String>>,
+	reader: Option<JoinHandle<()>>,
+	timeout: Duration,
+	generator: LineGenerator,
+	session: SessionLines,
+	out: W,
+}
+
+impl<W> Simulator<W>
+where
+	W: Write,
+{
+	/// Spawns the filter and performs the handshake.
+	pub(crate) fn spawn(
+		mut command: std::process::Command,
+		timeout: Duration,
+		out: W,
+	) -> Result<Self, SimError> {
+		let mut child = command
+			.stdin(Stdio::piped())
+			.stdout(Stdio::piped())
+			.spawn()?;
+		let stdin = child.stdin.take();
+		let stdout = child.stdout.take().expect("the output is piped");
+		let (tx, lines) = channel();
+		let reader = thread::spawn(move || {
+			for line in BufReader::new(stdout).lines() {
+				if tx.send(line).is_err() {
+					break;
+				}
+			}
+		});
+		let mut sim = Simulator {
+			child,
+			stdin,
+			lines,
+			reader: Some(reader),
+			timeout,
+			generator: LineGenerator::new(),
+			session: SessionLines::default(),
+			out,
+		};
+		sim.handshake()?;
+		Ok(sim)
+	}
+
+	/// Closes the input of the filter and waits for it to stop. The
+	/// filter is killed if it does not stop within the timeout.
+	pub(crate) fn stop(mut self) -> Result<(), SimError> {
+		self.stdin = None;
+		let deadline = Instant::now() + self.timeout;
+		let status = loop {
+			if let Some(status) = self.child.try_wait()? {
+				break status;
+			}
+			if Instant::now() >= deadline {
+				self.child.kill()?;
+				self.child.wait()?;
+				return violation(format!(
+					"the filter did not exit within {} seconds",
+					self.timeout.as_secs()
+				));
+			}
+			thread::sleep(EXIT_POLL_INTERVAL);
+		};
+		if let Some(reader) = self.reader.take() {
+			let _ = reader.join();
+		}
+		if let Some(line) = self.lines.try_iter().find_map(Result::ok) {
+			return violation(format!("unexpected line: {}", line));
+		}
+		writeln!(self.out, "filter exited: {}", status)?;
+		Ok(())
+	}
+
+	pub(crate) fn run(&mut self, command: &Command) -> Result<(), SimError> {
+		let mut session = mem::take(&mut self.session);
+		let res = self.run_session(&mut session, command);
+		self.session = session;
+		res
+	}
+
+	fn run_session(
+		&mut self,
+		session: &mut SessionLines,
+		command: &Command,
+	) -> Result<(), SimError> {
+		if let Command::RcptTo(_) | Command::Data(_) | Command::Commit = command {
+			// The transaction does not exist when mail-from was rejected.
+			if session.message_id().is_none() {
+				writeln!(self.out, "[{}] {}: no transaction", session.id(), command)?;
+				return Ok(());
+			}
+		}
+		let decision = match command {
+			Command::Connect { rdns, src } => {
+				*session = SessionLines::new(&mut self.generator);
+				session.connect(self, rdns, src)?
+			}
+			Command::Helo(identity) => session.helo(self, identity)?,
+			Command::Ehlo(identity) => session.ehlo(self, identity)?,
+			Command::MailFrom(address) => session.mail_from(self, address)?,
+			Command::RcptTo(address) => session.rcpt_to(self, address)?,
+			Command::Data(lines) => {
+				let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+				let data = session.data(self, &lines)?;
+				self.print_decision(session, command, &data.decision)?;
+				if self.generator.is_registered("filter|smtp-in|data-line") {
+					for line in data.lines.iter() {
+						writeln!(self.out, "[{}] data-line: {}", session.id(), line)?;
+					}
+				}
+				return Ok(());
+			}
+			Command::Commit => session.commit(self)?,
+			Command::Disconnect => {
+				session.disconnect(self)?;
+				writeln!(self.out, "[{}] disconnect", session.id())?;
+				return Ok(());
+			}
+		};
+		self.print_decision(session, command, &decision)
+	}
+
+	fn print_decision(
+		&mut self,
+		session: &SessionLines,
+		command: &Command,
+		decision: &Option<Decision>,
+	) -> Result<(), SimError> {
+		match decision {
+			Some(decision) => writeln!(self.out, "[{}] {}: {}", session.id(), command, decision)?,
+			None => writeln!(self.out, "[{}] {}", session.id(), command)?,
+		}
+		Ok(())
+	}
+
+	fn handshake(&mut self) -> Result<(), SimError> {
+		for line in self.generator.handshake() {
+			self.send(&line)?;
+		}
+		loop {
+			let line = match self.recv()? {
+				Some(line) => line,
+				None => return violation("missing `register|ready`".to_string()),
+			};
+			match self.generator.register(&line) {
+				Ok(true) => return Ok(()),
+				Ok(false) => {
+					let registration = line.strip_prefix("register|").unwrap_or(&line);
+					writeln!(self.out, "registered: {}", registration)?;
+				}
+				Err(e) => return violation(e),
+			}
+		}
+	}
+
+	fn send(&mut self, line: &str) -> Result<(), SimError> {
+		match &mut self.stdin {
+			Some(stdin) => {
+				stdin.write_all(line.as_bytes())?;
+				stdin.write_all(b"\n")?;
+				stdin.flush()?;
+				Ok(())
+			}
+			None => violation("the input of the filter is closed".to_string()),
+		}
+	}
+
+	/// Returns the next line written by the filter, or `None` if its
+	/// output has been closed.
+	fn recv(&mut self) -> Result<Option<String>, SimError> {
+		match self.lines.recv_timeout(self.timeout) {
+			Ok(line) => Ok(Some(line?)),
+			Err(RecvTimeoutError::Disconnected) => Ok(None),
+			Err(RecvTimeoutError::Timeout) => violation(format!(
+				"no response within {} seconds",
+				self.timeout.as_secs()
+			)),
+		}
+	}
+
+	/// Returns the next line written by the filter, failing if its
+	/// output has been closed.
+	fn recv_response(&mut self) -> Result<String, SimError> {
+		match self.recv()? {
+			Some(line) => Ok(line),
+			None => violation("the filter closed its output".to_string()),
+		}
+	}
+}
+
+impl<W> Transport for Simulator<W>
+where
+	W: Write,
+{
+	type Error = SimError;
+
+	fn generator(&mut self) -> &mut LineGenerator {
+		&mut self.generator
+	}
+
+	fn send_report(&mut self, line: String) -> Result<(), SimError> {
+		self.send(&line)
+	}
+
+	fn send_filter_request(
+		&mut self,
+		session_id: &str,
+		token: &str,
+		line: String,
+	) -> Result<Option<Decision>, SimError> {
+		self.send(&line)?;
+		let line = self.recv_response()?;
+		let decision = match line.splitn(4, '|').collect::<Vec<_>>().as_slice() {
+			["filter-result", s, t, decision] => {
+				if *s != session_id || *t != token {
+					return violation(format!("response with an unknown token: {}", line));
+				}
+				decision.parse::<Decision>()
+			}
+			_ => return violation(format!("unexpected line: {}", line)),
+		};
+		match decision {
+			Ok(decision) => Ok(Some(decision)),
+			Err(_) => violation(format!("malformed filter-result: {}", line)),
+		}
+	}
+
+	fn send_message(
+		&mut self,
+		session_id: &str,
+		token: &str,
+		lines: Vec<String>,
+	) -> Result<(Vec<String>, bool), SimError> {
+		for line in lines {
+			self.send(&line)?;
+		}
+		let mut message = Vec::new();
+		loop {
+			let line = self.recv_response()?;
+			match line.splitn(4, '|').collect::<Vec<_>>().as_slice() {
+				["filter-dataline", s, t, data_line] if *s == session_id && *t == token => {
+					if *data_line == "." {
+						return Ok((message, true));
+					}
+					let data_line = data_line.strip_prefix('.').unwrap_or(data_line);
+					message.push(data_line.to_string());
+				}
+				["filter-dataline", ..] | ["filter-result", ..] => {
+					return violation(format!("response with an unknown token: {}", line));
+				}
+				_ => return violation(format!("unexpected line: {}", line)),
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{SimError, Simulator};
+	use crate::script;
+	use std::process::Command;
+	use std::time::Duration;
+
+	const HANDSHAKE: &str = "read l; read l; read l; read l;";
+
+	fn simulate(filter: &str, script: &str) -> (Result<(), SimError>, String) {
+		simulate_with(filter, script, Duration::from_secs(5))
+	}
+
+	fn simulate_with(
+		filter: &str,
+		script: &str,
+		timeout: Duration,
+	) -> (Result<(), SimError>, String) {
+		let mut command = Command::new("sh");
+		command.arg("-c").arg(format!("{} {}", HANDSHAKE, filter));
+		let mut out = Vec::new();
+		let res = Simulator::spawn(command, timeout, &mut out).and_then(|mut sim| {
+			for command in script::parse(script).unwrap() {
+				sim.run(&command)?;
+			}
+			sim.stop()
+		});
+		(res, String::from_utf8(out).unwrap())
+	}
+
+	#[test]
+	fn test_session() {
+		let filter = "echo 'register|filter|smtp-in|helo'; \
+			echo 'register|filter|smtp-in|data-line'; \
+			echo 'register|ready'; \
+			while IFS='|' read -r kind v ts ss phase sid token param; do \
+				case $phase in \
+					helo) echo \"filter-result|$sid|$token|reject|550 go away\";; \
+					data-line) echo \"filter-dataline|$sid|$token|$param\";; \
+				esac; \
+			done";
+		let script = "connect mail.example.org 192.0.2.1:4242\n\
+			helo mail.example.org\n\
+			mail-from <alice@example.org>\n\
+			data\n\
+			Subject: Hello\n\
+			..dot\n\
+			.\n\
+			commit\n\
+			disconnect\n";
+		let (res, out) = simulate(filter, script);
+		res.unwrap();
+		let lines: Vec<&str> = out.lines().collect();
+		assert_eq!(
+			lines[..9],
+			[
+				"registered: filter|smtp-in|helo",
+				"registered: filter|smtp-in|data-line",
+				"[0000000000000001] connect mail.example.org 192.0.2.1:4242: proceed",
+				"[0000000000000001] helo mail.example.org: reject|550 go away",
+				"[0000000000000001] mail-from <alice@example.org>: proceed",
+				"[0000000000000001] data (2 lines): proceed",
+				"[0000000000000001] data-line: Subject: Hello",
+				"[0000000000000001] data-line: .dot",
+				"[0000000000000001] commit: proceed",
+			]
+		);
+		assert_eq!(lines[9], "[0000000000000001] disconnect");
+		assert!(lines[10].starts_with("filter exited"));
+	}
+
+	#[test]
+	fn test_rejected_mail_from() {
+		let filter = "echo 'register|filter|smtp-in|mail-from'; \
+			echo 'register|report|smtp-in|tx-begin'; \
+			echo 'register|ready'; \
+			while IFS='|' read -r kind v ts ss phase sid token param; do \
+				case $kind in \
+					filter) echo \"filter-result|$sid|$token|reject|550 go away\";; \
+					report) echo \"unexpected $phase\";; \
+				esac; \
+			done";
+		let script = "connect mail.example.org 192.0.2.1:4242\n\
+			mail-from <spammer@example.org>\n\
+			rcpt-to <bob@example.org>\n\
+			disconnect\n";
+		let (res, out) = simulate(filter, script);
+		res.unwrap();
+		let lines: Vec<&str> = out.lines().collect();
+		assert_eq!(
+			lines[2..5],
+			[
+				"[0000000000000001] connect mail.example.org 192.0.2.1:4242: proceed",
+				"[0000000000000001] mail-from <spammer@example.org>: reject|550 go away",
+				"[0000000000000001] rcpt-to <bob@example.org>: no transaction",
+			]
+		);
+	}
+
+	#[test]
+	fn test_violations() {
+		let script = "connect mail.example.org 192.0.2.1:4242\nhelo mail.example.org\n";
+		let helo = "echo 'register|filter|smtp-in|helo'; echo 'register|ready'; \
+			while IFS='|' read -r kind v ts ss phase sid token param; do";
+		let violations = [
+			(
+				"echo 'register|filter|smtp-in|helo'",
+				"missing `register|ready`",
+			),
+			(
+				"echo 'register|filter|smtp-in|hello'",
+				"invalid registration: register|filter|smtp-in|hello",
+			),
+			(
+				"echo 'register|filter|smtp-in|helo'; echo 'register|filter|smtp-in|helo'",
+				"duplicate registration: register|filter|smtp-in|helo",
+			),
+			(
+				&format!("{} echo \"filter-result|$sid|$token|maybe\"; done", helo),
+				"malformed filter-result: filter-result|0000000000000001|0000000000000002|maybe",
+			),
+			(
+				&format!("{} echo \"filter-result|$sid|42|proceed\"; done", helo),
+				"response with an unknown token: filter-result|0000000000000001|42|proceed",
+			),
+		];
+		for (filter, error) in violations.iter() {
+			match simulate(filter, script).0 {
+				Err(SimError::Protocol(e)) => assert_eq!(e, *error),
+				r => panic!("unexpected result for {}: {:?}", filter, r),
+			}
+		}
+	}
+
+	#[test]
+	fn test_stop_timeout() {
+		let filter = "echo 'register|ready'; exec sleep 10";
+		match simulate_with(filter, "", Duration::from_secs(1)).0 {
+			Err(SimError::Protocol(e)) => {
+				assert_eq!(e, "the filter did not exit within 1 seconds")
+			}
+			r => panic!("unexpected result: {:?}", r),
+		}
+	}
+}